@@ -0,0 +1,319 @@
+#![allow(dead_code)]
+
+// Aurora frame checks and payload conversions, the firmware's aurora module is the driver
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransmissionState {
+    OK,
+    NotImplemented,
+    NotExist,
+    OutOfRange,
+    EEpromError,
+    NotServiceMode,
+    InternalMicroError,
+    NotExecuted,
+    Retry,
+    Unknown,
+}
+
+impl TransmissionState {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => TransmissionState::OK,
+            51 => TransmissionState::NotImplemented,
+            52 => TransmissionState::NotExist,
+            53 => TransmissionState::OutOfRange,
+            54 => TransmissionState::EEpromError,
+            55 => TransmissionState::NotServiceMode,
+            56 => TransmissionState::InternalMicroError,
+            57 => TransmissionState::NotExecuted,
+            58 => TransmissionState::Retry,
+            _ => TransmissionState::Unknown,
+        }
+    }
+}
+
+// CRC-16/X-25, low byte first on the wire, buf is left untouched
+pub fn crc(buf: &[u8]) -> [u8; 2] {
+    let poly = 0x8408;
+    let mask = 0xffff;
+    let mut crc: u16 = 0xffff;
+
+    for byte in buf {
+        let mut data = *byte;
+        for _i in 0..8u8 {
+            if (crc & 0x1) ^ ((data & 0x1) as u16) > 0 {
+                crc = ((crc >> 1) ^ poly) & mask
+            } else {
+                crc >>= 1;
+            }
+            data >>= 1;
+        }
+    }
+    crc = !crc & mask;
+    crc.to_le_bytes()
+}
+
+// reply CRC covers bytes 0..6, sent low byte first like the request CRC
+pub fn crc_valid(response: &[u8; 8]) -> bool {
+    crc(&response[0..6]) == [response[6], response[7]]
+}
+
+// Printable part of an ASCII reply, padding and NULs dropped
+pub fn ascii_payload(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_matches(|c: char| c.is_whitespace() || c == '\0')
+        .to_string()
+}
+
+// float layout in bytes 2..6, a few firmware variants send some measurements little endian
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FloatOrder {
+    #[default]
+    Big,
+    Little,
+}
+
+pub fn convert_bytes_to_f32(response: [u8; 8], order: FloatOrder) -> anyhow::Result<f32> {
+    let bytes = response[2..6].try_into()?;
+    Ok(match order {
+        FloatOrder::Big => f32::from_be_bytes(bytes),
+        FloatOrder::Little => f32::from_le_bytes(bytes),
+    })
+}
+
+// nothing an inverter measures, usually the byte order is swapped. 230 V read the wrong way
+// round is 3.7e-41, so tiny denormals count too
+pub fn implausible_f32(value: f32) -> bool {
+    !value.is_finite() || value.abs() > 1.0e6 || (value != 0.0 && value.abs() < 1.0e-20)
+}
+
+pub fn convert_bytes_to_i32(response: [u8; 8]) -> anyhow::Result<i32> {
+    Ok(i32::from_be_bytes(response[2..6].try_into()?))
+}
+
+// Measure (59) codes from the Aurora protocol, the NC values are unassigned
+#[allow(unused)]
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum DspRequest {
+    // 0 isn't a measure code
+    NC0 = 0,
+    // grid voltage
+    Grid = 1,
+    Current = 2,
+    GridPower = 3,
+    Frequency = 4,
+    Vbulk = 5,
+    IleakDc = 6,
+    Ileak = 7,
+    Pin1 = 8,
+    Pin2 = 9,
+    NC10 = 10,
+    NC11 = 11,
+    NC12 = 12,
+    NC13 = 13,
+    NC14 = 14,
+    NC15 = 15,
+    NC16 = 16,
+    NC17 = 17,
+    NC18 = 18,
+    NC19 = 19,
+    NC20 = 20,
+    InverterTemperature = 21,
+    BoosterTemperature = 22,
+    Input1Voltage = 23,
+    NC24 = 24,
+    Input1Current = 25,
+    Input2Voltage = 26,
+    Input2Current = 27,
+    GridVoltageDCDC = 28,
+    GridFrequencyDCDC = 29,
+    IsolationResistance = 30,
+    VbulkDCDC = 31,
+    AverageGridVoltage = 32,
+    VbulkMid = 33,
+    PowerPeak = 34,
+    PowerPeakToday = 35,
+    GridVoltageneutral = 36,
+    WindGeneratorFrequency = 37,
+    GridVoltageneutralphase = 38,
+    GridCurrentphaser = 39,
+    GridCurrentphases = 40,
+    GridCurrentphaset = 41,
+    Frequencyphaser = 42,
+    Frequencyphases = 43,
+    Frequencyphaset = 44,
+    VbulkPostitive = 45,
+    VbulkNegative = 46,
+    SupervisorTemperature = 47,
+    AlimTemperature = 48,
+    HeatSinkTemperature = 49,
+    Temperature1 = 50,
+    Temperature2 = 51,
+    Temperature3 = 52,
+    Fan1Speed = 53,
+    Fan2Speed = 54,
+    Fan3Speed = 55,
+    Fan4Speed = 56,
+    Fan5Speed = 57,
+    PowerSaturationlimit = 58,
+    RiferimentoAnelloBulk = 59,
+    Vpanelmicro = 60,
+    GridVoltagephaser = 61,
+    GridVoltagephases = 62,
+    GridVoltagephaset = 63,
+}
+impl DspRequest {
+    pub fn as_code(&self) -> anyhow::Result<u8> {
+        Ok(*self as u8)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum DspFunction {
+    State,                //50
+    PN,                   //52
+    Version,              //58
+    Measure,              //59
+    Serial,               //63
+    ManufactureDate,      //65
+    Flags,                //67
+    CumulatedFloatEnergy, //68
+    TimeDate,             //70
+    SetTimeDate,          //71
+    Firmware,             //72
+    CumulatedEnergy,      //78
+    Alarms,               //86
+}
+impl DspFunction {
+    // read functions only, for the raw debug command
+    pub fn read_from_code(code: u8) -> Option<Self> {
+        let function = match code {
+            50 => DspFunction::State,
+            52 => DspFunction::PN,
+            58 => DspFunction::Version,
+            59 => DspFunction::Measure,
+            63 => DspFunction::Serial,
+            65 => DspFunction::ManufactureDate,
+            67 => DspFunction::Flags,
+            68 => DspFunction::CumulatedFloatEnergy,
+            70 => DspFunction::TimeDate,
+            72 => DspFunction::Firmware,
+            78 => DspFunction::CumulatedEnergy,
+            86 => DspFunction::Alarms,
+            _ => return None,
+        };
+        Some(function)
+    }
+    pub fn to_code(&self) -> u8 {
+        match self {
+            DspFunction::State => 50,
+            DspFunction::PN => 52,
+            DspFunction::Version => 58,
+            DspFunction::Measure => 59,
+            DspFunction::Serial => 63,
+            DspFunction::ManufactureDate => 65,
+            DspFunction::Flags => 67,
+            DspFunction::CumulatedFloatEnergy => 68,
+            DspFunction::TimeDate => 70,
+            DspFunction::SetTimeDate => 71,
+            DspFunction::Firmware => 72,
+            DspFunction::CumulatedEnergy => 78,
+            DspFunction::Alarms => 86,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // transmission state OK, global state 6 (Run), 230.5 V as a big endian float
+    const GRID_VOLTAGE_REPLY: [u8; 8] = [0x00, 0x06, 0x43, 0x66, 0x80, 0x00, 0x35, 0xA0];
+
+    #[test]
+    fn crc_check_value() {
+        // CRC-16/X-25 catalogue check value 0x906E over "123456789"
+        assert_eq!(crc(b"123456789"), [0x6E, 0x90]);
+    }
+
+    #[test]
+    fn crc_request_frames() {
+        // serial number (63) and grid voltage measure (59, 1) requests to address 2
+        assert_eq!(crc(&[2, 63, 0, 0, 0, 0, 0, 0]), [0xA2, 0x47]);
+        assert_eq!(crc(&[2, 59, 1, 0, 0, 0, 0, 0]), [0xFF, 0x2C]);
+        assert_eq!(crc(&[2, 78, 5, 0, 0, 0, 0, 0]), [0xBC, 0xDD]);
+    }
+
+    #[test]
+    fn crc_empty_is_sentinel() {
+        assert_eq!(crc(&[]), (!0xffffu16).to_le_bytes());
+    }
+
+    #[test]
+    fn crc_leaves_frame_intact() {
+        // the way the driver's request_data fills bytes 8 and 9, no copy needed
+        let mut request = [2, 59, 1, 0, 0, 0, 0, 0, 0, 0];
+        [request[8], request[9]] = crc(&request[0..8]);
+        assert_eq!(request, [2, 59, 1, 0, 0, 0, 0, 0, 0xFF, 0x2C]);
+    }
+
+    #[test]
+    fn measure_reply_decodes() {
+        assert!(crc_valid(&GRID_VOLTAGE_REPLY));
+        assert_eq!(
+            TransmissionState::from_code(GRID_VOLTAGE_REPLY[0]),
+            TransmissionState::OK
+        );
+        let value = convert_bytes_to_f32(GRID_VOLTAGE_REPLY, FloatOrder::Big).unwrap();
+        assert_eq!(value, 230.5);
+        assert!(!implausible_f32(value));
+        let swapped = convert_bytes_to_f32(GRID_VOLTAGE_REPLY, FloatOrder::Little).unwrap();
+        assert!(implausible_f32(swapped));
+    }
+
+    #[test]
+    fn corrupt_reply_fails_crc() {
+        let mut reply = GRID_VOLTAGE_REPLY;
+        reply[3] ^= 0x01;
+        assert!(!crc_valid(&reply));
+    }
+
+    #[test]
+    fn energy_reply_decodes() {
+        // 12345 Wh from function 78
+        let reply = [0x00, 0x06, 0x00, 0x00, 0x30, 0x39, 0x00, 0x00];
+        assert_eq!(convert_bytes_to_i32(reply).unwrap(), 12_345);
+    }
+
+    #[test]
+    fn dsp_request_codes() {
+        // type byte of command 59, from the measure table of the Aurora protocol
+        let codes = [
+            // 1 Grid Voltage
+            (DspRequest::Grid, 1),
+            // 3 Grid Power
+            (DspRequest::GridPower, 3),
+            // 4 Frequency
+            (DspRequest::Frequency, 4),
+            // 21 Inverter Temperature
+            (DspRequest::InverterTemperature, 21),
+            // 23 Input 1 Voltage, 24 is unassigned
+            (DspRequest::Input1Voltage, 23),
+            // 25 Input 1 Current
+            (DspRequest::Input1Current, 25),
+            // 30 Isolation Resistance (Riso)
+            (DspRequest::IsolationResistance, 30),
+            // 34 Power Peak
+            (DspRequest::PowerPeak, 34),
+        ];
+        for (request, code) in codes {
+            assert_eq!(request.as_code().unwrap(), code);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::fmt;
+
+// Link level failures shared by the Aurora and Solax drivers.
+// Returned inside anyhow errors, callers downcast to tell a noisy line from a dead port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InverterError {
+    // nothing, or only part of a frame, before the timeout
+    Timeout,
+    CrcMismatch,
+    // Solax frame not starting with AA 55
+    BadPreamble,
+    // complete frame, shorter than its decoder reads
+    ShortFrame { len: usize, expected: usize },
+    // Aurora transmission state byte other than OK
+    TransmissionState(u8),
+    // Modbus-RTU exception code
+    ModbusException(u8),
+    // valid frame the driver has no decoder for
+    UnexpectedReply,
+    // UART driver error
+    Hardware(String),
+}
+
+impl InverterError {
+    pub fn hardware(e: impl fmt::Debug) -> Self {
+        Self::Hardware(format!("{:?}", e))
+    }
+}
+
+impl fmt::Display for InverterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "no reply before the timeout"),
+            Self::CrcMismatch => write!(f, "reply CRC mismatch"),
+            Self::BadPreamble => write!(f, "reply preamble incorrect"),
+            Self::ShortFrame { len, expected } => {
+                write!(f, "reply of {} bytes, {} needed", len, expected)
+            }
+            Self::TransmissionState(code) => write!(f, "transmission state {}", code),
+            Self::ModbusException(code) => write!(f, "Modbus exception {}", code),
+            Self::UnexpectedReply => write!(f, "reply not understood"),
+            Self::Hardware(e) => write!(f, "RS485 port error {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InverterError {}
@@ -0,0 +1,7 @@
+// Hardware free protocol and OTA upload parsing, no UART or esp-idf types, builds for the host
+pub mod aurora;
+pub mod error;
+pub mod modbus;
+pub mod multipart;
+pub mod readings;
+pub mod solax;
@@ -0,0 +1,121 @@
+#![allow(dead_code)]
+
+// Modbus-RTU read input registers, the Solax alternative to the AA 55 framing
+use crate::error::InverterError;
+use byteorder::{BigEndian, ByteOrder};
+
+pub const READ_INPUT_REGISTERS: u8 = 0x04;
+// set on the function code of an exception reply
+const EXCEPTION_FLAG: u8 = 0x80;
+// address, function, exception code, CRC
+const EXCEPTION_LEN: usize = 5;
+// address, function, byte count ... CRC
+const HEADER_LEN: usize = 3;
+const CRC_LEN: usize = 2;
+
+// CRC-16/MODBUS, reflected 0x8005, sent low byte first
+pub fn crc16(buf: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in buf {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc_valid(frame: &[u8]) -> bool {
+    let (body, crc) = frame.split_at(frame.len() - CRC_LEN);
+    crc16(body) == u16::from_le_bytes([crc[0], crc[1]])
+}
+
+pub fn read_input_registers(address: u8, start: u16, count: u16) -> Vec<u8> {
+    let mut frame = vec![address, READ_INPUT_REGISTERS, 0, 0, 0, 0];
+    BigEndian::write_u16(&mut frame[2..4], start);
+    BigEndian::write_u16(&mut frame[4..6], count);
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+pub fn reply_len(count: u16) -> usize {
+    HEADER_LEN + count as usize * 2 + CRC_LEN
+}
+
+// enough bytes to stop waiting, a whole reply or an exception
+pub fn reply_complete(address: u8, count: u16, buf: &[u8]) -> bool {
+    match buf {
+        [a, f, ..] if *a == address && *f == READ_INPUT_REGISTERS | EXCEPTION_FLAG => {
+            buf.len() >= EXCEPTION_LEN
+        }
+        _ => buf.len() >= reply_len(count),
+    }
+}
+
+// register bytes of a read input registers reply, big endian as sent
+pub fn parse_registers(address: u8, count: u16, reply: &[u8]) -> Result<&[u8], InverterError> {
+    if reply.len() < EXCEPTION_LEN {
+        return Err(InverterError::Timeout);
+    }
+    if reply[0] != address {
+        return Err(InverterError::UnexpectedReply);
+    }
+    if reply[1] == READ_INPUT_REGISTERS | EXCEPTION_FLAG {
+        return match crc_valid(&reply[..EXCEPTION_LEN]) {
+            true => Err(InverterError::ModbusException(reply[2])),
+            false => Err(InverterError::CrcMismatch),
+        };
+    }
+    if reply[1] != READ_INPUT_REGISTERS || reply[2] as usize != count as usize * 2 {
+        return Err(InverterError::UnexpectedReply);
+    }
+    let len = reply_len(count);
+    if reply.len() < len {
+        return Err(InverterError::Timeout);
+    }
+    if !crc_valid(&reply[..len]) {
+        return Err(InverterError::CrcMismatch);
+    }
+    Ok(&reply[HEADER_LEN..len - CRC_LEN])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_input_registers_request() {
+        assert_eq!(
+            read_input_registers(1, 0, 1),
+            [0x01, 0x04, 0x00, 0x00, 0x00, 0x01, 0x31, 0xCA]
+        );
+    }
+
+    #[test]
+    fn register_reply_parses() {
+        let reply = [0x01, 0x04, 0x02, 0x00, 0x7B, 0xF9, 0x13];
+        assert!(reply_complete(1, 1, &reply));
+        assert_eq!(parse_registers(1, 1, &reply), Ok(&reply[3..5]));
+    }
+
+    #[test]
+    fn exception_and_corrupt_replies() {
+        let mut exception = vec![0x01, 0x84, 0x02];
+        exception.extend_from_slice(&crc16(&exception).to_le_bytes());
+        assert!(reply_complete(1, 1, &exception));
+        assert_eq!(
+            parse_registers(1, 1, &exception),
+            Err(InverterError::ModbusException(2))
+        );
+        let corrupt = [0x01, 0x04, 0x02, 0x00, 0x7C, 0xF9, 0x13];
+        assert_eq!(
+            parse_registers(1, 1, &corrupt),
+            Err(InverterError::CrcMismatch)
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::anyhow;
+
+// part headers larger than this are treated as a malformed upload
+const MAX_HEADER_LEN: usize = 1024;
+
+// Streams the first part of a multipart/form-data body, whatever the chunk alignment.
+// Bytes that could be the start of the closing boundary are carried to the next chunk.
+pub struct MultipartFile {
+    delimiter: Vec<u8>,
+    carry: Vec<u8>,
+    in_body: bool,
+    done: bool,
+}
+
+impl MultipartFile {
+    pub fn new(boundary: &str) -> Self {
+        Self {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            carry: vec![],
+            in_body: false,
+            done: false,
+        }
+    }
+
+    // boundary parameter of a multipart/form-data Content-Type
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        if !content_type
+            .trim_start()
+            .to_ascii_lowercase()
+            .starts_with("multipart/form-data")
+        {
+            return None;
+        }
+        content_type
+            .split(';')
+            .filter_map(|param| param.trim().split_once('='))
+            .find(|(key, _)| key.eq_ignore_ascii_case("boundary"))
+            .map(|(_, boundary)| Self::new(boundary.trim_matches('"')))
+    }
+
+    // appends the file bytes found in chunk to out
+    pub fn feed(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.carry.extend_from_slice(chunk);
+        if !self.in_body {
+            match find_bytes(&self.carry, b"\r\n\r\n") {
+                Some(pos) => {
+                    self.carry.drain(..pos + 4);
+                    self.in_body = true;
+                }
+                None if self.carry.len() > MAX_HEADER_LEN => {
+                    return Err(anyhow!("multipart headers over {} bytes", MAX_HEADER_LEN))
+                }
+                None => return Ok(()),
+            }
+        }
+        match find_bytes(&self.carry, &self.delimiter) {
+            Some(pos) => {
+                out.extend_from_slice(&self.carry[..pos]);
+                self.carry.clear();
+                self.done = true;
+            }
+            None => {
+                let keep = self.delimiter.len() - 1;
+                if self.carry.len() > keep {
+                    let n = self.carry.len() - keep;
+                    out.extend(self.carry.drain(..n));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // errors if the body ended before the closing boundary
+    pub fn finish(&self) -> anyhow::Result<()> {
+        if self.done {
+            Ok(())
+        } else {
+            Err(anyhow!("multipart body ended before the closing boundary"))
+        }
+    }
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDARY: &str = "----form7MA4YWxk";
+
+    fn body(file: &[u8]) -> Vec<u8> {
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"firmware\"; filename=\"fw.bin\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            BOUNDARY
+        )
+        .into_bytes();
+        body.extend_from_slice(file);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    fn feed_chunks(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut multipart = MultipartFile::new(BOUNDARY);
+        let mut out = vec![];
+        for chunk in chunks {
+            multipart.feed(chunk, &mut out).unwrap();
+        }
+        multipart.finish().unwrap();
+        out
+    }
+
+    #[test]
+    fn delimiter_split_across_chunks() {
+        // looks like the start of a delimiter but isn't one
+        let file = b"\x00\x01\r\n--not the boundary\r\n\xff";
+        let body = body(file);
+        let start = body.len() - format!("\r\n--{}--\r\n", BOUNDARY).len();
+        // every split inside the closing "\r\n--<boundary>"
+        for split in start + 1..start + 4 + BOUNDARY.len() {
+            assert_eq!(feed_chunks(&[&body[..split], &body[split..]]), file);
+        }
+    }
+
+    #[test]
+    fn any_chunk_alignment() {
+        let file: Vec<u8> = (0..=255).collect();
+        let body = body(&file);
+        for split in 1..body.len() {
+            assert_eq!(feed_chunks(&[&body[..split], &body[split..]]), file);
+        }
+        let bytes: Vec<&[u8]> = body.chunks(1).collect();
+        assert_eq!(feed_chunks(&bytes), file);
+    }
+
+    #[test]
+    fn missing_closing_boundary() {
+        let body = body(b"firmware");
+        let mut multipart = MultipartFile::new(BOUNDARY);
+        let mut out = vec![];
+        multipart.feed(&body[..body.len() - 12], &mut out).unwrap();
+        assert!(multipart.finish().is_err());
+    }
+}
@@ -0,0 +1,518 @@
+#![allow(dead_code)]
+
+// Aurora readings decoded from replies: Dsp measurements and the cumulated energy counters
+use crate::aurora::*;
+use anyhow::*;
+use log::info;
+use serde::Serialize;
+use std::result::Result::Ok;
+use std::time::Instant;
+
+// Which cumulated energy command the inverter firmware answers
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum EnergyFunction {
+    // probed once per inverter, float readings win when the firmware has them
+    Auto,
+    // function 78, integer Wh
+    Cumulated,
+    // function 68, IEEE-754 kWh on newer firmware
+    CumulatedFloat,
+}
+
+// kWh of rounding and counter granularity allowed on top of the power limit
+const ENERGY_JUMP_SLACK: f32 = 0.1;
+// rejected readings in a row that agree with each other before they become the new
+// baseline, so one bad frame accepted earlier can't lock a counter out
+const ENERGY_REBASELINE_READS: u8 = 3;
+
+// EnergyTotals sanity check state per EnergyRequest, kept outside EnergyTotals as every
+// history snapshot copies that
+#[derive(Debug, Clone)]
+pub struct EnergyCheck {
+    // highest plant output, bounds how fast a counter can rise
+    max_kw: f32,
+    // last accepted read
+    read_at: [Option<Instant>; 6],
+    // rejected readings consistent with each other, (latest, read at, count)
+    candidate: [Option<(f32, Instant, u8)>; 6],
+}
+
+impl EnergyCheck {
+    pub fn new(max_kw: f32) -> Self {
+        Self {
+            max_kw,
+            read_at: [None; 6],
+            candidate: [None; 6],
+        }
+    }
+    // no more added than max_kw could produce in the time between. Only the period
+    // counters roll over, a drop in Total or SinceReset is a bad frame
+    fn consistent(&self, command: EnergyRequest, from: f32, at: Instant, f: f32) -> bool {
+        let rise = f - from;
+        if rise < 0.0 {
+            return !matches!(command, EnergyRequest::Total | EnergyRequest::SinceReset);
+        }
+        let hours = at.elapsed().as_secs_f32() / 3600.0;
+        rise <= self.max_kw * hours + ENERGY_JUMP_SLACK
+    }
+    // true when f should replace last_good
+    fn accept(&mut self, command: EnergyRequest, last_good: f32, f: f32) -> bool {
+        if !f.is_finite() || f < 0.0 {
+            return false;
+        }
+        let i = command as usize;
+        let accepted = match self.read_at[i] {
+            Some(at) => self.consistent(command, last_good, at, f),
+            None => true,
+        };
+        if accepted {
+            self.read_at[i] = Some(Instant::now());
+            self.candidate[i] = None;
+            return true;
+        }
+        let count = match self.candidate[i] {
+            Some((from, at, count)) if self.consistent(command, from, at, f) => count + 1,
+            _ => 1,
+        };
+        if count >= ENERGY_REBASELINE_READS {
+            info!(
+                "Energy {:?} consistently reads {} kWh, replacing {} kWh",
+                command, f, last_good
+            );
+            self.read_at[i] = Some(Instant::now());
+            self.candidate[i] = None;
+            return true;
+        }
+        self.candidate[i] = Some((f, Instant::now(), count));
+        false
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Serialize)]
+pub struct EnergyTotals {
+    day: f32,
+    week: f32,
+    month: f32,
+    year: f32,
+    total: f32,
+    since_reset: f32,
+    // kWh added to day since the previous poll, never negative across the rollover
+    day_delta: f32,
+    // total minus the total at the last gateway reset, see the firmware's energy_baseline module
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since_gateway_reset: Option<f32>,
+    #[serde(skip)]
+    baseline: Option<f32>,
+    // day total before the inverter's own midnight reset, only for the poll that saw it
+    #[serde(skip)]
+    day_reset: Option<f32>,
+    #[serde(skip)]
+    day_seen: bool,
+}
+impl EnergyTotals {
+    pub fn day(&self) -> f32 {
+        self.day
+    }
+    pub fn total(&self) -> f32 {
+        self.total
+    }
+    pub fn set_baseline(&mut self, baseline: Option<f32>) {
+        self.baseline = baseline;
+        self.update_since_gateway_reset();
+    }
+    fn update_since_gateway_reset(&mut self) {
+        // an inverter swapped under the same address would go negative
+        self.since_gateway_reset = self
+            .baseline
+            .map(|baseline| (self.total - baseline).max(0.0));
+    }
+    fn value(&self, command: EnergyRequest) -> f32 {
+        match command {
+            EnergyRequest::Day => self.day,
+            EnergyRequest::Week => self.week,
+            EnergyRequest::Month => self.month,
+            EnergyRequest::Year => self.year,
+            EnergyRequest::Total => self.total,
+            EnergyRequest::SinceReset => self.since_reset,
+        }
+    }
+    pub fn day_reset(&self) -> Option<f32> {
+        self.day_reset
+    }
+    fn update_day(&mut self, day: f32) {
+        self.day_reset = None;
+        self.day_delta = 0.0;
+        if self.day_seen {
+            if day < self.day {
+                // everything counted since the reset is new energy
+                info!("Daily energy rolled over at {} kWh", self.day);
+                self.day_reset = Some(self.day);
+                self.day_delta = day;
+            } else {
+                self.day_delta = day - self.day;
+            }
+        }
+        self.day = day;
+        self.day_seen = true;
+    }
+    pub fn update_value(
+        &mut self,
+        command: EnergyRequest,
+        function: EnergyFunction,
+        response: [u8; 8],
+        float_order: FloatOrder,
+        check: &mut EnergyCheck,
+    ) -> anyhow::Result<()> {
+        // Wh counters, scaled to kWh like the float readings
+        let f: f32 = match function {
+            EnergyFunction::CumulatedFloat => convert_bytes_to_f32(response, float_order)?,
+            _ => convert_bytes_to_i32(response)? as f32 * 0.001,
+        };
+        // a bad frame would otherwise stick in the lifetime counters, the last good value stays
+        if !check.accept(command, self.value(command), f) {
+            info!(
+                "Energy {:?} reading {} kWh rejected, last good {} kWh",
+                command,
+                f,
+                self.value(command)
+            );
+            return Ok(());
+        }
+        match command {
+            EnergyRequest::Day => self.update_day(f),
+            EnergyRequest::Week => self.week = f,
+            EnergyRequest::Month => self.month = f,
+            EnergyRequest::Year => self.year = f,
+            EnergyRequest::Total => {
+                self.total = f;
+                self.update_since_gateway_reset();
+            }
+            EnergyRequest::SinceReset => self.since_reset = f,
+        }
+        Ok(())
+    }
+}
+#[derive(Debug, Copy, Clone)]
+pub enum EnergyRequest {
+    Day,
+    Week,
+    Month,
+    Year,
+    Total,
+    SinceReset,
+}
+
+impl EnergyRequest {
+    pub fn as_code(&self) -> Result<u8> {
+        Ok(match self {
+            Self::Day => 0,
+            Self::Week => 1,
+            Self::Month => 3,
+            Self::Year => 4,
+            Self::Total => 5,
+            Self::SinceReset => 6,
+        })
+    }
+    // function 68 numbers the periods 1..=6 without 78's gap at 2
+    pub fn as_float_code(&self) -> Result<u8> {
+        Ok(match self {
+            Self::Day => 1,
+            Self::Week => 2,
+            Self::Month => 3,
+            Self::Year => 4,
+            Self::Total => 5,
+            Self::SinceReset => 6,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Serialize)]
+pub struct Dsp {
+    // grid voltage (measure code 1), the name is kept for the existing <id>/grid topic
+    pub grid: f32,
+    pub current: f32,
+    pub gridpower: f32,
+    pub frequency: f32,
+    pub vbulk: f32,
+    pub ileakdc: f32,
+    pub ileak: f32,
+    pub pin1: f32,
+    pub pin2: f32,
+    pub invertertemperature: f32,
+    pub boostertemperature: f32,
+    pub input1voltage: f32,
+    pub input1current: f32,
+    pub input2voltage: f32,
+    pub input2current: f32,
+    pub powerpeak: f32,
+    pub powerpeaktoday: f32,
+    #[serde(skip_serializing)]
+    pub gridvoltagedcdc: f32,
+    #[serde(skip_serializing)]
+    pub gridfrequencydcdc: f32,
+    #[serde(skip_serializing)]
+    pub isolationresistance: f32,
+    #[serde(skip_serializing)]
+    pub vbulkdcdc: f32,
+    #[serde(skip_serializing)]
+    pub averagegridvoltage: f32,
+    #[serde(skip_serializing)]
+    pub vbulkmid: f32,
+    #[serde(skip_serializing)]
+    pub gridvoltageneutral: f32,
+    #[serde(skip_serializing)]
+    pub windgeneratorfrequency: f32,
+    #[serde(skip_serializing)]
+    pub gridvoltageneutralphase: f32,
+    #[serde(skip_serializing)]
+    pub gridcurrentphaser: f32,
+    #[serde(skip_serializing)]
+    pub gridcurrentphases: f32,
+    #[serde(skip_serializing)]
+    pub gridcurrentphaset: f32,
+    #[serde(skip_serializing)]
+    pub frequencyphaser: f32,
+    #[serde(skip_serializing)]
+    pub frequencyphases: f32,
+    #[serde(skip_serializing)]
+    pub frequencyphaset: f32,
+    #[serde(skip_serializing)]
+    pub vbulkpostitive: f32,
+    #[serde(skip_serializing)]
+    pub vbulknegative: f32,
+    #[serde(skip_serializing)]
+    pub supervisortemperature: f32,
+    #[serde(skip_serializing)]
+    pub alimtemperature: f32,
+    #[serde(skip_serializing)]
+    pub heatsinktemperature: f32,
+    #[serde(skip_serializing)]
+    pub powersaturationlimit: f32,
+    #[serde(skip_serializing)]
+    pub riferimentoanellobulk: f32,
+    #[serde(skip_serializing)]
+    pub vpanelmicro: f32,
+    #[serde(skip_serializing)]
+    pub gridvoltagephaser: f32,
+    #[serde(skip_serializing)]
+    pub gridvoltagephases: f32,
+    #[serde(skip_serializing)]
+    pub gridvoltagephaset: f32,
+}
+
+// Published Dsp field: the measure request behind it, scale from the raw float and its unit
+#[derive(Copy, Clone)]
+pub struct DspFieldMeta {
+    pub field: &'static str,
+    pub request: DspRequest,
+    pub scale: f32,
+    pub unit: &'static str,
+    // Home Assistant device_class
+    pub device_class: &'static str,
+}
+
+const fn meta(
+    field: &'static str,
+    request: DspRequest,
+    scale: f32,
+    unit: &'static str,
+    device_class: &'static str,
+) -> DspFieldMeta {
+    DspFieldMeta {
+        field,
+        request,
+        scale,
+        unit,
+        device_class,
+    }
+}
+
+// polled each cycle unless the config narrows it, source for HA discovery and /metrics; power arrives in W
+pub const DSP_FIELDS: [DspFieldMeta; 17] = [
+    meta("grid", DspRequest::Grid, 1.0, "V", "voltage"),
+    meta("current", DspRequest::Current, 1.0, "A", "current"),
+    meta("gridpower", DspRequest::GridPower, 0.001, "kW", "power"),
+    meta("frequency", DspRequest::Frequency, 1.0, "Hz", "frequency"),
+    meta("vbulk", DspRequest::Vbulk, 1.0, "V", "voltage"),
+    meta("ileakdc", DspRequest::IleakDc, 1.0, "A", "current"),
+    meta("ileak", DspRequest::Ileak, 1.0, "A", "current"),
+    meta("pin1", DspRequest::Pin1, 0.001, "kW", "power"),
+    meta("pin2", DspRequest::Pin2, 0.001, "kW", "power"),
+    meta(
+        "invertertemperature",
+        DspRequest::InverterTemperature,
+        1.0,
+        "°C",
+        "temperature",
+    ),
+    meta(
+        "boostertemperature",
+        DspRequest::BoosterTemperature,
+        1.0,
+        "°C",
+        "temperature",
+    ),
+    meta(
+        "input1voltage",
+        DspRequest::Input1Voltage,
+        1.0,
+        "V",
+        "voltage",
+    ),
+    meta(
+        "input1current",
+        DspRequest::Input1Current,
+        1.0,
+        "A",
+        "current",
+    ),
+    meta(
+        "input2voltage",
+        DspRequest::Input2Voltage,
+        1.0,
+        "V",
+        "voltage",
+    ),
+    meta(
+        "input2current",
+        DspRequest::Input2Current,
+        1.0,
+        "A",
+        "current",
+    ),
+    meta("powerpeak", DspRequest::PowerPeak, 0.001, "kW", "power"),
+    meta(
+        "powerpeaktoday",
+        DspRequest::PowerPeakToday,
+        0.001,
+        "kW",
+        "power",
+    ),
+];
+
+impl Dsp {
+    // f already scaled, see dsp_scale
+    pub fn update_value(&mut self, command: DspRequest, f: f32) {
+        match command {
+            DspRequest::Grid => self.grid = f,
+            DspRequest::Current => self.current = f,
+            DspRequest::GridPower => self.gridpower = f,
+            DspRequest::Frequency => self.frequency = f,
+            DspRequest::Vbulk => self.vbulk = f,
+            DspRequest::IleakDc => self.ileakdc = f,
+            DspRequest::Ileak => self.ileak = f,
+            DspRequest::Pin1 => self.pin1 = f,
+            DspRequest::Pin2 => self.pin2 = f,
+            DspRequest::InverterTemperature => self.invertertemperature = f,
+            DspRequest::BoosterTemperature => self.boostertemperature = f,
+            DspRequest::Input1Voltage => self.input1voltage = f,
+            DspRequest::Input1Current => self.input1current = f,
+            DspRequest::Input2Voltage => self.input2voltage = f,
+            DspRequest::Input2Current => self.input2current = f,
+            DspRequest::IsolationResistance => self.isolationresistance = f,
+            DspRequest::VbulkDCDC => self.vbulkdcdc = f,
+            DspRequest::AverageGridVoltage => self.averagegridvoltage = f,
+            DspRequest::VbulkMid => self.vbulkmid = f,
+            DspRequest::PowerPeak => self.powerpeak = f,
+            DspRequest::PowerPeakToday => self.powerpeaktoday = f,
+            DspRequest::HeatSinkTemperature => self.heatsinktemperature = f,
+            _ => {
+                info!("Not supported");
+            }
+        }
+    }
+}
+
+// fields outside DSP_FIELDS are stored raw
+pub fn dsp_scale(request: DspRequest) -> f32 {
+    DSP_FIELDS
+        .iter()
+        .find(|meta| meta.request as usize == request as usize)
+        .map_or(1.0, |meta| meta.scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // OK reply, global state 6 (Run), the 4 value bytes and the CRC
+    fn reply(value: [u8; 4]) -> [u8; 8] {
+        let mut reply = [0x00, 0x06, value[0], value[1], value[2], value[3], 0, 0];
+        [reply[6], reply[7]] = crc(&reply[0..6]);
+        reply
+    }
+
+    fn energy(totals: &mut EnergyTotals, check: &mut EnergyCheck, command: EnergyRequest, wh: i32) {
+        let function = EnergyFunction::Cumulated;
+        let response = reply(wh.to_be_bytes());
+        assert!(crc_valid(&response));
+        totals
+            .update_value(command, function, response, FloatOrder::Big, check)
+            .unwrap();
+    }
+
+    // Wh counters are scaled by 0.001 in f32
+    fn assert_kwh(kwh: f32, expected: f32) {
+        assert!(
+            (kwh - expected).abs() < 1e-3,
+            "{} kWh, expected {}",
+            kwh,
+            expected
+        );
+    }
+
+    #[test]
+    fn measure_reply_updates_field() {
+        let mut dsp = Dsp::default();
+        for (request, value, expected) in [
+            (DspRequest::Grid, 230.5f32, 230.5),
+            (DspRequest::GridPower, 1520.0, 1.52),
+        ] {
+            let response = reply(value.to_be_bytes());
+            assert!(crc_valid(&response));
+            let f = convert_bytes_to_f32(response, FloatOrder::Big).unwrap();
+            dsp.update_value(request, f * dsp_scale(request));
+            let field = match request {
+                DspRequest::Grid => dsp.grid,
+                _ => dsp.gridpower,
+            };
+            assert_kwh(field, expected);
+        }
+    }
+
+    #[test]
+    fn day_counter_drops_to_zero() {
+        let mut totals = EnergyTotals::default();
+        let mut check = EnergyCheck::new(100.0);
+        energy(&mut totals, &mut check, EnergyRequest::Day, 12_345);
+        assert_kwh(totals.day(), 12.345);
+        assert_eq!(totals.day_reset(), None);
+        // the inverter's midnight
+        energy(&mut totals, &mut check, EnergyRequest::Day, 0);
+        assert_kwh(totals.day(), 0.0);
+        assert_kwh(totals.day_reset().unwrap(), 12.345);
+        assert_kwh(totals.day_delta, 0.0);
+        energy(&mut totals, &mut check, EnergyRequest::Day, 50);
+        assert_eq!(totals.day_reset(), None);
+        assert_kwh(totals.day_delta, 0.05);
+    }
+
+    #[test]
+    fn total_drop_rejected_then_rebaselined() {
+        let mut totals = EnergyTotals::default();
+        let mut check = EnergyCheck::new(100.0);
+        energy(&mut totals, &mut check, EnergyRequest::Total, 1_000_000);
+        assert_kwh(totals.total(), 1_000.0);
+        // a lifetime counter never goes down, one bad frame is dropped
+        energy(&mut totals, &mut check, EnergyRequest::Total, 400_000);
+        assert_kwh(totals.total(), 1_000.0);
+        energy(&mut totals, &mut check, EnergyRequest::Total, 400_000);
+        assert_kwh(totals.total(), 1_000.0);
+        // the third consistent reading replaces the earlier value
+        energy(&mut totals, &mut check, EnergyRequest::Total, 400_000);
+        assert_kwh(totals.total(), 400.0);
+        // and is the new baseline for the plausibility check
+        energy(&mut totals, &mut check, EnergyRequest::Total, 400_010);
+        assert_kwh(totals.total(), 400.01);
+    }
+}
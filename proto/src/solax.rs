@@ -0,0 +1,715 @@
+#![allow(dead_code)]
+
+// Solax X1 Air frames and payload decoders, the firmware's solax_x1_air module is the driver
+
+use crate::error::InverterError;
+use byteorder::{BigEndian, ByteOrder};
+use log::info;
+use serde::Serialize;
+
+// header (preamble, addresses, control, function, length) plus 2 byte checksum
+pub const MIN_FRAME_LEN: usize = 11;
+// control and function codes follow the preamble and addresses
+pub const CONTROL_OFFSET: usize = 6;
+pub const FUNCTION_OFFSET: usize = 7;
+// data length byte follows preamble, addresses, control and function
+pub const LEN_OFFSET: usize = 8;
+// replies echo the request function with the top bit set
+pub const REPLY_FLAG: u8 = 0x80;
+// live data payload after the header, up to the error code ending at byte 59
+pub const LIVE_DATA_OFFSET: usize = 9;
+pub const LIVE_DATA_REGISTERS: u16 = 25;
+// shortest frame each decoder reads to the end of, CRC not included
+const LIVE_DATA_LEN: usize = 59;
+const QUERY_ID_LEN: usize = 66;
+const QUERY_CONFIG_LEN: usize = 77;
+
+// a truncated frame off a noisy line must not index past the end
+fn check_len(response: &[u8], len: usize) -> Result<(), InverterError> {
+    if response.len() < len {
+        return Err(InverterError::ShortFrame {
+            len: response.len(),
+            expected: len,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Data {
+    pub livedata: LiveData,
+    pub id: QueryID,
+    pub config: QueryConfig,
+}
+
+// https://github.com/syssi/esphome-modbus-solax-x1
+#[allow(non_snake_case)]
+#[allow(clippy::upper_case_acronyms)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, Serialize, Default)]
+pub enum Safety {
+    VDE0126,
+    VDE4105,
+    AS4777,
+    G98,
+    C10_11,
+    TOR,
+    EN50438_NL,
+    Denmark2019_W,
+    CEB,
+    Cyprus2019,
+    cNRS097_2_1,
+    VDE0126_Greece,
+    UTE_C15_712_Fr,
+    IEC61727,
+    G99,
+    CQC,
+    VDE0126_Greece_is,
+    C15_712_Fr_island_50,
+    C15_712_Fr_island_60,
+    Guyana,
+    MEA_Thailand,
+    PEA_Thailand,
+    cNewZealand,
+    cIreland,
+    cCE10_21,
+    cRD1699,
+    EN50438_Sweden,
+    EN50549_PL,
+    Czech_PPDS,
+    EN50438_Norway,
+    EN50438_Portug,
+    cCQC_WideRange,
+    BRAZIL,
+    EN50438_CEZ,
+    IEC_Chile,
+    Sri_Lanka,
+    BRAZIL_240,
+    EN50549_SK,
+    EN50549_EU,
+    G98_NI,
+    Denmark2019_E,
+    #[default]
+    Unknown,
+}
+// unit variants serialise as their names, e.g. "PermanentFault"
+#[derive(Debug, Serialize, Default)]
+pub enum RunMode {
+    Wait,
+    Check,
+    Normal,
+    Fault,
+    PermanentFault,
+    UpdateMode,
+    #[default]
+    Unknown,
+}
+
+impl RunMode {
+    // HA enum sensor options, Debug names match the serialised ones
+    pub const ALL: [RunMode; 7] = [
+        RunMode::Wait,
+        RunMode::Check,
+        RunMode::Normal,
+        RunMode::Fault,
+        RunMode::PermanentFault,
+        RunMode::UpdateMode,
+        RunMode::Unknown,
+    ];
+}
+
+#[derive(Debug, Serialize, Default)]
+pub enum ErrorCode {
+    None,
+    MainsLostFault,
+    GridVoltFault,
+    GridFreqFault,
+    PvVoltFault,
+    IsolationFault,
+    TemperatureOverFault,
+    FanFault,
+    OtherDeviceFault,
+    #[default]
+    Unknown,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 10] = [
+        ErrorCode::None,
+        ErrorCode::MainsLostFault,
+        ErrorCode::GridVoltFault,
+        ErrorCode::GridFreqFault,
+        ErrorCode::PvVoltFault,
+        ErrorCode::IsolationFault,
+        ErrorCode::TemperatureOverFault,
+        ErrorCode::FanFault,
+        ErrorCode::OtherDeviceFault,
+        ErrorCode::Unknown,
+    ];
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct LiveData {
+    pub temperature: u16,
+    pub energy_today: u16,
+    pub dc1_voltage: u16,
+    pub dc2_voltage: u16,
+    pub dc1_current: u16,
+    pub dc2_current: u16,
+    pub current: u16,
+    pub voltage: u16,
+    pub frequency: u16,
+    pub active_power: u16,
+    pub import_active: u32,
+    pub runtime_total: u32,
+    pub run_mode: RunMode,
+    pub error_code: ErrorCode,
+}
+impl LiveData {
+    // Unknown is also the value before the first decode, so not a fault
+    pub fn is_faulted(&self) -> bool {
+        matches!(self.run_mode, RunMode::Fault | RunMode::PermanentFault)
+            || !matches!(self.error_code, ErrorCode::None | ErrorCode::Unknown)
+    }
+    // Modbus input registers in the same order as the AA 55 live data payload
+    pub fn from_registers(registers: &[u8]) -> Result<LiveData, InverterError> {
+        let mut response = vec![0; LIVE_DATA_OFFSET];
+        response.extend_from_slice(registers);
+        response.resize(LIVE_DATA_OFFSET + LIVE_DATA_REGISTERS as usize * 2, 0);
+        Self::decode(&response)
+    }
+    pub fn decode(response: &[u8]) -> Result<LiveData, InverterError> {
+        check_len(response, LIVE_DATA_LEN)?;
+        Ok(Self {
+            temperature: BigEndian::read_u16(&response[9..]),
+            energy_today: BigEndian::read_u16(&response[11..]),
+            dc1_voltage: BigEndian::read_u16(&response[13..]),
+            dc2_voltage: BigEndian::read_u16(&response[15..]),
+            dc1_current: BigEndian::read_u16(&response[17..]),
+            dc2_current: BigEndian::read_u16(&response[19..]),
+            current: BigEndian::read_u16(&response[21..]),
+            voltage: BigEndian::read_u16(&response[23..]),
+            frequency: BigEndian::read_u16(&response[25..]),
+            active_power: BigEndian::read_u16(&response[27..]),
+            import_active: BigEndian::read_u32(&response[31..]),
+            runtime_total: BigEndian::read_u32(&response[35..]),
+            run_mode: match BigEndian::read_u16(&response[39..]) {
+                0 => RunMode::Wait,
+                1 => RunMode::Check,
+                2 => RunMode::Normal,
+                3 => RunMode::Fault,
+                4 => RunMode::PermanentFault,
+                5 => RunMode::UpdateMode,
+                _ => RunMode::Unknown,
+            },
+            error_code: match BigEndian::read_u32(&response[55..]) {
+                0 => ErrorCode::None,
+                1 => ErrorCode::MainsLostFault,
+                2 => ErrorCode::GridVoltFault,
+                3 => ErrorCode::GridFreqFault,
+                4 => ErrorCode::PvVoltFault,
+                5 => ErrorCode::IsolationFault,
+                6 => ErrorCode::TemperatureOverFault,
+                7 => ErrorCode::FanFault,
+                8 => ErrorCode::OtherDeviceFault,
+                _ => ErrorCode::Unknown,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct QueryID {
+    pub inverter_phases: u8,
+    pub bus_power: String,
+    pub firmware_version: String,
+    pub module_name: String,
+    pub factory_name: String,
+    pub serial_number: String,
+    pub rated_bus_voltage: String,
+}
+
+impl QueryID {
+    pub fn decode(response: &[u8]) -> Result<QueryID, InverterError> {
+        check_len(response, QUERY_ID_LEN)?;
+        Ok(Self {
+            inverter_phases: response[9],
+            bus_power: String::from_utf8_lossy(&response[10..15]).to_string(),
+            firmware_version: String::from_utf8_lossy(&response[16..20]).to_string(),
+            module_name: String::from_utf8_lossy(&response[21..34]).to_string(),
+            factory_name: String::from_utf8_lossy(&response[35..48]).to_string(),
+            serial_number: String::from_utf8_lossy(&response[49..62]).to_string(),
+            rated_bus_voltage: String::from_utf8_lossy(&response[63..66]).to_string(),
+        })
+    }
+}
+
+#[allow(non_snake_case)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, Serialize)]
+pub struct QueryConfig {
+    pub wVpvStart: u16,
+    pub wTimeStart: u16,
+    pub wVacMinProtect: u16,
+    pub wVacMaxProtect: u16,
+    pub wFacMinProtect: u16,
+    pub wFacMaxProtect: u16,
+    pub wDciLimits: u16,
+    pub wGrid10MinAvgProtect: u16,
+    pub wVacMinSlowProtect: u16,
+    pub wVacMaxSlowProtect: u16,
+    pub wFacMinSlowProtect: u16,
+    pub wFacMaxSlowProtect: u16,
+    pub wSafety: Safety,
+    pub wPowerfactor_mode: u8,
+    pub wPowerfactor_data: u8,
+    pub wUpperLimit: u8,
+    pub wLowerLimit: u8,
+    pub wPowerLow: u8,
+    pub wPowerUp: u8,
+    pub Qpower_set: u16,
+    pub WFreqSetPoint: u16,
+    pub WFreqDroopRate: u16,
+    pub QuVupRate: u16,
+    pub QuVlowRate: u16,
+    pub WPowerLimitsPercent: u16,
+    pub WWgra: u16,
+    pub wWv2: u16,
+    pub wWv3: u16,
+    pub wWv4: u16,
+    pub wQurangeV1: u16,
+    pub wQurangeV4: u16,
+    pub BVoltPowerLimtit: u16,
+    pub WPowerManagerEnable: u16,
+    pub WGlobalSeachMPPTStrartFlg: u16,
+    pub WFrqProtectRestrictive: u16,
+    pub WQuDelayTimer: u16,
+    pub WFreqActivePowerDelayTimer: u16,
+}
+
+impl QueryConfig {
+    pub fn decode(response: &[u8]) -> Result<QueryConfig, InverterError> {
+        check_len(response, QUERY_CONFIG_LEN)?;
+        Ok(Self {
+            wVpvStart: BigEndian::read_u16(&response[9..]),
+            wTimeStart: BigEndian::read_u16(&response[11..]),
+            wVacMinProtect: BigEndian::read_u16(&response[13..]),
+            wVacMaxProtect: BigEndian::read_u16(&response[15..]),
+            wFacMinProtect: BigEndian::read_u16(&response[17..]),
+            wFacMaxProtect: BigEndian::read_u16(&response[19..]),
+            wDciLimits: BigEndian::read_u16(&response[21..]),
+            wGrid10MinAvgProtect: BigEndian::read_u16(&response[23..]),
+            wVacMinSlowProtect: BigEndian::read_u16(&response[25..]),
+            wVacMaxSlowProtect: BigEndian::read_u16(&response[27..]),
+            wFacMinSlowProtect: BigEndian::read_u16(&response[29..]),
+            wFacMaxSlowProtect: BigEndian::read_u16(&response[31..]),
+            wSafety: match BigEndian::read_u16(&response[33..]) {
+                0 => Safety::VDE0126,
+                1 => Safety::VDE4105,
+                2 => Safety::AS4777,
+                3 => Safety::G98,
+                4 => Safety::C10_11,
+                5 => Safety::TOR,
+                6 => Safety::EN50438_NL,
+                7 => Safety::Denmark2019_W,
+                8 => Safety::CEB,
+                9 => Safety::Cyprus2019,
+                10 => Safety::cNRS097_2_1,
+                11 => Safety::VDE0126_Greece,
+                12 => Safety::UTE_C15_712_Fr,
+                13 => Safety::IEC61727,
+                14 => Safety::G99,
+                15 => Safety::CQC,
+                16 => Safety::VDE0126_Greece_is,
+                17 => Safety::C15_712_Fr_island_50,
+                18 => Safety::C15_712_Fr_island_60,
+                19 => Safety::Guyana,
+                20 => Safety::MEA_Thailand,
+                21 => Safety::PEA_Thailand,
+                22 => Safety::cNewZealand,
+                23 => Safety::cIreland,
+                24 => Safety::cCE10_21,
+                25 => Safety::cRD1699,
+                26 => Safety::EN50438_Sweden,
+                27 => Safety::EN50549_PL,
+                28 => Safety::Czech_PPDS,
+                29 => Safety::EN50438_Norway,
+                30 => Safety::EN50438_Portug,
+                31 => Safety::cCQC_WideRange,
+                32 => Safety::BRAZIL,
+                33 => Safety::EN50438_CEZ,
+                34 => Safety::IEC_Chile,
+                35 => Safety::Sri_Lanka,
+                36 => Safety::BRAZIL_240,
+                37 => Safety::EN50549_SK,
+                38 => Safety::EN50549_EU,
+                39 => Safety::G98_NI,
+                40 => Safety::Denmark2019_E,
+                _ => Safety::Unknown,
+            },
+            wPowerfactor_mode: response[35],
+            wPowerfactor_data: response[36],
+            wUpperLimit: response[37],
+            wLowerLimit: response[38],
+            wPowerLow: response[39],
+            wPowerUp: response[40],
+            Qpower_set: BigEndian::read_u16(&response[41..]),
+            WFreqSetPoint: BigEndian::read_u16(&response[43..]),
+            WFreqDroopRate: BigEndian::read_u16(&response[45..]),
+            QuVupRate: BigEndian::read_u16(&response[47..]),
+            QuVlowRate: BigEndian::read_u16(&response[49..]),
+            WPowerLimitsPercent: BigEndian::read_u16(&response[51..]),
+            WWgra: BigEndian::read_u16(&response[53..]),
+            wWv2: BigEndian::read_u16(&response[55..]),
+            wWv3: BigEndian::read_u16(&response[57..]),
+            wWv4: BigEndian::read_u16(&response[59..]),
+            wQurangeV1: BigEndian::read_u16(&response[61..]),
+            wQurangeV4: BigEndian::read_u16(&response[63..]),
+            BVoltPowerLimtit: BigEndian::read_u16(&response[65..]),
+            WPowerManagerEnable: BigEndian::read_u16(&response[67..]),
+            WGlobalSeachMPPTStrartFlg: BigEndian::read_u16(&response[69..]),
+            WFrqProtectRestrictive: BigEndian::read_u16(&response[71..]),
+            WQuDelayTimer: BigEndian::read_u16(&response[73..]),
+            WFreqActivePowerDelayTimer: BigEndian::read_u16(&response[75..]),
+        })
+    }
+}
+
+// QueryConfig in units, V and Hz scaled from the 0.1 V and 0.01 Hz registers. Registers
+// without a documented scale keep their raw value
+#[derive(Debug, Serialize)]
+pub struct GridSettings {
+    // V
+    pub pv_start_voltage: f32,
+    pub start_delay_secs: u16,
+    // V
+    pub grid_voltage_min: f32,
+    pub grid_voltage_max: f32,
+    // Hz
+    pub grid_frequency_min: f32,
+    pub grid_frequency_max: f32,
+    pub dci_limit_ma: u16,
+    // V
+    pub grid_voltage_10min_avg_max: f32,
+    pub grid_voltage_min_slow: f32,
+    pub grid_voltage_max_slow: f32,
+    // Hz
+    pub grid_frequency_min_slow: f32,
+    pub grid_frequency_max_slow: f32,
+    // grid code
+    pub safety: Safety,
+    pub power_factor_mode: u8,
+    pub power_factor: f32,
+    pub power_factor_upper_limit: f32,
+    pub power_factor_lower_limit: f32,
+    pub power_low_percent: u8,
+    pub power_up_percent: u8,
+    pub reactive_power_set: u16,
+    // Hz
+    pub frequency_set_point: f32,
+    pub frequency_droop_rate: u16,
+    pub qu_voltage_up_rate: u16,
+    pub qu_voltage_low_rate: u16,
+    pub power_limit_percent: u16,
+    pub power_gradient: u16,
+    // Q(U) curve points, V
+    pub qu_voltage_2: f32,
+    pub qu_voltage_3: f32,
+    pub qu_voltage_4: f32,
+    pub qu_range_voltage_1: f32,
+    pub qu_range_voltage_4: f32,
+    pub battery_voltage_power_limit: u16,
+    pub power_manager_enabled: bool,
+    pub global_mppt_search: bool,
+    pub frequency_protect_restrictive: bool,
+    pub qu_delay_secs: u16,
+    pub frequency_active_power_delay_secs: u16,
+}
+
+impl From<&QueryConfig> for GridSettings {
+    fn from(raw: &QueryConfig) -> Self {
+        let volts = |register: u16| register as f32 * 0.1;
+        let hertz = |register: u16| register as f32 * 0.01;
+        let ratio = |register: u8| register as f32 * 0.01;
+        Self {
+            pv_start_voltage: volts(raw.wVpvStart),
+            start_delay_secs: raw.wTimeStart,
+            grid_voltage_min: volts(raw.wVacMinProtect),
+            grid_voltage_max: volts(raw.wVacMaxProtect),
+            grid_frequency_min: hertz(raw.wFacMinProtect),
+            grid_frequency_max: hertz(raw.wFacMaxProtect),
+            dci_limit_ma: raw.wDciLimits,
+            grid_voltage_10min_avg_max: volts(raw.wGrid10MinAvgProtect),
+            grid_voltage_min_slow: volts(raw.wVacMinSlowProtect),
+            grid_voltage_max_slow: volts(raw.wVacMaxSlowProtect),
+            grid_frequency_min_slow: hertz(raw.wFacMinSlowProtect),
+            grid_frequency_max_slow: hertz(raw.wFacMaxSlowProtect),
+            safety: raw.wSafety,
+            power_factor_mode: raw.wPowerfactor_mode,
+            power_factor: ratio(raw.wPowerfactor_data),
+            power_factor_upper_limit: ratio(raw.wUpperLimit),
+            power_factor_lower_limit: ratio(raw.wLowerLimit),
+            power_low_percent: raw.wPowerLow,
+            power_up_percent: raw.wPowerUp,
+            reactive_power_set: raw.Qpower_set,
+            frequency_set_point: hertz(raw.WFreqSetPoint),
+            frequency_droop_rate: raw.WFreqDroopRate,
+            qu_voltage_up_rate: raw.QuVupRate,
+            qu_voltage_low_rate: raw.QuVlowRate,
+            power_limit_percent: raw.WPowerLimitsPercent,
+            power_gradient: raw.WWgra,
+            qu_voltage_2: volts(raw.wWv2),
+            qu_voltage_3: volts(raw.wWv3),
+            qu_voltage_4: volts(raw.wWv4),
+            qu_range_voltage_1: volts(raw.wQurangeV1),
+            qu_range_voltage_4: volts(raw.wQurangeV4),
+            battery_voltage_power_limit: raw.BVoltPowerLimtit,
+            power_manager_enabled: raw.WPowerManagerEnable != 0,
+            global_mppt_search: raw.WGlobalSeachMPPTStrartFlg != 0,
+            frequency_protect_restrictive: raw.WFrqProtectRestrictive != 0,
+            qu_delay_secs: raw.WQuDelayTimer,
+            frequency_active_power_delay_secs: raw.WFreqActivePowerDelayTimer,
+        }
+    }
+}
+
+pub fn has_preamble(frame: &[u8]) -> bool {
+    frame.len() >= 2 && frame[0] == 0xAA && frame[1] == 0x55
+}
+
+// reply carries the request's control code and its function with REPLY_FLAG set
+pub fn answers(request: &[u8], reply: &[u8]) -> bool {
+    request.len() > FUNCTION_OFFSET
+        && reply.len() > FUNCTION_OFFSET
+        && reply[CONTROL_OFFSET] == request[CONTROL_OFFSET]
+        && reply[FUNCTION_OFFSET] == request[FUNCTION_OFFSET] | REPLY_FLAG
+}
+
+// complete frames by their length byte, noise between them and a partial tail are skipped
+pub fn split_frames(buf: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = vec![];
+    let mut start = 0;
+    while buf.len() - start >= MIN_FRAME_LEN {
+        let rest = &buf[start..];
+        if !has_preamble(rest) {
+            start += 1;
+            continue;
+        }
+        let len = MIN_FRAME_LEN + rest[LEN_OFFSET] as usize;
+        if rest.len() < len {
+            break;
+        }
+        frames.push(rest[..len].to_vec());
+        start += len;
+    }
+    frames
+}
+
+pub fn send_broadcast_message() -> Vec<u8> {
+    let mut request: Vec<u8> = vec![0xAA, 0x55, 0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00];
+    request.extend(calc_partial_crc(&request));
+    request
+}
+
+pub fn register_inverter(payload: &[u8], inverter_address: u8) -> Result<Vec<u8>, InverterError> {
+    let serial_number = extract_serial_number(payload)?;
+    info!(
+        "Discovered serial number {:?}",
+        String::from_utf8_lossy(&serial_number)
+    );
+    let mut message: Vec<u8> = vec![0xAA, 0x55, 0x00, 0x00, 0x00, 0x00, 0x10, 0x01, 0x0F];
+    message.extend(serial_number);
+    message.extend([inverter_address]);
+    let crc: Vec<u8> = calc_partial_crc(&message);
+    Ok(message.iter().chain(&crc).copied().collect())
+}
+
+pub fn request_live_data() -> Vec<u8> {
+    let mut request: Vec<u8> = vec![0xAA, 0x55, 0x01, 0x00, 0x00, 0x0A, 0x11, 0x02, 0x00];
+    request.extend(calc_partial_crc(&request));
+    request
+}
+
+pub fn request_query_id_data() -> Vec<u8> {
+    let mut request: Vec<u8> = vec![0xAA, 0x55, 0x01, 0x00, 0x00, 0x0A, 0x11, 0x03, 0x00];
+    request.extend(calc_partial_crc(&request));
+    request
+}
+
+pub fn request_config_data() -> Vec<u8> {
+    let mut request: Vec<u8> = vec![0xAA, 0x55, 0x01, 0x00, 0x00, 0x0A, 0x11, 0x04, 0x00];
+    request.extend(calc_partial_crc(&request));
+    request
+}
+
+pub fn extract_serial_number(payload: &[u8]) -> Result<Vec<u8>, InverterError> {
+    check_len(payload, 23)?;
+    Ok(payload[9..23].to_vec())
+}
+
+pub fn extract_crc(payload: &[u8]) -> Vec<u8> {
+    payload.iter().rev().take(2).rev().copied().collect()
+}
+
+// Solax checksum is the byte sum truncated to 16 bits, so overflow wraps by design
+pub fn calc_partial_crc(payload: &Vec<u8>) -> Vec<u8> {
+    let mut val: u16 = 0;
+    for i in payload {
+        val = val.wrapping_add(*i as u16);
+    }
+    vec![(val >> 8) as u8, val as u8]
+}
+
+pub fn check_crc(i_payload: &[u8]) -> bool {
+    if i_payload.len() < 2 {
+        return false;
+    }
+    let mut payload = i_payload.to_owned();
+    payload.pop().unwrap();
+    payload.pop().unwrap();
+    let crc_should_be = calc_partial_crc(&payload);
+    let i_crc: Vec<u8> = extract_crc(i_payload);
+    crc_should_be == i_crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // reply from inverter address 0x0A: header, payload, byte sum checksum
+    fn reply(function: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0xAA, 0x55, 0x00, 0x0A, 0x01, 0x00, 0x11, function];
+        frame.push(payload.len() as u8);
+        frame.extend_from_slice(payload);
+        frame.extend(calc_partial_crc(&frame));
+        frame
+    }
+
+    fn live_data_payload() -> Vec<u8> {
+        let mut payload = vec![0u8; LIVE_DATA_LEN - LIVE_DATA_OFFSET];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            let start = offset - LIVE_DATA_OFFSET;
+            payload[start..start + bytes.len()].copy_from_slice(bytes);
+        };
+        put(9, &31u16.to_be_bytes());
+        put(11, &42u16.to_be_bytes());
+        put(13, &2_950u16.to_be_bytes());
+        put(23, &2_405u16.to_be_bytes());
+        put(25, &4_998u16.to_be_bytes());
+        put(27, &1_520u16.to_be_bytes());
+        put(31, &123_456u32.to_be_bytes());
+        put(35, &8_760u32.to_be_bytes());
+        put(39, &2u16.to_be_bytes());
+        put(55, &0u32.to_be_bytes());
+        payload
+    }
+
+    #[test]
+    fn live_data_reply_decodes() {
+        let frame = reply(0x82, &live_data_payload());
+        assert!(has_preamble(&frame));
+        assert!(check_crc(&frame));
+        assert!(answers(&request_live_data(), &frame));
+        let live = LiveData::decode(&frame).unwrap();
+        assert_eq!(live.temperature, 31);
+        assert_eq!(live.energy_today, 42);
+        assert_eq!(live.dc1_voltage, 2_950);
+        assert_eq!(live.voltage, 2_405);
+        assert_eq!(live.frequency, 4_998);
+        assert_eq!(live.active_power, 1_520);
+        assert_eq!(live.import_active, 123_456);
+        assert_eq!(live.runtime_total, 8_760);
+        assert!(matches!(live.run_mode, RunMode::Normal));
+        assert!(matches!(live.error_code, ErrorCode::None));
+        assert!(!live.is_faulted());
+    }
+
+    #[test]
+    fn query_id_reply_decodes() {
+        let mut payload = vec![b' '; QUERY_ID_LEN - LIVE_DATA_OFFSET];
+        payload[0] = 1;
+        payload[40..53].copy_from_slice(b"XA1234567890A");
+        let frame = reply(0x83, &payload);
+        assert!(check_crc(&frame));
+        let id = QueryID::decode(&frame).unwrap();
+        assert_eq!(id.inverter_phases, 1);
+        assert_eq!(id.serial_number, "XA1234567890A");
+    }
+
+    #[test]
+    fn malformed_preamble_rejected() {
+        assert!(has_preamble(&[0xAA, 0x55, 0x00]));
+        assert!(!has_preamble(&[0x00, 0x55, 0x00]));
+        assert!(!has_preamble(&[0xAA, 0x00, 0x00]));
+        assert!(!has_preamble(&[0x55, 0xAA, 0x00]));
+        // one byte replies and nothing at all must not panic
+        assert!(!has_preamble(&[0xAA]));
+        assert!(!has_preamble(&[]));
+        let mut frame = reply(0x82, &live_data_payload());
+        frame[0] = 0x00;
+        assert!(split_frames(&frame).is_empty());
+    }
+
+    #[test]
+    fn checksum_known_frames() {
+        let broadcast = [
+            0xAA, 0x55, 0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x01, 0x10,
+        ];
+        assert_eq!(send_broadcast_message(), broadcast);
+        assert!(check_crc(&broadcast));
+        let live_data = [
+            0xAA, 0x55, 0x01, 0x00, 0x00, 0x0A, 0x11, 0x02, 0x00, 0x01, 0x1D,
+        ];
+        assert_eq!(request_live_data(), live_data);
+        assert!(check_crc(&live_data));
+    }
+
+    #[test]
+    fn checksum_wraps_past_u16() {
+        // 300 * 0xFF = 76500, one wrap past 65535
+        assert_eq!(calc_partial_crc(&vec![0xFF; 300]), [0x2A, 0xD4]);
+    }
+
+    #[test]
+    fn three_chained_frames_split() {
+        let live = reply(0x82, &live_data_payload());
+        let id = reply(0x83, &[b' '; QUERY_ID_LEN - LIVE_DATA_OFFSET]);
+        let config = reply(0x84, &[0; QUERY_CONFIG_LEN - LIVE_DATA_OFFSET]);
+        let mut buf = id.clone();
+        // line noise between frames and a partial frame at the end are skipped
+        buf.push(0x00);
+        buf.extend_from_slice(&live);
+        buf.extend_from_slice(&config);
+        buf.extend_from_slice(&live[..5]);
+        let frames = split_frames(&buf);
+        assert_eq!(frames, [id, live.clone(), config]);
+        let answer: Vec<_> = frames
+            .iter()
+            .filter(|frame| answers(&request_live_data(), frame))
+            .collect();
+        assert_eq!(answer, [&live]);
+    }
+
+    #[test]
+    fn short_buffers_decode_to_errors() {
+        let frame = [0u8; QUERY_CONFIG_LEN];
+        let short = |len, expected| Some(InverterError::ShortFrame { len, expected });
+        for len in 0..LIVE_DATA_LEN {
+            let decoded = LiveData::decode(&frame[..len]);
+            assert_eq!(decoded.err(), short(len, LIVE_DATA_LEN));
+        }
+        for len in 0..QUERY_ID_LEN {
+            let decoded = QueryID::decode(&frame[..len]);
+            assert_eq!(decoded.err(), short(len, QUERY_ID_LEN));
+        }
+        for len in 0..QUERY_CONFIG_LEN {
+            let decoded = QueryConfig::decode(&frame[..len]);
+            assert_eq!(decoded.err(), short(len, QUERY_CONFIG_LEN));
+        }
+        assert!(LiveData::decode(&frame[..LIVE_DATA_LEN]).is_ok());
+        assert!(QueryID::decode(&frame[..QUERY_ID_LEN]).is_ok());
+        assert!(QueryConfig::decode(&frame).is_ok());
+        assert!(!check_crc(&[]));
+        assert!(!check_crc(&[0x00]));
+    }
+}
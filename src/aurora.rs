@@ -1,140 +1,162 @@
 #![allow(dead_code, clippy::clone_on_copy)]
 
+use crate::backend::{Importance, MqttMessage};
+use crate::error::InverterError;
+#[cfg(feature = "aurora")]
+use crate::frame_log::{Direction, FrameLog};
+use crate::inverter::*;
+use crate::proto::aurora::*;
 use anyhow::*;
 use embedded_hal::serial::Write;
 use esp_idf_hal::serial::{Rx, Tx, UART1};
-use log::info;
+use log::{info, warn};
 use nb::block;
-use serde::Serialize;
-use std::convert::TryInto;
 use std::result::Result::Ok;
 use std::time::{Duration, Instant};
 
 type DataMap = std::collections::HashMap<String, serde_json::Value>;
 
-#[derive(Debug)]
-pub struct MqttMessage {
-    pub topic: String,
-    pub payload: String,
+// serial reads compared during init to spot two inverters sharing an address
+const IDENTITY_CHECK_READS: usize = 3;
+// FreeRTOS tick at the default CONFIG_FREERTOS_HZ, UART timeouts can't be shorter
+const MIN_IDLE_GAP: Duration = Duration::from_millis(10);
+// attempts per request while the inverter answers Retry (58)
+const RETRY_ATTEMPTS: usize = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+// inverters missing at startup are re-probed this often instead of polled
+const ABSENT_REPROBE_INTERVAL: Duration = Duration::from_secs(600);
+
+// selectable on the inverter display, 19200 out of the box
+pub const SUPPORTED_BAUDS: [u32; 7] = [2_400, 4_800, 9_600, 19_200, 38_400, 57_600, 115_200];
+
+// Modbus style T3.5 end-of-frame gap: 3.5 characters of 10 bits at the bus baud rate
+pub fn idle_gap_for_baud(baud: u32) -> Duration {
+    Duration::from_micros(35_000_000 / baud.max(1) as u64).max(MIN_IDLE_GAP)
 }
 
-#[derive(Debug, Copy, Clone, Serialize)]
-pub enum Status {
-    Offline,
-    Online,
-}
-#[derive(Debug, Copy, Clone, Serialize)]
-pub struct Availablilty {
-    status: Status,
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    // keep polling, publish the error topic
+    Report,
+    // publish the error topic and hold the inverter offline until the check passes
+    Skip,
 }
 
-#[derive(Debug, Copy, Clone, Default, Serialize)]
-pub struct EnergyTotals {
-    day: f32,
-    week: f32,
-    month: f32,
-    year: f32,
-    total: f32,
-    since_reset: f32,
-}
-impl EnergyTotals {
-    pub fn update_value(
-        &mut self,
-        command: EnergyRequest,
-        response: [u8; 8],
-    ) -> anyhow::Result<()> {
-        let f: f32 = convert_bytes_to_i32(response)? as f32 * 0.001;
-        match command {
-            EnergyRequest::Day => self.day = f,
-            EnergyRequest::Week => self.week = f,
-            EnergyRequest::Month => self.month = f,
-            EnergyRequest::Year => self.year = f,
-            EnergyRequest::Total => self.total = f,
-            EnergyRequest::SinceReset => self.since_reset = f,
-        }
-        Ok(())
-    }
-}
-#[derive(Debug, Copy, Clone)]
-pub enum EnergyRequest {
-    Day,
-    Week,
-    Month,
-    Year,
-    Total,
-    SinceReset,
+// One bus request of a poll pass, in order. Several inverters take turns a step at a time,
+// so one that stops answering costs a single timeout rather than delaying the rest
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PollStep {
+    Init,
+    Identity,
+    Measure(usize),
+    Global(usize),
+    Energy(usize),
+    Alarms,
+    State,
+    Time,
 }
 
-impl EnergyRequest {
-    pub fn as_code(&self) -> Result<u8> {
-        Ok(match self {
-            Self::Day => 0,
-            Self::Week => 1,
-            Self::Month => 3,
-            Self::Year => 4,
-            Self::Total => 5,
-            Self::SinceReset => 6,
-        })
+// measure codes also read with the global flag, the whole plant on multi-inverter models.
+// Same 8 byte reply, (topic suffix, request)
+pub const GLOBAL_FIELDS: [(&str, DspRequest); 2] = [
+    ("gridpower", DspRequest::GridPower),
+    ("pin1", DspRequest::Pin1),
+];
+
+// cumulated energy periods read each pass
+pub const ENERGY_REQUESTS: [EnergyRequest; 6] = [
+    EnergyRequest::Day,
+    EnergyRequest::Week,
+    EnergyRequest::Month,
+    EnergyRequest::Year,
+    EnergyRequest::Total,
+    EnergyRequest::SinceReset,
+];
+
+fn set_global(inverter: &mut AuroraInverter, name: &'static str, value: f32) {
+    match inverter.global.iter_mut().find(|(n, _)| *n == name) {
+        Some((_, v)) => *v = value,
+        None => inverter.global.push((name, value)),
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct AuroraInverter {
-    pub data: Dsp,
-    availability: Availablilty,
-    id: u8,
-    pub energy: EnergyTotals,
-    lastmessage: Instant,
-}
-impl AuroraInverter {
-    pub fn new(id: u8) -> Self {
-        Self {
-            data: Dsp::default(),
-            availability: Availablilty {
-                status: Status::Offline,
-            },
-            id,
-            energy: EnergyTotals::default(),
-            lastmessage: Instant::now() - Duration::from_secs(60),
-        }
-    }
-    pub fn id(&self) -> u8 {
-        self.id
-    }
-}
-impl core::fmt::Debug for AuroraInverter {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        writeln!(
-            f,
-            "Inverter ID: {}\n{:?}\n{:#?}\n{:#?}",
-            self.id, self.availability, self.energy, self.data
-        )
-    }
+// Bus timing and protocol choices, fixed for the life of the driver
+#[derive(Debug, Copy, Clone)]
+pub struct AuroraSettings {
+    // reply timeout until an inverter's turnaround has been measured
+    pub timeout: Duration,
+    // line idle this long after the first byte ends a frame
+    pub idle_gap: Duration,
+    pub collision_policy: CollisionPolicy,
+    pub energy_function: EnergyFunction,
+    // between the requests of one poll pass
+    pub request_gap: Duration,
+    pub float_order: FloatOrder,
+    // DSP_FIELDS subset measured each pass
+    pub measurements: Vec<DspFieldMeta>,
 }
 
+// UART driver, reads each inverter into its crate::inverter::AuroraInverter
 pub struct Aurora {
     tx: Tx<UART1>,
     rx: Rx<UART1>,
     timeout: Duration,
+    idle_gap: Duration,
+    collision_policy: CollisionPolicy,
+    energy_function: EnergyFunction,
+    request_gap: Duration,
+    float_order: FloatOrder,
+    measurements: Vec<DspFieldMeta>,
+    frame_log: FrameLog,
 }
 impl Aurora {
     // protocol handler only
-    pub fn new(rx: Rx<UART1>, tx: Tx<UART1>, timeout: Duration) -> anyhow::Result<Self> {
-        Ok(Self { rx, tx, timeout })
+    pub fn new(
+        rx: Rx<UART1>,
+        tx: Tx<UART1>,
+        settings: AuroraSettings,
+        frame_log: FrameLog,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            rx,
+            tx,
+            timeout: settings.timeout,
+            idle_gap: settings.idle_gap,
+            collision_policy: settings.collision_policy,
+            energy_function: settings.energy_function,
+            request_gap: settings.request_gap,
+            float_order: settings.float_order,
+            // PollStep::Measure indexes it, so never empty
+            measurements: match settings.measurements.is_empty() {
+                true => DSP_FIELDS.to_vec(),
+                false => settings.measurements,
+            },
+            frame_log,
+        })
+    }
+    // some transceivers drop a frame sent straight after the last reply
+    pub fn pace(&self, first: bool) {
+        if !first && !self.request_gap.is_zero() {
+            std::thread::sleep(self.request_gap);
+        }
     }
     pub fn init_inverter(&mut self, inverter: &mut AuroraInverter) -> anyhow::Result<()> {
         // checks that inverter is communicating and not alarming
+        let was_offline = matches!(inverter.availability.status, Status::Offline);
         let response = self.request_data(
             inverter,
             DspFunction::Measure,
             DspRequest::Grid.as_code()?,
             false,
         )?;
-        if convert_bytes_to_f32(response)? > 0.0 {
+        if convert_bytes_to_f32(response, self.float_order)? > 0.0 {
             inverter.availability = Availablilty {
                 status: Status::Online,
             };
             inverter.lastmessage = Instant::now();
+            if was_offline {
+                self.check_address_collision(inverter)?;
+            }
             return Ok(());
         }
 
@@ -143,16 +165,251 @@ impl Aurora {
         };
         Err(anyhow!("No response from inverter"))
     }
+    // the DSP_FIELDS subset each pass reads
+    pub fn measurements(&self) -> &[DspFieldMeta] {
+        &self.measurements
+    }
+    // one quiet probe, absent inverters are retried after ABSENT_REPROBE_INTERVAL
+    pub fn discover(&mut self, inverter: &mut AuroraInverter) -> bool {
+        inverter.present = self.init_inverter(inverter).is_ok();
+        if inverter.present {
+            info!("ABB{} found", inverter.id);
+        } else {
+            info!(
+                "ABB{} not responding, re-probing every {:?}",
+                inverter.id, ABSENT_REPROBE_INTERVAL
+            );
+            inverter.next_probe = Instant::now() + ABSENT_REPROBE_INTERVAL;
+        }
+        inverter.present
+    }
+    // a whole pass for one inverter, crate::backend interleaves several with poll_step instead
     pub fn poll_inverter(&mut self, inverter: &mut AuroraInverter) -> anyhow::Result<&mut Aurora> {
-        self.init_inverter(inverter)?;
-        // aurora.init_inverter(inverter2)?;
-        self.poll_data(inverter)?;
-        self.request_energy_totals(inverter)?;
+        let mut step = Some(PollStep::Init);
+        let mut first = true;
+        while let Some(current) = step {
+            self.pace(first);
+            first = false;
+            step = self.poll_step(inverter, current)?;
+        }
+        Ok(self)
+    }
 
-        inverter.lastmessage = Instant::now();
-        // println!("{:?}", inverter);
+    // runs one step of a pass, the next step or None once the pass is complete
+    pub fn poll_step(
+        &mut self,
+        inverter: &mut AuroraInverter,
+        step: PollStep,
+    ) -> anyhow::Result<Option<PollStep>> {
+        if step == PollStep::Init {
+            inverter.pass_start = Instant::now();
+        }
+        match self.run_step(inverter, step) {
+            Ok(Some(next)) => Ok(Some(next)),
+            Ok(None) => {
+                inverter.lastmessage = Instant::now();
+                inverter.last_pass_ms = Some(inverter.pass_start.elapsed().as_millis() as u32);
+                Ok(None)
+            }
+            Err(e) => {
+                // partial reads leave a mix of old and new values
+                inverter.availability = Availablilty {
+                    status: Status::Offline,
+                };
+                Err(e)
+            }
+        }
+    }
 
-        Ok(self)
+    fn run_step(
+        &mut self,
+        inverter: &mut AuroraInverter,
+        step: PollStep,
+    ) -> anyhow::Result<Option<PollStep>> {
+        let next = match step {
+            PollStep::Init => {
+                self.init_inverter(inverter)?;
+                if inverter.address_collision && self.collision_policy == CollisionPolicy::Skip {
+                    // marked offline by poll_step, so the identity check re-runs next cycle
+                    return Err(anyhow!(
+                        "Probable address collision on ABB{}, skipping poll",
+                        inverter.id
+                    ));
+                }
+                match inverter.identity {
+                    None => PollStep::Identity,
+                    Some(_) => PollStep::Measure(0),
+                }
+            }
+            PollStep::Identity => {
+                if let Err(e) = self.read_identity(inverter) {
+                    info!("ABB{} identity read failed {:?}", inverter.id, e);
+                }
+                PollStep::Measure(0)
+            }
+            PollStep::Measure(i) => {
+                self.request_measure(inverter, self.measurements[i].request)?;
+                match i + 1 < self.measurements.len() {
+                    true => PollStep::Measure(i + 1),
+                    false if inverter.global_unsupported => PollStep::Energy(0),
+                    false => PollStep::Global(0),
+                }
+            }
+            PollStep::Global(i) => {
+                let (name, request) = GLOBAL_FIELDS[i];
+                match self.request_global_measure(inverter, request) {
+                    Ok(value) => set_global(inverter, name, value),
+                    Err(InverterError::TransmissionState(code)) => {
+                        info!(
+                            "ABB{} has no global measurements, state {:?}",
+                            inverter.id,
+                            TransmissionState::from_code(code)
+                        );
+                        inverter.global_unsupported = true;
+                        inverter.global.clear();
+                        return Ok(Some(PollStep::Energy(0)));
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                match i + 1 < GLOBAL_FIELDS.len() {
+                    true => PollStep::Global(i + 1),
+                    false => PollStep::Energy(0),
+                }
+            }
+            PollStep::Energy(i) => {
+                let function = self.energy_function_for(inverter);
+                self.request_energy(inverter, function, ENERGY_REQUESTS[i])?;
+                match i + 1 < ENERGY_REQUESTS.len() {
+                    true => PollStep::Energy(i + 1),
+                    false => PollStep::Alarms,
+                }
+            }
+            PollStep::Alarms => {
+                self.request_alarms(inverter)?;
+                PollStep::State
+            }
+            PollStep::State => {
+                self.read_state(inverter)?;
+                PollStep::Time
+            }
+            PollStep::Time => {
+                // older firmware may not answer, not worth failing the poll over
+                if let Err(e) = self.read_time(inverter) {
+                    info!("ABB{} clock read failed {:?}", inverter.id, e);
+                }
+                return Ok(None);
+            }
+        };
+        Ok(Some(next))
+    }
+
+    pub fn read_serial(&mut self, inverter: &mut AuroraInverter) -> anyhow::Result<String> {
+        // serial number reply is 6 ASCII bytes with no transmission state
+        let response = self.request_frame(inverter, DspFunction::Serial, 0, false)?;
+        if !crc_valid(&response) {
+            return Err(InverterError::CrcMismatch.into());
+        }
+        Ok(ascii_payload(&response[0..6]))
+    }
+
+    pub fn read_part_number(&mut self, inverter: &mut AuroraInverter) -> anyhow::Result<String> {
+        // same 6 ASCII byte reply as the serial number
+        let response = self.request_frame(inverter, DspFunction::PN, 0, false)?;
+        if !crc_valid(&response) {
+            return Err(InverterError::CrcMismatch.into());
+        }
+        Ok(ascii_payload(&response[0..6]))
+    }
+
+    pub fn read_manufacture_date(
+        &mut self,
+        inverter: &mut AuroraInverter,
+    ) -> anyhow::Result<String> {
+        // week then year, two ASCII digits each
+        let response = self.request_data(inverter, DspFunction::ManufactureDate, 0, false)?;
+        let week = ascii_payload(&response[2..4]);
+        let year = ascii_payload(&response[4..6]);
+        Ok(format!("20{}-W{}", year, week))
+    }
+
+    pub fn read_identity(&mut self, inverter: &mut AuroraInverter) -> anyhow::Result<()> {
+        let serial = self.read_serial(inverter)?;
+        let version = self.request_data(inverter, DspFunction::Version, 0, false)?;
+        let firmware = self.request_data(inverter, DspFunction::Firmware, 0, false)?;
+        let firmware: Vec<String> = ascii_payload(&firmware[2..6])
+            .chars()
+            .map(String::from)
+            .collect();
+        let identity = Identity {
+            serial,
+            version: ascii_payload(&version[2..6]),
+            // release is 4 single character fields, shown dotted as on the inverter display
+            firmware: firmware.join("."),
+            part_number: self
+                .read_part_number(inverter)
+                .map_err(|e| info!("ABB{} part number read failed {:?}", inverter.id, e))
+                .ok(),
+            manufacture_date: self
+                .read_manufacture_date(inverter)
+                .map_err(|e| info!("ABB{} manufacture date read failed {:?}", inverter.id, e))
+                .ok(),
+        };
+        info!("ABB{} identity {:?}", inverter.id, identity);
+        inverter.identity = Some(identity);
+        inverter.identity_unpublished = true;
+        Ok(())
+    }
+
+    pub fn identity_to_vec_mqtt_json(
+        &self,
+        inverter: &AuroraInverter,
+        mqtt_topic_name: &str,
+    ) -> anyhow::Result<Vec<MqttMessage>> {
+        let identity = match &inverter.identity {
+            Some(identity) => identity,
+            None => return Ok(vec![]),
+        };
+        let data: DataMap = serde_json::from_value(serde_json::to_value(identity)?)?;
+        Ok(data
+            .iter()
+            .map(|(key, value)| MqttMessage {
+                topic: format!("{}/{:?}/{}", mqtt_topic_name, inverter.id(), key),
+                payload: value.as_str().unwrap_or_default().to_string(),
+                importance: Importance::Retained,
+            })
+            .collect())
+    }
+
+    fn check_address_collision(&mut self, inverter: &mut AuroraInverter) -> anyhow::Result<()> {
+        // colliding replies garble each other, so serials disagree between reads or fail
+        // their CRC. One bad CRC among agreeing serials is put down to line noise
+        let mut serials: Vec<String> = vec![];
+        let mut crc_failures = 0;
+        let mut last_error = None;
+        for _ in 0..IDENTITY_CHECK_READS {
+            match self.read_serial(inverter) {
+                Ok(serial) => serials.push(serial),
+                Err(e)
+                    if e.downcast_ref::<InverterError>() == Some(&InverterError::CrcMismatch) =>
+                {
+                    crc_failures += 1
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        inverter.address_collision = crc_failures > 1 || serials.iter().any(|s| *s != serials[0]);
+        if inverter.address_collision {
+            info!(
+                "ABB{} returned inconsistent serials {:?} and {} CRC failures, probable address collision",
+                inverter.id, serials, crc_failures
+            );
+            return Ok(());
+        }
+        // no serial to judge by, the check re-runs with the next init
+        if serials.is_empty() {
+            return Err(last_error.unwrap_or_else(|| InverterError::CrcMismatch.into()));
+        }
+        Ok(())
     }
 
     pub fn data_to_vec_mqtt_json(
@@ -161,78 +418,376 @@ impl Aurora {
         mqtt_topic_name: &str,
     ) -> anyhow::Result<Vec<MqttMessage>> {
         let mut mqtt_payload: Vec<MqttMessage> = vec![];
-        let d1 = serde_json::to_string(&inverter.data)?;
-        let d2 = serde_json::to_string(&inverter.energy)?;
-        let d3 = serde_json::to_string(&inverter.availability)?;
-        [d1, d2, d3].iter().for_each(|message_json| {
+        // offline inverters only report status, stale readings would look live
+        let mut sections = vec![(
+            serde_json::to_string(&inverter.availability)?,
+            Importance::Normal,
+        )];
+        if inverter.is_online() {
+            sections.push((serde_json::to_string(&inverter.data)?, Importance::Normal));
+            sections.push((
+                serde_json::to_string(&inverter.energy)?,
+                Importance::Critical,
+            ));
+        }
+        sections.iter().for_each(|(message_json, importance)| {
             let data: DataMap =
                 serde_json::from_str(message_json).expect("Serde error in contruction");
             data.iter().for_each(|(key, value)| {
                 mqtt_payload.push(MqttMessage {
                     topic: format!("{}/{:?}/{}", mqtt_topic_name, inverter.id(), key),
                     payload: format!("{}", value),
+                    importance: *importance,
                 });
             });
         });
+        // published offline too, for staleness alerts
+        mqtt_payload.push(MqttMessage {
+            topic: format!("{}/{:?}/last_seen_secs", mqtt_topic_name, inverter.id()),
+            payload: inverter.seconds_since_last_message().to_string(),
+            importance: Importance::Normal,
+        });
+        if inverter.is_online() {
+            mqtt_payload.push(MqttMessage {
+                topic: format!("{}/{:?}/alarm", mqtt_topic_name, inverter.id()),
+                payload: format!("{:?}", inverter.active_alarm()),
+                importance: Importance::Normal,
+            });
+            mqtt_payload.push(MqttMessage {
+                topic: format!("{}/{:?}/alarms", mqtt_topic_name, inverter.id()),
+                payload: serde_json::to_string(&inverter.alarms)?,
+                importance: Importance::Normal,
+            });
+            if let Some(day) = inverter.energy.day_reset() {
+                mqtt_payload.push(MqttMessage {
+                    topic: format!("{}/{:?}/day_reset", mqtt_topic_name, inverter.id()),
+                    payload: day.to_string(),
+                    importance: Importance::Critical,
+                });
+            }
+            for (name, value) in &inverter.global {
+                mqtt_payload.push(MqttMessage {
+                    topic: format!("{}/{:?}/global_{}", mqtt_topic_name, inverter.id(), name),
+                    payload: value.to_string(),
+                    importance: Importance::Normal,
+                });
+            }
+            if let Some(secs) = inverter.clock_secs {
+                mqtt_payload.push(MqttMessage {
+                    topic: format!("{}/{:?}/clock_secs", mqtt_topic_name, inverter.id()),
+                    payload: secs.to_string(),
+                    importance: Importance::Normal,
+                });
+            }
+            if let Some(state) = inverter.operating_state {
+                for (key, value) in [
+                    ("global_state", format!("{:?}", state.global)),
+                    ("inverter_state", format!("{:?}", state.inverter)),
+                    ("dcdc1_state", format!("{:?}", state.dcdc1)),
+                    ("dcdc2_state", format!("{:?}", state.dcdc2)),
+                ] {
+                    mqtt_payload.push(MqttMessage {
+                        topic: format!("{}/{:?}/{}", mqtt_topic_name, inverter.id(), key),
+                        payload: value,
+                        importance: Importance::Normal,
+                    });
+                }
+            }
+        }
+        if inverter.address_collision {
+            mqtt_payload.push(MqttMessage {
+                topic: format!("{}/{:?}/error", mqtt_topic_name, inverter.id()),
+                payload: "address_collision".to_string(),
+                importance: Importance::Normal,
+            });
+        }
 
         Ok(mqtt_payload)
     }
+    // whole inverter as one nested document for <topic>/<id>/state
+    pub fn data_to_single_json(
+        &self,
+        inverter: &AuroraInverter,
+        mqtt_topic_name: &str,
+        uptime_secs: u64,
+        unix_secs: Option<i64>,
+    ) -> anyhow::Result<MqttMessage> {
+        let online = inverter.is_online();
+        let global: std::collections::HashMap<&str, f32> =
+            inverter.global.iter().copied().collect();
+        let mut state = serde_json::json!({
+            "status": inverter.availability.status,
+            "last_seen_secs": inverter.seconds_since_last_message(),
+            "data": if online { Some(&inverter.data) } else { None },
+            "energy": if online { Some(&inverter.energy) } else { None },
+            "alarm": if online { Some(format!("{:?}", inverter.active_alarm())) } else { None },
+            "operating_state": if online { inverter.operating_state } else { None },
+            "global": if online { Some(global) } else { None },
+            "address_collision": inverter.address_collision,
+            "uptime_secs": uptime_secs,
+        });
+        // wall clock only once SNTP has synced
+        if let Some(secs) = unix_secs {
+            state["timestamp"] = secs.into();
+            state["time"] = crate::clock::iso8601(secs).into();
+        }
+        Ok(MqttMessage {
+            topic: format!("{}/{:?}/state", mqtt_topic_name, inverter.id()),
+            payload: state.to_string(),
+            importance: Importance::Retained,
+        })
+    }
     pub fn poll_data(&mut self, inverter: &mut AuroraInverter) -> anyhow::Result<()> {
         // takes mut reference of inverter struct and updates values
 
-        for request in [
-            // DspRequest::GridVoltage,
-            DspRequest::Grid,
-            DspRequest::Current,
-            DspRequest::GridPower,
-            DspRequest::Frequency,
-            DspRequest::Vbulk,
-            DspRequest::Ileak,
-            DspRequest::IleakDc,
-            DspRequest::Pin1,
-            DspRequest::Pin2,
-            DspRequest::InverterTemperature,
-            DspRequest::BoosterTemperature,
-            DspRequest::Input1Current,
-            DspRequest::Input1Voltage,
-            DspRequest::Input2Current,
-            DspRequest::Input2Voltage,
-            DspRequest::PowerPeak,
-            DspRequest::PowerPeakToday,
-        ]
-        .iter()
-        {
-            let response =
-                self.request_data(inverter, DspFunction::Measure, request.as_code()?, false)?;
-            inverter.data.update_value(*request, response)?;
-            inverter.lastmessage = Instant::now();
+        let requests: Vec<DspRequest> = self.measurements.iter().map(|meta| meta.request).collect();
+        for (i, request) in requests.into_iter().enumerate() {
+            self.pace(i == 0);
+            self.request_measure(inverter, request)?;
+        }
+        Ok(())
+    }
+
+    // global flag set, scaled like the per inverter reading
+    pub fn request_global_measure(
+        &mut self,
+        inverter: &mut AuroraInverter,
+        request: DspRequest,
+    ) -> Result<f32, InverterError> {
+        let code = request
+            .as_code()
+            .map_err(|_| InverterError::UnexpectedReply)?;
+        let response = self.request_data(inverter, DspFunction::Measure, code, true)?;
+        let value = convert_bytes_to_f32(response, self.float_order)
+            .map_err(|_| InverterError::UnexpectedReply)?;
+        inverter.lastmessage = Instant::now();
+        Ok(value * dsp_scale(request))
+    }
+
+    fn request_measure(
+        &mut self,
+        inverter: &mut AuroraInverter,
+        request: DspRequest,
+    ) -> anyhow::Result<()> {
+        let response =
+            self.request_data(inverter, DspFunction::Measure, request.as_code()?, false)?;
+        inverter.lastmessage = Instant::now();
+        let value = convert_bytes_to_f32(response, self.float_order)? * dsp_scale(request);
+        // kept out of the published data, the last good value stays
+        if implausible_f32(value) {
+            if !inverter.float_order_warned {
+                warn!(
+                    "ABB{} {:?} decoded as {}, try the other DeviceConfig::aurora_float_order than {:?}",
+                    inverter.id, request, value, self.float_order
+                );
+                inverter.float_order_warned = true;
+            }
+            return Ok(());
         }
+        inverter.data.update_value(request, value);
         Ok(())
     }
 
+    // a probe answered on function 68 means the firmware has float readings
+    fn energy_function_for(&mut self, inverter: &mut AuroraInverter) -> EnergyFunction {
+        if let Some(function) = inverter.energy_function {
+            return function;
+        }
+        let function = match self.energy_function {
+            EnergyFunction::Auto => match self.request_data(
+                inverter,
+                DspFunction::CumulatedFloatEnergy,
+                EnergyRequest::Total.as_float_code().unwrap_or_default(),
+                false,
+            ) {
+                Ok(response)
+                    if convert_bytes_to_f32(response, self.float_order)
+                        .map_or(false, f32::is_finite) =>
+                {
+                    EnergyFunction::CumulatedFloat
+                }
+                _ => EnergyFunction::Cumulated,
+            },
+            configured => configured,
+        };
+        info!("ABB{} energy readings via {:?}", inverter.id, function);
+        inverter.energy_function = Some(function);
+        function
+    }
+
     pub fn request_energy_totals(
         &mut self,
         inverter: &mut AuroraInverter,
     ) -> anyhow::Result<&mut Aurora> {
-        for request in [
-            EnergyRequest::Day,
-            EnergyRequest::Week,
-            EnergyRequest::Month,
-            EnergyRequest::Year,
-            EnergyRequest::Total,
-            EnergyRequest::SinceReset,
-        ]
-        .iter()
-        {
-            let response = self.request_data(
+        let function = self.energy_function_for(inverter);
+        for (i, request) in ENERGY_REQUESTS.iter().enumerate() {
+            self.pace(i == 0);
+            self.request_energy(inverter, function, *request)?;
+        }
+
+        Ok(self)
+    }
+
+    fn request_energy(
+        &mut self,
+        inverter: &mut AuroraInverter,
+        function: EnergyFunction,
+        request: EnergyRequest,
+    ) -> anyhow::Result<()> {
+        let response = match function {
+            EnergyFunction::CumulatedFloat => self.request_data(
+                inverter,
+                DspFunction::CumulatedFloatEnergy,
+                request.as_float_code()?,
+                false,
+            )?,
+            _ => self.request_data(
                 inverter,
                 DspFunction::CumulatedEnergy,
                 request.as_code()?,
                 false,
-            )?;
-            inverter.energy.update_value(*request, response)?;
-            inverter.lastmessage = Instant::now();
+            )?,
+        };
+        inverter.energy.update_value(
+            request,
+            function,
+            response,
+            self.float_order,
+            &mut inverter.energy_check,
+        )?;
+        inverter.lastmessage = Instant::now();
+        Ok(())
+    }
+
+    pub fn request_alarms(&mut self, inverter: &mut AuroraInverter) -> anyhow::Result<&mut Aurora> {
+        // bytes 2..6 hold one alarm code each
+        let response = self.request_data(inverter, DspFunction::Alarms, 0, false)?;
+        inverter.alarms = response[2..6]
+            .iter()
+            .map(|code| AlarmCode::from_code(*code))
+            .collect();
+        if inverter.active_alarm() != AlarmCode::NoAlarm {
+            info!("ABB{} alarms {:?}", inverter.id, inverter.alarms);
+        }
+        inverter.lastmessage = Instant::now();
+
+        Ok(self)
+    }
+
+    // unchecked reply for reverse engineering, see MqttCommand::RawDsp
+    pub fn raw_measure(
+        &mut self,
+        inverter: &mut AuroraInverter,
+        function: DspFunction,
+        command: u8,
+    ) -> serde_json::Value {
+        match self.request_frame(inverter, function, command, false) {
+            Ok(response) => serde_json::json!({
+                "id": inverter.id,
+                "function": function.to_code(),
+                "command": command,
+                "response": format!("{:02x?}", response),
+                "crc_ok": crc_valid(&response),
+                "transmission_state": format!("{:?}", TransmissionState::from_code(response[0])),
+                "f32": convert_bytes_to_f32(response, self.float_order).ok(),
+            }),
+            Err(e) => serde_json::json!({
+                "id": inverter.id,
+                "function": function.to_code(),
+                "command": command,
+                "error": e.to_string(),
+            }),
+        }
+    }
+
+    // state read (50) without retries, every outcome is reported instead of returned as an error
+    pub fn self_test(&mut self, inverter: &mut AuroraInverter) -> SelfTest {
+        let started = Instant::now();
+        let reply = self.request_frame(inverter, DspFunction::State, 0, false);
+        let round_trip_ms = started.elapsed().as_millis() as u32;
+        match reply {
+            Ok(response) => SelfTest {
+                id: inverter.id,
+                answered: true,
+                crc_ok: crc_valid(&response),
+                round_trip_ms,
+                transmission_state: Some(format!(
+                    "{:?}",
+                    TransmissionState::from_code(response[0])
+                )),
+                error: None,
+            },
+            Err(e) => SelfTest {
+                id: inverter.id,
+                answered: false,
+                crc_ok: false,
+                round_trip_ms,
+                transmission_state: None,
+                error: Some(e.to_string()),
+            },
         }
+    }
+
+    pub fn read_state(&mut self, inverter: &mut AuroraInverter) -> anyhow::Result<&mut Aurora> {
+        // global, inverter, DC/DC channel 1, DC/DC channel 2, alarm
+        let response = self.request_data(inverter, DspFunction::State, 0, false)?;
+        let state = OperatingState {
+            global: GlobalState::from_code(response[1]),
+            inverter: InverterState::from_code(response[2]),
+            dcdc1: DcDcState::from_code(response[3]),
+            dcdc2: DcDcState::from_code(response[4]),
+            alarm: AlarmCode::from_code(response[5]),
+        };
+        if inverter.operating_state.map(|s| s.global) != Some(state.global) {
+            info!("ABB{} state {:?}", inverter.id, state);
+        }
+        inverter.operating_state = Some(state);
+        inverter.lastmessage = Instant::now();
+
+        Ok(self)
+    }
+
+    pub fn read_time(&mut self, inverter: &mut AuroraInverter) -> anyhow::Result<&mut Aurora> {
+        // bytes 2..6 are seconds since 2000-01-01 00:00, big endian
+        let response = self.request_data(inverter, DspFunction::TimeDate, 0, false)?;
+        inverter.clock_secs = Some(u32::from_be_bytes(response[2..6].try_into()?));
+        inverter.lastmessage = Instant::now();
+
+        Ok(self)
+    }
+
+    // secs_since_2000 in the inverter's own zone, only accepted in service mode
+    pub fn set_time(
+        &mut self,
+        inverter: &mut AuroraInverter,
+        secs_since_2000: u32,
+    ) -> anyhow::Result<&mut Aurora> {
+        let secs = secs_since_2000.to_be_bytes();
+        let response = self.send_frame(
+            inverter,
+            DspFunction::SetTimeDate,
+            [secs[0], secs[1], secs[2], secs[3], 0, 0],
+        )?;
+        if !crc_valid(&response) {
+            info!(
+                "ABB{} set time ack CRC mismatch {:02x?}",
+                inverter.id, response
+            );
+            return Err(InverterError::CrcMismatch.into());
+        }
+        match TransmissionState::from_code(response[0]) {
+            TransmissionState::OK => {}
+            TransmissionState::NotServiceMode => {
+                return Err(anyhow::anyhow!(
+                    "ABB{} refused set time, not in service mode",
+                    inverter.id
+                ))
+            }
+            _ => return Err(InverterError::TransmissionState(response[0]).into()),
+        }
+        info!("ABB{} clock set to {}", inverter.id, secs_since_2000);
+        inverter.clock_secs = Some(secs_since_2000);
+        inverter.lastmessage = Instant::now();
 
         Ok(self)
     }
@@ -243,37 +798,83 @@ impl Aurora {
         function: DspFunction,
         command: u8,
         global: bool,
-    ) -> anyhow::Result<[u8; 8]> {
+    ) -> Result<[u8; 8], InverterError> {
         // uses enum to get data
+        let mut attempt = 1;
+        loop {
+            let mut response = self.request_frame(inverter, function, command, global)?;
+            let corrupt = !crc_valid(&response);
+            let retry =
+                corrupt || TransmissionState::from_code(response[0]) == TransmissionState::Retry;
+            if retry && attempt < RETRY_ATTEMPTS {
+                info!(
+                    "ABB{} {}, attempt {} of {}",
+                    inverter.id,
+                    if corrupt {
+                        "reply CRC mismatch"
+                    } else {
+                        "asked for retry"
+                    },
+                    attempt,
+                    RETRY_ATTEMPTS
+                );
+                attempt += 1;
+                std::thread::sleep(RETRY_DELAY);
+                continue;
+            }
+            if corrupt {
+                info!("ABB{} reply CRC mismatch {:02x?}", inverter.id, response);
+                return Err(InverterError::CrcMismatch);
+            }
+            if let Err(e) = self.response_error_check(&mut response) {
+                inverter.transmission_errors.record(
+                    format!("{:?} command {}", function, command),
+                    TransmissionState::from_code(response[0]),
+                );
+                return Err(e);
+            }
+            return Ok(response);
+        }
+    }
+
+    fn request_frame(
+        &mut self,
+        inverter: &mut AuroraInverter,
+        function: DspFunction,
+        command: u8,
+        global: bool,
+    ) -> Result<[u8; 8], InverterError> {
+        // raw reply, no transmission state check
 
         let global_measure: u8 = if global { 1 } else { 0 };
-        let mut request: [u8; 10] = [
-            inverter.id,
-            function.to_code(),
-            command as u8,
-            global_measure,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-        ];
-        // Clone here to stop overwrite of payload
-        [request[8], request[9]] = crc(&mut request.clone()[0..8]);
+        self.send_frame(inverter, function, [command, global_measure, 0, 0, 0, 0])
+    }
+
+    // address, function, six argument bytes, CRC
+    fn send_frame(
+        &mut self,
+        inverter: &mut AuroraInverter,
+        function: DspFunction,
+        args: [u8; 6],
+    ) -> Result<[u8; 8], InverterError> {
+        let mut request: [u8; 10] = [0u8; 10];
+        request[0] = inverter.id;
+        request[1] = function.to_code();
+        request[2..8].copy_from_slice(&args);
+        [request[8], request[9]] = crc(&request[0..8]);
         let mut response: [u8; 8] = [0u8; 8];
 
         self.send_and_recv(&request, &mut response, inverter)?;
-        self.response_error_check(&mut response)?;
         Ok(response)
     }
 
-    fn response_error_check(&self, response: &mut [u8]) -> anyhow::Result<()> {
-        if self.parse(response[0]) != TransmissionState::OK {
-            return Err(anyhow!(
+    fn response_error_check(&self, response: &mut [u8]) -> Result<(), InverterError> {
+        if TransmissionState::from_code(response[0]) != TransmissionState::OK {
+            info!(
                 "ABB response error state {:?}",
-                self.parse(response[0])
-            ));
+                TransmissionState::from_code(response[0])
+            );
+            return Err(InverterError::TransmissionState(response[0]));
         }
         Ok(())
     }
@@ -283,303 +884,82 @@ impl Aurora {
         request: &[u8],
         response: &mut [u8; 8],
         inverter: &mut AuroraInverter,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), InverterError> {
         // clear rx buffer
-        self.rx.flush()?;
+        self.rx.flush().map_err(InverterError::hardware)?;
         info!("ESP >> ABB{} {:02x?}", inverter.id, request);
+        self.frame_log.record("aurora", Direction::Tx, request);
         self.write_all(request)?;
-        self.read_all(response)?;
-        Ok(())
+        let timeout = inverter.turnaround.timeout(self.timeout);
+        match self.read_all(response, timeout) {
+            Ok(turnaround) => {
+                inverter.turnaround.record(turnaround);
+                Ok(())
+            }
+            Err(InverterError::Timeout) => {
+                inverter.turnaround.timed_out();
+                Err(InverterError::Timeout)
+            }
+            Err(e) => Err(e),
+        }
     }
 
-    fn read_all(&mut self, buf: &mut [u8; 8]) -> anyhow::Result<()> {
+    // time to the first reply byte on success
+    fn read_all(
+        &mut self,
+        buf: &mut [u8; 8],
+        timeout: Duration,
+    ) -> Result<Duration, InverterError> {
         // println!("RX {} bytes to be read", bytes);
-        self.rx.flush()?;
-        self.rx.read_bytes_blocking(buf, self.timeout)?;
-
-        info!("ESP << ABB  {:02x?}", buf);
-        Ok(())
-    }
-
-    fn write_all(&mut self, bytevec: &[u8]) -> anyhow::Result<()> {
-        for byte in bytevec {
-            block!(self.tx.write(*byte))?;
+        self.rx.flush().map_err(InverterError::hardware)?;
+        let (len, turnaround) = self.read_frame(buf, timeout)?;
+
+        info!("ESP << ABB  {:02x?}", &buf[..len]);
+        self.frame_log.record("aurora", Direction::Rx, &buf[..len]);
+        if len < buf.len() {
+            info!("Short reply, {} of {} bytes", len, buf.len());
+            return Err(InverterError::Timeout);
         }
-        Ok(())
+        Ok(turnaround)
     }
 
-    fn parse(&self, code: u8) -> TransmissionState {
-        match code {
-            0 => TransmissionState::OK,
-            51 => TransmissionState::NotImplemented,
-            52 => TransmissionState::NotExist,
-            53 => TransmissionState::OutOfRange,
-            54 => TransmissionState::EEpromError,
-            55 => TransmissionState::NotServiceMode,
-            56 => TransmissionState::InternalMicroError,
-            57 => TransmissionState::NotExecuted,
-            58 => TransmissionState::Retry,
-            _ => TransmissionState::Unknown,
+    // reads until the line is idle for idle_gap or buf is full, returns bytes read and
+    // the wait for the first one
+    fn read_frame(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<(usize, Duration), InverterError> {
+        if buf.is_empty() {
+            return Ok((0, Duration::ZERO));
         }
-    }
-}
-#[derive(Debug, Copy, Clone, Default, Serialize)]
-pub struct Dsp {
-    pub grid: f32,
-    pub current: f32,
-    pub gridpower: f32,
-    pub frequency: f32,
-    pub vbulk: f32,
-    pub ileakdc: f32,
-    pub ileak: f32,
-    pub pin1: f32,
-    pub pin2: f32,
-    pub invertertemperature: f32,
-    pub boostertemperature: f32,
-    pub input1voltage: f32,
-    pub input1current: f32,
-    pub input2voltage: f32,
-    pub input2current: f32,
-    pub powerpeak: f32,
-    pub powerpeaktoday: f32,
-    #[serde(skip_serializing)]
-    pub gridvoltagedcdc: f32,
-    #[serde(skip_serializing)]
-    pub gridfrequencydcdc: f32,
-    #[serde(skip_serializing)]
-    pub isolationresistance: f32,
-    #[serde(skip_serializing)]
-    pub vbulkdcdc: f32,
-    #[serde(skip_serializing)]
-    pub averagegridvoltage: f32,
-    #[serde(skip_serializing)]
-    pub vbulkmid: f32,
-    #[serde(skip_serializing)]
-    pub gridvoltageneutral: f32,
-    #[serde(skip_serializing)]
-    pub windgeneratorfrequency: f32,
-    #[serde(skip_serializing)]
-    pub gridvoltageneutralphase: f32,
-    #[serde(skip_serializing)]
-    pub gridcurrentphaser: f32,
-    #[serde(skip_serializing)]
-    pub gridcurrentphases: f32,
-    #[serde(skip_serializing)]
-    pub gridcurrentphaset: f32,
-    #[serde(skip_serializing)]
-    pub frequencyphaser: f32,
-    #[serde(skip_serializing)]
-    pub frequencyphases: f32,
-    #[serde(skip_serializing)]
-    pub frequencyphaset: f32,
-    #[serde(skip_serializing)]
-    pub vbulkpostitive: f32,
-    #[serde(skip_serializing)]
-    pub vbulknegative: f32,
-    #[serde(skip_serializing)]
-    pub supervisortemperature: f32,
-    #[serde(skip_serializing)]
-    pub alimtemperature: f32,
-    #[serde(skip_serializing)]
-    pub heatsinktemperature: f32,
-    #[serde(skip_serializing)]
-    pub powersaturationlimit: f32,
-    #[serde(skip_serializing)]
-    pub riferimentoanellobulk: f32,
-    #[serde(skip_serializing)]
-    pub vpanelmicro: f32,
-    #[serde(skip_serializing)]
-    pub gridvoltagephaser: f32,
-    #[serde(skip_serializing)]
-    pub gridvoltagephases: f32,
-    #[serde(skip_serializing)]
-    pub gridvoltagephaset: f32,
-}
-
-impl Dsp {
-    pub fn update_value(&mut self, command: DspRequest, response: [u8; 8]) -> anyhow::Result<()> {
-        let f = convert_bytes_to_f32(response)?;
-        // let i = convert_energy_bytes(response)?;
-        match command {
-            // DspRequest::NC0 => todo!(),
-            DspRequest::Grid => self.grid = f,
-            DspRequest::Current => self.current = f,
-            DspRequest::GridPower => self.gridpower = f * 0.001,
-            DspRequest::Frequency => self.frequency = f,
-            DspRequest::Vbulk => self.vbulk = f,
-            DspRequest::IleakDc => self.ileakdc = f,
-            DspRequest::Ileak => self.ileak = f,
-            DspRequest::Pin1 => self.pin1 = f * 0.001,
-            DspRequest::Pin2 => self.pin2 = f * 0.001,
-            DspRequest::InverterTemperature => self.invertertemperature = f,
-            DspRequest::BoosterTemperature => self.boostertemperature = f,
-            DspRequest::Input1Voltage => self.input1voltage = f,
-            DspRequest::Input1Current => self.input1current = f,
-            DspRequest::Input2Voltage => self.input2voltage = f,
-            DspRequest::Input2Current => self.input2current = f,
-            DspRequest::IsolationResistance => self.isolationresistance = f,
-            DspRequest::VbulkDCDC => self.vbulkdcdc = f,
-            DspRequest::AverageGridVoltage => self.averagegridvoltage = f,
-            DspRequest::VbulkMid => self.vbulkmid = f,
-            DspRequest::PowerPeak => self.powerpeak = f * 0.001,
-            DspRequest::PowerPeakToday => self.powerpeaktoday = f * 0.001,
-            DspRequest::HeatSinkTemperature => self.heatsinktemperature = f,
-            _ => {
-                info!("Not supported");
-            }
+        // first byte gets the full turnaround timeout
+        let started = Instant::now();
+        let mut len = self
+            .rx
+            .read_bytes_blocking(&mut buf[..1], timeout)
+            .map_err(InverterError::hardware)?;
+        if len == 0 {
+            return Err(InverterError::Timeout);
         }
-        Ok(())
-    }
-}
-
-#[allow(unused)]
-#[derive(Copy, Clone)]
-pub enum DspRequest {
-    GridVoltage,
-    Grid,
-    Current,
-    GridPower,
-    Frequency,
-    Vbulk,
-    IleakDc,
-    Ileak,
-    Pin1,
-    Pin2,
-    NC10,
-    NC11,
-    NC12,
-    NC13,
-    NC14,
-    NC15,
-    NC16,
-    NC17,
-    NC18,
-    NC19,
-    NC20,
-    InverterTemperature,
-    BoosterTemperature,
-    Input1Voltage,
-    NC24,
-    Input1Current,
-    Input2Voltage,
-    Input2Current,
-    GridVoltageDCDC,
-    GridFrequencyDCDC,
-    IsolationResistance,
-    VbulkDCDC,
-    AverageGridVoltage,
-    VbulkMid,
-    PowerPeak,
-    PowerPeakToday,
-    GridVoltageneutral,
-    WindGeneratorFrequency,
-    GridVoltageneutralphase,
-    GridCurrentphaser,
-    GridCurrentphases,
-    GridCurrentphaset,
-    Frequencyphaser,
-    Frequencyphases,
-    Frequencyphaset,
-    VbulkPostitive,
-    VbulkNegative,
-    SupervisorTemperature,
-    AlimTemperature,
-    HeatSinkTemperature,
-    Temperature1,
-    Temperature2,
-    Temperature3,
-    Fan1Speed,
-    Fan2Speed,
-    Fan3Speed,
-    Fan4Speed,
-    Fan5Speed,
-    PowerSaturationlimit,
-    RiferimentoAnelloBulk,
-    Vpanelmicro,
-    GridVoltagephaser,
-    GridVoltagephases,
-    GridVoltagephaset,
-}
-impl DspRequest {
-    pub fn as_code(&self) -> anyhow::Result<u8> {
-        Ok((*self as usize).try_into()?)
-    }
-}
-
-pub enum DspFunction {
-    State,                //50
-    PN,                   //52
-    Version,              //58
-    Measure,              //59
-    Serial,               //63
-    MaufacturerDate,      //65
-    Flags,                //67
-    CumulatedFloatEnergy, //68
-    TimeDate,             //70
-    Firmware,             //72
-    CumulatedEnergy,      //78
-    Alarms,               //86
-}
-impl DspFunction {
-    pub fn to_code(&self) -> u8 {
-        match self {
-            DspFunction::State => 50,
-            DspFunction::PN => 52,
-            DspFunction::Version => 58,
-            DspFunction::Measure => 59,
-            DspFunction::Serial => 63,
-            DspFunction::MaufacturerDate => 65,
-            DspFunction::Flags => 67,
-            DspFunction::CumulatedFloatEnergy => 68,
-            DspFunction::TimeDate => 70,
-            DspFunction::Firmware => 72,
-            DspFunction::CumulatedEnergy => 78,
-            DspFunction::Alarms => 86,
+        let turnaround = started.elapsed();
+        while len < buf.len() {
+            match self
+                .rx
+                .read_bytes_blocking(&mut buf[len..], self.idle_gap)
+                .map_err(InverterError::hardware)?
+            {
+                0 => break,
+                n => len += n,
+            }
         }
-    }
-}
-
-#[derive(Debug, PartialEq, Eq)]
-enum TransmissionState {
-    OK,
-    NotImplemented,
-    NotExist,
-    OutOfRange,
-    EEpromError,
-    NotServiceMode,
-    InternalMicroError,
-    NotExecuted,
-    Retry,
-    Unknown,
-}
-
-fn crc(buf: &mut [u8]) -> [u8; 2] {
-    let poly = 0x8408;
-    let mask = 0xffff;
-    let mut crc: u16 = 0xffff;
-
-    if buf.is_empty() {
-        return (!crc & mask).to_le_bytes();
+        Ok((len, turnaround))
     }
 
-    for data in buf.iter_mut() {
-        for _i in 0..8u8 {
-            if (crc & 0x1) ^ ((*data & 0x1) as u16) > 0 {
-                crc = ((crc >> 1) ^ poly) & mask
-            } else {
-                crc >>= 1;
-            }
-            *data >>= 1;
+    fn write_all(&mut self, bytevec: &[u8]) -> Result<(), InverterError> {
+        for byte in bytevec {
+            block!(self.tx.write(*byte)).map_err(InverterError::hardware)?;
         }
+        Ok(())
     }
-    crc = !crc & mask;
-    crc.to_le_bytes()
-}
-
-fn convert_bytes_to_f32(response: [u8; 8]) -> anyhow::Result<f32> {
-    Ok(f32::from_be_bytes(response[2..6].try_into()?))
-}
-
-fn convert_bytes_to_i32(response: [u8; 8]) -> anyhow::Result<i32> {
-    Ok(i32::from_be_bytes(response[2..6].try_into()?))
 }
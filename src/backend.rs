@@ -0,0 +1,286 @@
+use crate::error::InverterError;
+use crate::state::AppState;
+use log::{error, info, warn};
+
+#[cfg(feature = "aurora")]
+use crate::aurora::{Aurora, PollStep};
+#[cfg(feature = "aurora")]
+use crate::inverter::AuroraInverter;
+#[cfg(feature = "solax")]
+use crate::solax_x1_air::SolaxX1Air;
+#[cfg(feature = "aurora")]
+use crate::{INVERTER_CLOCK_SKEW_WARN, MQTT_SINGLE_JSON};
+#[cfg(feature = "aurora")]
+use std::sync::atomic::Ordering;
+
+#[derive(Debug)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub payload: String,
+    pub importance: Importance,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Importance {
+    // fast changing, the next poll replaces a lost message
+    Normal,
+    // cumulative values where a gap shows in graphs, sent at least once
+    Critical,
+    // identity and single JSON state documents, kept broker-side for late subscribers
+    Retained,
+}
+
+// One RS485 protocol driver, the poll task runs whichever the device config selected.
+// Devices live in AppState, so every step gets it alongside the driver.
+pub trait InverterBackend {
+    // log prefix
+    const NAME: &'static str;
+    // boot time bring up, Err only when nothing answered
+    fn init(&mut self, state: &AppState) -> anyhow::Result<()>;
+    // one pass over the bus, failures are logged and counted here. Messages, topics under
+    // prefix, go to publish as each device finishes rather than all at the end
+    fn poll(&mut self, state: &AppState, prefix: &str, publish: &mut dyn FnMut(Vec<MqttMessage>));
+}
+
+#[cfg(feature = "aurora")]
+impl InverterBackend for Aurora {
+    const NAME: &'static str = "Aurora";
+
+    fn init(&mut self, state: &AppState) -> anyhow::Result<()> {
+        let mut inverters = state
+            .inverters
+            .lock()
+            .map_err(|_| anyhow::anyhow!("inverter list lock failed"))?;
+        let mut found = 0;
+        for inverter in inverters.iter_mut() {
+            if self.discover(inverter) {
+                found += 1;
+            }
+        }
+        if found == 0 {
+            return Err(anyhow::anyhow!("no inverter answered at boot"));
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self, state: &AppState, prefix: &str, publish: &mut dyn FnMut(Vec<MqttMessage>)) {
+        let mut inverters = match state.inverters.try_lock() {
+            Ok(inverters) => inverters,
+            Err(_) => {
+                info!("Inverter lock failed, skipping inverter poll");
+                return;
+            }
+        };
+        let mut passes: Vec<(usize, Option<PollStep>)> = vec![];
+        for (index, inverter) in inverters.iter_mut().enumerate() {
+            if !inverter.is_present() {
+                if !inverter.reprobe_due() || !self.discover(inverter) {
+                    continue;
+                }
+                // found late, its discovery configs are still missing
+                state.ha_discovery_sent.store(false, Ordering::Relaxed);
+            }
+            passes.push((index, Some(PollStep::Init)));
+        }
+        // one request per inverter per turn, a silent inverter drops out after its first
+        // timeout and the others' values go out as soon as their own pass completes
+        let mut first = true;
+        while passes.iter().any(|(_, step)| step.is_some()) {
+            for (index, step) in passes.iter_mut() {
+                let current = match step {
+                    Some(current) => *current,
+                    None => continue,
+                };
+                let inverter = &mut inverters[*index];
+                self.pace(first);
+                first = false;
+                let polled = self.poll_step(inverter, current);
+                *step = polled.as_ref().ok().copied().flatten();
+                if step.is_some() {
+                    continue;
+                }
+                let polled = polled.map(|_| ());
+                check_transceiver(state, &polled);
+                if let Err(e) = polled {
+                    inverter.record_poll_error();
+                    log_poll_error(&format!("ABB{}", inverter.id()), &e);
+                }
+                // offline inverters publish status only
+                publish(self.inverter_messages(state, inverter, prefix));
+            }
+        }
+        publish(site_totals(state, &inverters, prefix));
+        drop(inverters);
+        record_history(state);
+    }
+}
+
+#[cfg(feature = "aurora")]
+impl Aurora {
+    // everything one inverter publishes after its pass
+    fn inverter_messages(
+        &self,
+        state: &AppState,
+        inverter: &mut AuroraInverter,
+        prefix: &str,
+    ) -> Vec<MqttMessage> {
+        let mut messages = vec![];
+        // retained, the identity doesn't change while running
+        if inverter.take_identity_unpublished() {
+            match self.identity_to_vec_mqtt_json(inverter, prefix) {
+                Ok(identity) => messages.extend(identity),
+                Err(e) => info!("Identity serialise failed {:?}", e),
+            }
+        }
+        let data = if MQTT_SINGLE_JSON {
+            self.data_to_single_json(
+                inverter,
+                prefix,
+                state.boot_time.elapsed().as_secs(),
+                state.clock.unix_secs(),
+            )
+            .map(|m| vec![m])
+        } else {
+            self.data_to_vec_mqtt_json(inverter, prefix)
+        };
+        match data {
+            Ok(data) => messages.extend(data),
+            Err(e) => info!("ABB{} serialise failed {:?}", inverter.id(), e),
+        }
+        // retained so the dashboard keeps the last refusal, not just a poll error count
+        if inverter.transmission_errors.take_unpublished() {
+            messages.push(MqttMessage {
+                topic: format!("{}/{}/last_error", prefix, inverter.id()),
+                payload: inverter.transmission_errors.to_json().to_string(),
+                importance: Importance::Retained,
+            });
+        }
+        if let Some(skew) = clock_skew(state, inverter) {
+            messages.push(MqttMessage {
+                topic: format!("{}/{}/clock_skew_secs", prefix, inverter.id()),
+                payload: skew.to_string(),
+                importance: Importance::Normal,
+            });
+        }
+        messages
+    }
+}
+
+#[cfg(feature = "solax")]
+impl InverterBackend for SolaxX1Air {
+    const NAME: &'static str = "Solax";
+
+    fn init(&mut self, state: &AppState) -> anyhow::Result<()> {
+        let init = self.init_inverter();
+        check_transceiver(state, &init);
+        init
+    }
+
+    fn poll(&mut self, state: &AppState, prefix: &str, publish: &mut dyn FnMut(Vec<MqttMessage>)) {
+        let polled = if self.is_online() {
+            self.poll_data().map(|_| ())
+        } else {
+            self.init_inverter()
+        };
+        check_transceiver(state, &polled);
+        if let Err(e) = polled {
+            log_poll_error(Self::NAME, &e);
+        }
+        publish(self.data_to_vec_mqtt_json(&format!("{}/solax", prefix)));
+    }
+}
+
+// inverter RTC minus SNTP local time, positive when the inverter runs ahead
+#[cfg(feature = "aurora")]
+fn clock_skew(state: &AppState, inverter: &AuroraInverter) -> Option<i64> {
+    if !inverter.is_online() {
+        return None;
+    }
+    let skew = inverter.clock_secs? as i64 - state.clock.local_secs_since_2000()?;
+    if skew.unsigned_abs() > INVERTER_CLOCK_SKEW_WARN.as_secs() {
+        warn!("ABB{} clock is {}s off local time", inverter.id(), skew);
+    }
+    Some(skew)
+}
+
+// <prefix>/site/*, sums over the online inverters so consumers don't each add them up
+#[cfg(feature = "aurora")]
+fn site_totals(state: &AppState, inverters: &[AuroraInverter], prefix: &str) -> Vec<MqttMessage> {
+    let online: Vec<&AuroraInverter> = inverters
+        .iter()
+        .filter(|inverter| inverter.is_online())
+        .collect();
+    // nothing generating at night, the energy sums would only be stale
+    let power = online
+        .iter()
+        .map(|inverter| inverter.data.gridpower)
+        .sum::<f32>();
+    let mut totals = vec![("power", power, Importance::Normal)];
+    if !online.is_empty() {
+        // generating hours only, night zeros would pin the minimum and drag the average
+        if let Ok(mut stats) = state.site_power.lock() {
+            stats.push(power, state.clock.local_secs_since_2000());
+            if let Some((min, max, avg)) = stats.summary() {
+                totals.push(("power_min", min, Importance::Normal));
+                totals.push(("power_max", max, Importance::Normal));
+                totals.push(("power_avg", avg, Importance::Normal));
+            }
+        }
+        totals.push((
+            "energy_today",
+            online.iter().map(|inverter| inverter.energy.day()).sum(),
+            Importance::Critical,
+        ));
+        totals.push((
+            "energy_total",
+            online.iter().map(|inverter| inverter.energy.total()).sum(),
+            Importance::Critical,
+        ));
+    }
+    totals
+        .into_iter()
+        .map(|(name, value, importance)| MqttMessage {
+            topic: format!("{}/site/{}", prefix, name),
+            payload: value.to_string(),
+            importance,
+        })
+        .collect()
+}
+
+// absent inverters have nothing worth keeping
+#[cfg(feature = "aurora")]
+fn record_history(state: &AppState) {
+    let uptime_secs = state.boot_time.elapsed().as_secs();
+    if let (Ok(inverters), Ok(mut history)) = (state.inverters.try_lock(), state.history.lock()) {
+        for inverter in inverters.iter().filter(|inverter| inverter.is_present()) {
+            history.push(inverter, uptime_secs);
+        }
+    }
+}
+
+// a dead RS485 port is worth shouting about, a quiet inverter at night isn't
+fn log_poll_error(name: &str, e: &anyhow::Error) {
+    match e.downcast_ref::<InverterError>() {
+        Some(InverterError::Hardware(_)) => {
+            error!("Poll error on {}, check the transceiver {:?}", name, e)
+        }
+        Some(InverterError::Timeout) => info!("{} not answering", name),
+        _ => warn!("Poll error on {} {:?}", name, e),
+    }
+}
+
+// a latched up transceiver only shows as UART errors, power cycling it beats a reboot
+fn check_transceiver(state: &AppState, polled: &anyhow::Result<()>) {
+    let hardware_error = matches!(
+        polled
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<InverterError>()),
+        Some(InverterError::Hardware(_))
+    );
+    if let Ok(mut transceiver) = state.transceiver.lock() {
+        if let Err(e) = transceiver.record(hardware_error) {
+            error!("RS485 transceiver power cycle failed {:?}", e);
+        }
+    }
+}
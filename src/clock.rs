@@ -0,0 +1,83 @@
+use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncStatus};
+use log::info;
+
+const DAYS_1970_TO_2000: i64 = 10_957;
+
+// Wall clock from SNTP; local time follows the configured POSIX TZ string
+pub struct Clock {
+    sntp: EspSntp,
+}
+
+impl Clock {
+    // servers primary first
+    pub fn start(servers: &[String], timezone: &str) -> anyhow::Result<Self> {
+        // e.g. "GMT0BST,M3.5.0/1,M10.5.0" for the UK, applies to localtime() everywhere
+        std::env::set_var("TZ", timezone);
+        unsafe { esp_idf_sys::tzset() };
+
+        // slots beyond CONFIG_LWIP_SNTP_MAX_SERVERS are dropped
+        let mut conf = SntpConf::default();
+        for (slot, server) in conf.servers.iter_mut().zip(servers) {
+            *slot = server.clone();
+        }
+        let sntp = EspSntp::new(&conf)?;
+        info!("SNTP started with {}, TZ {}", servers.join(" / "), timezone);
+        Ok(Self { sntp })
+    }
+
+    pub fn is_synced(&self) -> bool {
+        matches!(self.sntp.get_sync_status(), SyncStatus::Completed)
+    }
+
+    // UTC seconds since 1970, None until SNTP has synced
+    pub fn unix_secs(&self) -> Option<i64> {
+        if !self.is_synced() {
+            return None;
+        }
+        Some(unsafe { esp_idf_sys::time(std::ptr::null_mut()) } as i64)
+    }
+
+    // local wall time in seconds since 2000-01-01 00:00, None until SNTP has synced
+    pub fn local_secs_since_2000(&self) -> Option<i64> {
+        if !self.is_synced() {
+            return None;
+        }
+        let now = unsafe { esp_idf_sys::time(std::ptr::null_mut()) };
+        let mut tm = esp_idf_sys::tm::default();
+        unsafe { esp_idf_sys::localtime_r(&now, &mut tm) };
+        let days = days_from_civil(
+            tm.tm_year as i64 + 1900,
+            tm.tm_mon as i64 + 1,
+            tm.tm_mday as i64,
+        ) - DAYS_1970_TO_2000;
+        let secs = tm.tm_hour as i64 * 3_600 + tm.tm_min as i64 * 60 + tm.tm_sec as i64;
+        Some(days * 86_400 + secs)
+    }
+}
+
+// UTC ISO-8601, e.g. 2024-06-01T12:34:56Z
+pub fn iso8601(unix_secs: i64) -> String {
+    let now = unix_secs as esp_idf_sys::time_t;
+    let mut tm = esp_idf_sys::tm::default();
+    unsafe { esp_idf_sys::gmtime_r(&now, &mut tm) };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+// days since 1970-01-01 for a proleptic Gregorian date, month 1..=12
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
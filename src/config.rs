@@ -0,0 +1,443 @@
+use crate::inverter::{DspFieldMeta, DSP_FIELDS};
+use crate::proto::aurora::FloatOrder;
+use crate::storage;
+use esp_idf_svc::nvs::EspDefaultNvs;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+const NAMESPACE: &str = "config";
+const KEY: &str = "device";
+// two inverters with identity reads need a few seconds of bus time
+pub const POLL_INTERVAL_MIN_SECS: u64 = 2;
+pub const POLL_INTERVAL_MAX_SECS: u64 = 3600;
+// ~23 requests a poll, more than this each would eat the shortest poll interval
+pub const REQUEST_GAP_MAX_MS: u64 = 80;
+const MQTT_KEEPALIVE_SECS: std::ops::RangeInclusive<u64> = 5..=600;
+const MQTT_RECONNECT_SECS: std::ops::RangeInclusive<u64> = 1..=600;
+// legal Aurora RS485 addresses
+const INVERTER_ID_RANGE: std::ops::RangeInclusive<u8> = 1..=63;
+const MQTT_SCHEMES: [&str; 4] = ["mqtt://", "mqtts://", "ws://", "wss://"];
+
+// RS485 protocol backend, each needs its cargo feature
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InverterKind {
+    Aurora,
+    Solax,
+}
+
+impl InverterKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InverterKind::Aurora => "aurora",
+            InverterKind::Solax => "solax",
+        }
+    }
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "aurora" => Some(InverterKind::Aurora),
+            "solax" => Some(InverterKind::Solax),
+            _ => None,
+        }
+    }
+    pub fn built_in(&self) -> bool {
+        match self {
+            InverterKind::Aurora => cfg!(feature = "aurora"),
+            InverterKind::Solax => cfg!(feature = "solax"),
+        }
+    }
+}
+
+// Solax wire format, the Modbus-RTU port needs no registration handshake
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SolaxFraming {
+    Aa55,
+    Modbus,
+}
+
+// InfluxDB v2 write target, plain http:// as the TLS certificate bundle isn't built in
+#[derive(Clone, Serialize, Deserialize)]
+pub struct InfluxConfig {
+    pub url: String,
+    pub token: String,
+    pub org: String,
+    pub bucket: String,
+}
+
+// Runtime credentials, NVS copy wins over the compile time .env values
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    pub ssid: String,
+    pub pass: String,
+    // (ssid, pass) for other sites, the strongest in range is joined
+    #[serde(default)]
+    pub extra_networks: Vec<(String, String)>,
+    // extra topic level so several gateways can share a broker, <topic>/<device_name>/...
+    #[serde(default)]
+    pub device_name: Option<String>,
+    // None uses crate::INVERTER_KIND
+    #[serde(default)]
+    pub inverter: Option<InverterKind>,
+    // Aurora RS485 addresses, None probes crate::AURORA_INVERTER_IDS
+    #[serde(default)]
+    pub inverter_ids: Option<Vec<u8>>,
+    // None uses the inverter protocol's default
+    #[serde(default)]
+    pub rs485_baud: Option<u32>,
+    // None uses the AA 55 protocol
+    #[serde(default)]
+    pub solax_framing: Option<SolaxFraming>,
+    // None polls every crate::MQTT_FREQUENCY
+    #[serde(default)]
+    pub poll_interval_secs: Option<u64>,
+    // primary first, None uses crate::NTP_SERVER and NTP_FALLBACK_SERVER
+    #[serde(default)]
+    pub ntp_servers: Option<Vec<String>>,
+    // POSIX TZ string, None uses crate::TIMEZONE
+    #[serde(default)]
+    pub timezone: Option<String>,
+    // pause between Aurora requests for slow transceivers, None uses crate::AURORA_REQUEST_GAP
+    #[serde(default)]
+    pub request_gap_ms: Option<u64>,
+    // Aurora measurement float byte order, None is big endian
+    #[serde(default)]
+    pub aurora_float_order: Option<FloatOrder>,
+    // inverter::DSP_FIELDS names measured each pass, None measures them all
+    #[serde(default)]
+    pub measurements: Option<Vec<String>>,
+    // RS485 transceiver powered only while the bus is in use, for battery or solar supplies
+    #[serde(default)]
+    pub low_power: bool,
+    pub mqtt_addr: String,
+    // extra brokers that get a copy of every publish, same credentials and topics
+    #[serde(default)]
+    pub mqtt_mirror_addrs: Vec<String>,
+    pub mqtt_username: String,
+    pub mqtt_password: String,
+    pub mqtt_client_id: String,
+    // None uses crate::MQTT_KEEPALIVE, MQTT_CLEAN_SESSION and MQTT_RECONNECT_TIMEOUT
+    #[serde(default)]
+    pub mqtt_keepalive_secs: Option<u64>,
+    #[serde(default)]
+    pub mqtt_clean_session: Option<bool>,
+    #[serde(default)]
+    pub mqtt_reconnect_secs: Option<u64>,
+    // PEM text for mqtts:// brokers, the CA is required, the client pair only for mutual TLS
+    #[serde(default)]
+    pub mqtt_ca_cert: Option<String>,
+    #[serde(default)]
+    pub mqtt_client_cert: Option<String>,
+    #[serde(default)]
+    pub mqtt_client_key: Option<String>,
+    pub http_username: String,
+    pub http_password: String,
+    // readings also written here each poll cycle when set
+    #[serde(default)]
+    pub influx: Option<InfluxConfig>,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            ssid: crate::SSID.to_string(),
+            pass: crate::PASS.to_string(),
+            extra_networks: vec![],
+            device_name: None,
+            inverter: None,
+            inverter_ids: None,
+            rs485_baud: None,
+            solax_framing: None,
+            poll_interval_secs: None,
+            ntp_servers: None,
+            timezone: None,
+            request_gap_ms: None,
+            aurora_float_order: None,
+            measurements: None,
+            low_power: false,
+            mqtt_addr: crate::MQTT_ADDR.to_string(),
+            mqtt_mirror_addrs: vec![],
+            mqtt_username: crate::MQTT_USERNAME.to_string(),
+            mqtt_password: crate::MQTT_PASSWORD.to_string(),
+            mqtt_client_id: crate::MQTT_CLIENT_ID.to_string(),
+            mqtt_keepalive_secs: None,
+            mqtt_clean_session: None,
+            mqtt_reconnect_secs: None,
+            mqtt_ca_cert: None,
+            mqtt_client_cert: None,
+            mqtt_client_key: None,
+            http_username: crate::HTTP_USERNAME.to_string(),
+            http_password: crate::HTTP_PASSWORD.to_string(),
+            influx: None,
+        }
+    }
+}
+
+// keep passwords out of the logs
+impl core::fmt::Debug for DeviceConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DeviceConfig")
+            .field("ssid", &self.ssid)
+            .field(
+                "extra_networks",
+                &self
+                    .extra_networks
+                    .iter()
+                    .map(|(ssid, _)| ssid)
+                    .collect::<Vec<_>>(),
+            )
+            .field("device_name", &self.device_name)
+            .field("inverter", &self.inverter)
+            .field("inverter_ids", &self.inverter_ids)
+            .field("rs485_baud", &self.rs485_baud)
+            .field("solax_framing", &self.solax_framing)
+            .field("poll_interval_secs", &self.poll_interval_secs)
+            .field("ntp_servers", &self.ntp_servers)
+            .field("timezone", &self.timezone)
+            .field("request_gap_ms", &self.request_gap_ms)
+            .field("aurora_float_order", &self.aurora_float_order)
+            .field("measurements", &self.measurements)
+            .field("low_power", &self.low_power)
+            .field("mqtt_addr", &self.mqtt_addr)
+            .field("mqtt_mirror_addrs", &self.mqtt_mirror_addrs)
+            .field("mqtt_username", &self.mqtt_username)
+            .field("mqtt_client_id", &self.mqtt_client_id)
+            .field("mqtt_keepalive_secs", &self.mqtt_keepalive_secs)
+            .field("mqtt_clean_session", &self.mqtt_clean_session)
+            .field("mqtt_reconnect_secs", &self.mqtt_reconnect_secs)
+            .field("mqtt_ca_cert", &self.mqtt_ca_cert.is_some())
+            .field("mqtt_client_cert", &self.mqtt_client_cert.is_some())
+            .field("http_username", &self.http_username)
+            .field("influx", &self.influx.as_ref().map(|influx| &influx.url))
+            .finish()
+    }
+}
+
+impl DeviceConfig {
+    // primary network first
+    pub fn networks(&self) -> Vec<(String, String)> {
+        let mut networks = vec![(self.ssid.clone(), self.pass.clone())];
+        networks.extend(self.extra_networks.iter().cloned());
+        networks
+    }
+    pub fn inverter_kind(&self) -> InverterKind {
+        self.inverter.unwrap_or(crate::INVERTER_KIND)
+    }
+    pub fn ntp_servers(&self) -> Vec<String> {
+        match &self.ntp_servers {
+            Some(servers) => servers.clone(),
+            None => vec![
+                crate::NTP_SERVER.to_string(),
+                crate::NTP_FALLBACK_SERVER.to_string(),
+            ],
+        }
+    }
+    pub fn timezone(&self) -> &str {
+        self.timezone.as_deref().unwrap_or(crate::TIMEZONE)
+    }
+    pub fn solax_framing(&self) -> SolaxFraming {
+        self.solax_framing.unwrap_or(SolaxFraming::Aa55)
+    }
+    // device_name, else the STA MAC in hex when crate::MQTT_TOPIC_WITH_MAC is set
+    pub fn device_node(&self, mac: &[u8; 6]) -> Option<String> {
+        match &self.device_name {
+            Some(name) => Some(name.clone()),
+            None if crate::MQTT_TOPIC_WITH_MAC => {
+                Some(mac.iter().map(|b| format!("{:02x}", b)).collect())
+            }
+            None => None,
+        }
+    }
+    pub fn inverter_ids(&self) -> Vec<u8> {
+        match &self.inverter_ids {
+            Some(ids) => ids.clone(),
+            None => crate::AURORA_INVERTER_IDS.to_vec(),
+        }
+    }
+    // checked before web or JSON edits are stored, a bad URL would need a reflash to undo
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for addr in std::iter::once(&self.mqtt_addr).chain(&self.mqtt_mirror_addrs) {
+            let url = addr.to_ascii_lowercase();
+            if !MQTT_SCHEMES.iter().any(|scheme| url.starts_with(scheme)) {
+                return Err(anyhow::anyhow!(
+                    "MQTT URL {} needs one of {:?}",
+                    addr,
+                    MQTT_SCHEMES
+                ));
+            }
+        }
+        if let Some(secs) = self.mqtt_keepalive_secs {
+            if !MQTT_KEEPALIVE_SECS.contains(&secs) {
+                return Err(anyhow::anyhow!(
+                    "MQTT keepalive {}s outside {:?}",
+                    secs,
+                    MQTT_KEEPALIVE_SECS
+                ));
+            }
+        }
+        if let Some(secs) = self.mqtt_reconnect_secs {
+            if !MQTT_RECONNECT_SECS.contains(&secs) {
+                return Err(anyhow::anyhow!(
+                    "MQTT reconnect timeout {}s outside {:?}",
+                    secs,
+                    MQTT_RECONNECT_SECS
+                ));
+            }
+        }
+        if let Some(name) = &self.device_name {
+            // one topic level, no MQTT wildcards or separators
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+            if name.is_empty() || !name.chars().all(valid) {
+                return Err(anyhow::anyhow!(
+                    "device name {} may only use letters, digits, - and _",
+                    name
+                ));
+            }
+        }
+        if let Some(influx) = &self.influx {
+            if !influx.url.to_ascii_lowercase().starts_with("http://") {
+                return Err(anyhow::anyhow!("InfluxDB URL {} needs http://", influx.url));
+            }
+            if influx.org.is_empty() || influx.bucket.is_empty() {
+                return Err(anyhow::anyhow!("InfluxDB needs an org and a bucket"));
+            }
+        }
+        self.validate_measurements()?;
+        let ids = self.inverter_ids();
+        if self.inverter_kind() == InverterKind::Aurora && ids.is_empty() {
+            return Err(anyhow::anyhow!("at least one inverter ID is needed"));
+        }
+        if let Some(id) = ids.iter().find(|id| !INVERTER_ID_RANGE.contains(id)) {
+            return Err(anyhow::anyhow!(
+                "inverter ID {} outside {:?}",
+                id,
+                INVERTER_ID_RANGE
+            ));
+        }
+        if let Some(servers) = &self.ntp_servers {
+            if servers.is_empty() || servers.iter().any(|server| server.trim().is_empty()) {
+                return Err(anyhow::anyhow!(
+                    "NTP servers {:?} need a name each",
+                    servers
+                ));
+            }
+        }
+        if let Some(timezone) = &self.timezone {
+            // tzset falls back to UTC on anything it can't parse, an empty string is the likely slip
+            if timezone.trim().is_empty() {
+                return Err(anyhow::anyhow!("timezone needs a POSIX TZ string"));
+            }
+        }
+        // two entries for one address would poll and publish it twice
+        if let Some((i, id)) = ids
+            .iter()
+            .enumerate()
+            .find(|(i, id)| ids[..*i].contains(id))
+        {
+            return Err(anyhow::anyhow!(
+                "inverter ID {} listed twice, entry {}",
+                id,
+                i + 1
+            ));
+        }
+        Ok(())
+    }
+    fn validate_measurements(&self) -> anyhow::Result<()> {
+        let names = match &self.measurements {
+            Some(names) => names,
+            None => return Ok(()),
+        };
+        if names.is_empty() {
+            return Err(anyhow::anyhow!("at least one measurement is needed"));
+        }
+        if let Some(name) = names
+            .iter()
+            .find(|name| !DSP_FIELDS.iter().any(|meta| meta.field == name.as_str()))
+        {
+            return Err(anyhow::anyhow!(
+                "unknown measurement {}, pick from {:?}",
+                name,
+                DSP_FIELDS.iter().map(|meta| meta.field).collect::<Vec<_>>()
+            ));
+        }
+        Ok(())
+    }
+    pub fn poll_interval(&self) -> Duration {
+        match self.poll_interval_secs {
+            Some(secs) if valid_poll_interval(secs) => Duration::from_secs(secs),
+            _ => crate::MQTT_FREQUENCY,
+        }
+    }
+    // DSP_FIELDS order whatever the listed order, duplicates measured once.
+    // All of them when no name matches, an empty pass would measure nothing
+    pub fn measurements(&self) -> Vec<DspFieldMeta> {
+        let measurements: Vec<DspFieldMeta> = DSP_FIELDS
+            .iter()
+            .filter(|meta| match &self.measurements {
+                Some(names) => names.iter().any(|name| name == meta.field),
+                None => true,
+            })
+            .copied()
+            .collect();
+        if measurements.is_empty() {
+            return DSP_FIELDS.to_vec();
+        }
+        measurements
+    }
+    pub fn mqtt_keepalive(&self) -> Duration {
+        match self.mqtt_keepalive_secs {
+            Some(secs) if MQTT_KEEPALIVE_SECS.contains(&secs) => Duration::from_secs(secs),
+            _ => crate::MQTT_KEEPALIVE,
+        }
+    }
+    pub fn mqtt_reconnect_timeout(&self) -> Duration {
+        match self.mqtt_reconnect_secs {
+            Some(secs) if MQTT_RECONNECT_SECS.contains(&secs) => Duration::from_secs(secs),
+            _ => crate::MQTT_RECONNECT_TIMEOUT,
+        }
+    }
+    pub fn request_gap(&self) -> Duration {
+        match self.request_gap_ms {
+            Some(ms) if valid_request_gap(ms) => Duration::from_millis(ms),
+            _ => crate::AURORA_REQUEST_GAP,
+        }
+    }
+}
+
+pub fn valid_request_gap(ms: u64) -> bool {
+    ms <= REQUEST_GAP_MAX_MS
+}
+
+pub fn valid_poll_interval(secs: u64) -> bool {
+    (POLL_INTERVAL_MIN_SECS..=POLL_INTERVAL_MAX_SECS).contains(&secs)
+}
+
+// First run seeds NVS from the .env defaults
+pub fn load(default_nvs: Arc<EspDefaultNvs>) -> anyhow::Result<DeviceConfig> {
+    let mut nvs = storage::open(default_nvs, NAMESPACE)?;
+    match storage::load(&nvs, KEY)? {
+        Some(mut config) => {
+            info!("Loaded config from NVS {:?}", config);
+            // stored by older firmware or a field renamed since, measure everything instead
+            if let Err(e) = config.validate_measurements() {
+                warn!("Ignoring stored measurements, {:?}", e);
+                config.measurements = None;
+            }
+            Ok(config)
+        }
+        None => {
+            let config = DeviceConfig::default();
+            storage::store(&mut nvs, KEY, &config)?;
+            info!("No stored config, saved defaults {:?}", config);
+            Ok(config)
+        }
+    }
+}
+
+// takes effect on the next boot
+pub fn store(default_nvs: Arc<EspDefaultNvs>, config: &DeviceConfig) -> anyhow::Result<()> {
+    let mut nvs = storage::open(default_nvs, NAMESPACE)?;
+    storage::store(&mut nvs, KEY, config)
+}
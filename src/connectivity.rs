@@ -0,0 +1,200 @@
+use crate::idf_mqtt::{self, MqttClientType, MqttLink, MqttSettings};
+use crate::wifi_init::{self, WifiSettings};
+use esp_idf_svc::wifi::EspWifi;
+use log::info;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// reconnect attempts back off from MIN doubling up to MAX
+const WIFI_BACKOFF_MIN: Duration = Duration::from_secs(10);
+const WIFI_BACKOFF_MAX: Duration = Duration::from_secs(300);
+// esp-mqtt retries on its own, the client is only rebuilt when that isn't enough
+const MQTT_BACKOFF_MIN: Duration = Duration::from_secs(30);
+const MQTT_BACKOFF_MAX: Duration = Duration::from_secs(600);
+
+struct Backoff {
+    next_attempt: Instant,
+    delay: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            next_attempt: Instant::now() + min,
+            delay: min,
+            min,
+            max,
+        }
+    }
+    // link is up, the first attempt after a drop waits min
+    fn reset(&mut self) {
+        self.delay = self.min;
+        self.next_attempt = Instant::now() + self.min;
+    }
+    fn due(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+    fn failed(&mut self) {
+        self.next_attempt = Instant::now() + self.delay;
+        self.delay = (self.delay * 2).min(self.max);
+    }
+}
+
+// Shared view of the network path to the broker
+#[derive(Clone)]
+pub struct Connectivity {
+    wifi: Arc<Mutex<Box<EspWifi>>>,
+    wifi_settings: Arc<WifiSettings>,
+    wifi_backoff: Arc<Mutex<Backoff>>,
+    mqtt_link: MqttLink,
+    mqtt_settings: Arc<MqttSettings>,
+    mqtt_backoff: Arc<Mutex<Backoff>>,
+    publish_failures: Arc<AtomicU32>,
+    // consecutive failed publishes before the client is rebuilt
+    reconnect_threshold: u32,
+}
+
+impl Connectivity {
+    pub fn new(
+        wifi: Arc<Mutex<Box<EspWifi>>>,
+        wifi_settings: WifiSettings,
+        mqtt_link: MqttLink,
+        mqtt_settings: MqttSettings,
+        reconnect_threshold: u32,
+    ) -> Self {
+        Self {
+            wifi,
+            wifi_settings: Arc::new(wifi_settings),
+            wifi_backoff: Arc::new(Mutex::new(Backoff::new(WIFI_BACKOFF_MIN, WIFI_BACKOFF_MAX))),
+            mqtt_link,
+            mqtt_settings: Arc::new(mqtt_settings),
+            mqtt_backoff: Arc::new(Mutex::new(Backoff::new(MQTT_BACKOFF_MIN, MQTT_BACKOFF_MAX))),
+            publish_failures: Arc::new(AtomicU32::new(0)),
+            reconnect_threshold,
+        }
+    }
+    pub fn wifi_up(&self) -> bool {
+        match self.wifi.lock() {
+            Ok(wifi) => wifi_init::is_connected(&wifi),
+            Err(_) => false,
+        }
+    }
+    pub fn wifi_ip(&self) -> Option<String> {
+        match self.wifi.lock() {
+            Ok(wifi) => wifi_init::ip(&wifi).map(|ip| ip.to_string()),
+            Err(_) => None,
+        }
+    }
+    pub fn wifi_ssid(&self) -> Option<String> {
+        match self.wifi.lock() {
+            Ok(wifi) => wifi_init::ssid(&wifi),
+            Err(_) => None,
+        }
+    }
+    pub fn rssi(&self) -> Option<i8> {
+        wifi_init::rssi()
+    }
+    pub fn mqtt_up(&self) -> bool {
+        self.mqtt_link.is_connected()
+    }
+    pub fn is_up(&self) -> bool {
+        self.wifi_up() && self.mqtt_up()
+    }
+    // is_up with the signal above min_rssi, an unknown RSSI doesn't hold publishes back
+    pub fn can_publish(&self, min_rssi: Option<i8>) -> bool {
+        if !self.is_up() {
+            return false;
+        }
+        match (min_rssi, self.rssi()) {
+            (Some(min), Some(rssi)) => rssi >= min,
+            _ => true,
+        }
+    }
+    // counts consecutive publish failures, passing the result through
+    pub fn track<T>(&self, result: anyhow::Result<T>) -> anyhow::Result<T> {
+        if result.is_ok() {
+            self.publish_failures.store(0, Ordering::Relaxed);
+        } else {
+            self.publish_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+    // a dropped AP is never rejoined by the driver on its own
+    pub fn reconnect_wifi_if_down(&self) {
+        let mut backoff = match self.wifi_backoff.try_lock() {
+            Ok(backoff) => backoff,
+            Err(_) => return,
+        };
+        if self.wifi_up() {
+            backoff.reset();
+            return;
+        }
+        if !backoff.due() {
+            return;
+        }
+        let result = match self.wifi.lock() {
+            Ok(mut wifi) => wifi_init::reconnect(&mut wifi, &self.wifi_settings),
+            Err(_) => return,
+        };
+        match result {
+            Ok(()) => backoff.reset(),
+            Err(e) => {
+                info!(
+                    "Wifi reconnect failed {:?}, next try in {:?}",
+                    e, backoff.delay
+                );
+                backoff.failed();
+            }
+        }
+    }
+    // session setup after each connect, and a fresh client when the broker stays away.
+    // true when a new session was set up
+    pub fn maintain_mqtt(&self, client_m: Arc<Mutex<MqttClientType>>) -> bool {
+        let mut new_session = false;
+        if self.mqtt_link.take_needs_setup() {
+            match idf_mqtt::on_connected(client_m.clone(), &self.mqtt_settings) {
+                Ok(()) => new_session = true,
+                Err(e) => {
+                    info!("MQTT session setup failed {:?}", e);
+                    self.mqtt_link.request_setup();
+                }
+            }
+        }
+        let mut backoff = match self.mqtt_backoff.try_lock() {
+            Ok(backoff) => backoff,
+            Err(_) => return new_session,
+        };
+        if self.mqtt_up() {
+            backoff.reset();
+            return new_session;
+        }
+        // nothing to reach the broker over yet
+        if !self.wifi_up() || !backoff.due() {
+            return new_session;
+        }
+        info!(
+            "MQTT still disconnected, rebuilding client, next try in {:?}",
+            backoff.delay
+        );
+        backoff.failed();
+        if let Err(e) = idf_mqtt::reconnect(client_m, &self.mqtt_settings, self.mqtt_link.clone()) {
+            info!("MQTT reconnect failed {:?}", e);
+        }
+        new_session
+    }
+    // catches half-open connections the keepalive hasn't noticed yet
+    pub fn reconnect_if_failing(&self, client_m: Arc<Mutex<MqttClientType>>) {
+        let failures = self.publish_failures.load(Ordering::Relaxed);
+        if failures < self.reconnect_threshold {
+            return;
+        }
+        info!("{} consecutive MQTT publish failures", failures);
+        self.publish_failures.store(0, Ordering::Relaxed);
+        if let Err(e) = idf_mqtt::reconnect(client_m, &self.mqtt_settings, self.mqtt_link.clone()) {
+            info!("MQTT reconnect failed {:?}", e);
+        }
+    }
+}
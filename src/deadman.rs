@@ -0,0 +1,67 @@
+use log::{error, info};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Restarts the device when no poll cycle has completed for too long. Runs on its own thread,
+// a wedged cycle also starves any other esp_timer callback
+#[derive(Clone)]
+pub struct Deadman {
+    last_cycle: Arc<Mutex<Instant>>,
+    limit: Arc<Mutex<Duration>>,
+    // poll interval multiple and floor, see crate::DEADMAN_POLL_MULTIPLE
+    multiple: u32,
+    min: Duration,
+}
+
+impl Deadman {
+    pub fn new(poll_interval: Duration, multiple: u32, min: Duration) -> Self {
+        let deadman = Self {
+            last_cycle: Arc::new(Mutex::new(Instant::now())),
+            limit: Arc::new(Mutex::new(Duration::ZERO)),
+            multiple,
+            min,
+        };
+        deadman.set_poll_interval(poll_interval);
+        deadman
+    }
+
+    // end of every poll cycle
+    pub fn kick(&self) {
+        if let Ok(mut last_cycle) = self.last_cycle.lock() {
+            *last_cycle = Instant::now();
+        }
+    }
+
+    // a slow cycle overrunning a short interval is not a stall, hence the floor
+    pub fn set_poll_interval(&self, poll_interval: Duration) {
+        if let Ok(mut limit) = self.limit.lock() {
+            *limit = (poll_interval * self.multiple).max(self.min);
+        }
+    }
+
+    // multiple 0 disables
+    pub fn start(&self) -> Option<JoinHandle<()>> {
+        if self.multiple == 0 {
+            return None;
+        }
+        let deadman = self.clone();
+        info!("Poll deadman armed");
+        Some(thread::spawn(move || loop {
+            thread::sleep(CHECK_INTERVAL);
+            let (since, limit) = match (deadman.last_cycle.lock(), deadman.limit.lock()) {
+                (Ok(last_cycle), Ok(limit)) => (last_cycle.elapsed(), *limit),
+                _ => continue,
+            };
+            if since > limit {
+                error!(
+                    "No poll cycle completed for {:?}, over the {:?} limit, restarting",
+                    since, limit
+                );
+                unsafe { esp_idf_sys::esp_restart() };
+            }
+        }))
+    }
+}
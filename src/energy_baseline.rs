@@ -0,0 +1,38 @@
+use crate::storage;
+use esp_idf_svc::nvs::EspDefaultNvs;
+use esp_idf_svc::nvs_storage::EspNvsStorage;
+use log::info;
+use std::sync::Arc;
+
+const NAMESPACE: &str = "energy";
+const KEY: &str = "baselines";
+
+// Gateway side energy resets. The Aurora protocol has no command that clears the partial
+// counter (that is only done on the inverter display), so each reset stores the lifetime
+// total instead and since_gateway_reset is published as the difference.
+pub struct EnergyBaselines {
+    storage: EspNvsStorage,
+    // (inverter id, total kWh at the reset)
+    baselines: Vec<(u8, f32)>,
+}
+
+impl EnergyBaselines {
+    pub fn load(default_nvs: Arc<EspDefaultNvs>) -> anyhow::Result<Self> {
+        let storage = storage::open(default_nvs, NAMESPACE)?;
+        let baselines: Vec<(u8, f32)> = storage::load(&storage, KEY)?.unwrap_or_default();
+        info!("Energy baselines {:?}", baselines);
+        Ok(Self { storage, baselines })
+    }
+    pub fn get(&self, id: u8) -> Option<f32> {
+        self.baselines
+            .iter()
+            .find(|(baseline_id, _)| *baseline_id == id)
+            .map(|(_, total)| *total)
+    }
+    // persisted before returning, a reset lost to a power cut would skew the billing period
+    pub fn reset(&mut self, id: u8, total: f32) -> anyhow::Result<()> {
+        self.baselines.retain(|(baseline_id, _)| *baseline_id != id);
+        self.baselines.push((id, total));
+        storage::store(&mut self.storage, KEY, &self.baselines)
+    }
+}
@@ -1,76 +1,605 @@
 use esp_idf_svc::timer::*;
 
-use crate::aurora::{Aurora, AuroraInverter};
-use crate::idf_mqtt::{mqtt_publish, MqttClientType};
-use crate::MQTT_TOPIC_NAME;
-use log::info;
+#[cfg(feature = "aurora")]
+use crate::aurora::Aurora;
+#[cfg(any(feature = "aurora", feature = "solax"))]
+use crate::backend::{Importance, InverterBackend, MqttMessage};
+use crate::config;
+#[cfg(feature = "solax")]
+use crate::idf_mqtt::publish_solax_ha_discovery;
+use crate::idf_mqtt::{announce_offline, mqtt_publish_with, MqttCommand, PublishOptions};
+use crate::influx::InfluxSink;
+use crate::inverter::SelfTest;
+#[cfg(feature = "aurora")]
+use crate::inverter::DSP_FIELDS;
+use crate::lifecycle::LifecycleTracker;
+use crate::state::AppState;
+#[cfg(feature = "aurora")]
+use crate::{idf_mqtt::publish_ha_discovery, MQTT_SINGLE_JSON};
+use crate::{MQTT_MIN_RSSI, PUBLISH_ONLY_WHEN_CONNECTED};
+use embedded_svc::timer::PeriodicTimer;
+use esp_idf_svc::nvs::EspDefaultNvs;
+use log::{info, warn};
+#[cfg(feature = "aurora")]
+use std::convert::TryInto;
 use std::{
-    sync::{Arc, Mutex},
+    sync::{atomic::Ordering, mpsc::Receiver, Arc, Mutex},
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
-fn inverter_poll_task(
-    inverters_arc_mutex: Arc<Mutex<Vec<AuroraInverter>>>,
-    aurora_arc_mutex: Arc<Mutex<Aurora>>,
-    mqttclient_arc_mutex: Arc<Mutex<MqttClientType>>,
-    boot_time: Instant,
-) {
-    if let Ok(mut aurora) = aurora_arc_mutex.try_lock() {
-        if let Ok(mut inverters) = inverters_arc_mutex.try_lock() {
-            for inverter in inverters.iter_mut() {
-                let json_data = {
-                    if aurora.poll_inverter(inverter).is_err() {
-                        println!("Poll error on ABB{}", inverter.id())
-                    };
-                    // send zeroed data if error - clears MQTT
-                    aurora.data_to_vec_mqtt_json(&inverter, MQTT_TOPIC_NAME)
-                };
-                if let Ok(d) = json_data {
-                    d.iter().for_each(|m| {
-                        if let Err(e) = mqtt_publish(
-                            mqttclient_arc_mutex.clone(),
-                            &m.topic,
-                            m.payload.as_bytes(),
-                        ) {
-                            println!("mqtt_publish error {:?} {:#?}", e, d);
-                        };
-                    });
-
-                    // update alive time update
-                    let message = format!("Uptime {:?}", Instant::now().duration_since(boot_time));
-                    if let Err(e) = mqtt_publish(
-                        mqttclient_arc_mutex.clone(),
-                        MQTT_TOPIC_NAME,
-                        message.as_bytes(),
-                    ) {
-                        println!("mqtt_publish error {:?} {:#?}", e, d);
-                    };
-                }
+fn inverter_poll_task(state: &AppState) {
+    let cycle_start = Instant::now();
+    let connectivity = &state.connectivity;
+    connectivity.reconnect_wifi_if_down();
+    if connectivity.maintain_mqtt(state.mqttclient.clone()) {
+        // the broker may have lost its retained copies
+        if let Ok(mut cache) = state.publish_cache.lock() {
+            cache.clear();
+        }
+        state.ha_discovery_sent.store(false, Ordering::Relaxed);
+    }
+    state.mqtt_mirrors.maintain();
+    // still poll while offline so internal state stays fresh, just don't publish into a dead link.
+    // Inverter messages are held in the outbox meanwhile
+    let publish = !PUBLISH_ONLY_WHEN_CONNECTED || connectivity.can_publish(MQTT_MIN_RSSI);
+    if !publish {
+        info!(
+            "WiFi/MQTT not connected or RSSI {:?} below {:?}, skipping MQTT publish",
+            connectivity.rssi(),
+            MQTT_MIN_RSSI
+        );
+    }
+
+    if let Ok(mut lifecycle) = state.lifecycle.try_lock() {
+        if let Err(e) = lifecycle.record_ota_time(state.clock.unix_secs()) {
+            info!("Lifecycle OTA time save failed {:?}", e);
+        }
+        if let Err(e) = lifecycle.save_if_due() {
+            info!("Lifecycle save failed {:?}", e);
+        }
+        if publish && lifecycle.take_unpublished() {
+            publish_lifecycle(state, &mut lifecycle);
+        }
+    }
+    // discovery configs are retained, once per session is enough. Only marked sent once every
+    // config went out, a failed one is retried next cycle
+    if publish && !state.ha_discovery_sent.load(Ordering::Relaxed) {
+        let mut sent = true;
+        #[cfg(feature = "aurora")]
+        if let Some(aurora) = &state.aurora {
+            sent &= publish_aurora_discovery(state, aurora);
+        }
+        #[cfg(feature = "solax")]
+        if state.solax.is_some() {
+            if let Err(e) = publish_solax_ha_discovery(
+                state.mqttclient.clone(),
+                &state.topic_prefix,
+                state.device_node.as_deref(),
+            ) {
+                warn!("HA discovery failed on Solax {:?}", e);
+                sent = false;
+            }
+        }
+        state.ha_discovery_sent.store(sent, Ordering::Relaxed);
+    }
+    #[cfg(feature = "aurora")]
+    if let Some(aurora) = &state.aurora {
+        backend_poll_task(state, aurora, publish);
+    }
+    #[cfg(feature = "solax")]
+    if let Some(solax) = &state.solax {
+        backend_poll_task(state, solax, publish);
+    }
+
+    if publish {
+        connectivity.reconnect_if_failing(state.mqttclient.clone());
+        publish_sys_diagnostics(state);
+    }
+    // independent of the broker, only needs the network
+    if let Some(influx) = &state.influx {
+        if connectivity.wifi_up() {
+            write_influx(state, influx);
+        }
+    }
+
+    if let Ok(mut stats) = state.stats.lock() {
+        stats.cycles = stats.cycles.wrapping_add(1);
+        stats.last_cycle_ms = cycle_start.elapsed().as_millis() as u32;
+    }
+    state.deadman.kick();
+}
+
+// HA discovery for each present inverter, unpolled fields would show as entities that never
+// get a value. False when any config failed to publish
+#[cfg(feature = "aurora")]
+fn publish_aurora_discovery(state: &AppState, aurora: &Mutex<Aurora>) -> bool {
+    let fields = match aurora.lock() {
+        Ok(aurora) => aurora.measurements().to_vec(),
+        Err(_) => DSP_FIELDS.to_vec(),
+    };
+    let inverters = match state.inverters.lock() {
+        Ok(inverters) => inverters,
+        Err(_) => return false,
+    };
+    let mut sent = true;
+    for inverter in inverters.iter().filter(|inverter| inverter.is_present()) {
+        if let Err(e) = publish_ha_discovery(
+            state.mqttclient.clone(),
+            &fields,
+            inverter.id(),
+            &state.topic_prefix,
+            state.device_node.as_deref(),
+            MQTT_SINGLE_JSON,
+        ) {
+            warn!("HA discovery failed on ABB{} {:?}", inverter.id(), e);
+            sent = false;
+        }
+    }
+    sent
+}
+
+fn write_influx(state: &AppState, influx: &InfluxSink) {
+    let lines = match state.inverters.try_lock() {
+        Ok(inverters) => crate::influx::line_protocol(
+            &inverters,
+            state.device_node.as_deref(),
+            state.clock.unix_secs(),
+        ),
+        Err(_) => return,
+    };
+    match lines {
+        Ok(lines) if lines.is_empty() => (),
+        Ok(lines) => {
+            if let Err(e) = influx.write(&lines) {
+                info!("InfluxDB write failed {:?}", e);
+            }
+        }
+        Err(e) => info!("InfluxDB line protocol failed {:?}", e),
+    }
+}
+
+// boot time probe of whichever backend the device config selected
+pub fn init_backends(state: &AppState) {
+    #[cfg(feature = "aurora")]
+    if let Some(aurora) = &state.aurora {
+        init_backend(state, aurora);
+    }
+    #[cfg(feature = "solax")]
+    if let Some(solax) = &state.solax {
+        init_backend(state, solax);
+    }
+}
+
+#[cfg(any(feature = "aurora", feature = "solax"))]
+fn init_backend<B: InverterBackend>(state: &AppState, backend: &Arc<Mutex<B>>) {
+    match backend.lock() {
+        Ok(mut backend) => {
+            if let Err(e) = on_bus(state, || backend.init(state)) {
+                info!("{} init failed {:?}", B::NAME, e);
             }
-        } else {
-            info!("Inverter lock failed, skipping inverter poll")
         }
+        Err(_) => info!("{} lock failed, skipping init", B::NAME),
+    }
+}
+
+// a low_power transceiver is powered for the duration of f
+#[cfg(any(feature = "aurora", feature = "solax"))]
+fn on_bus<T>(state: &AppState, f: impl FnOnce() -> T) -> T {
+    if let Ok(mut transceiver) = state.transceiver.lock() {
+        if let Err(e) = transceiver.wake() {
+            info!("RS485 transceiver power up failed {:?}", e);
+        }
+    }
+    let result = f();
+    if let Ok(mut transceiver) = state.transceiver.lock() {
+        if let Err(e) = transceiver.release() {
+            info!("RS485 transceiver power down failed {:?}", e);
+        }
+    }
+    result
+}
+
+#[cfg(any(feature = "aurora", feature = "solax"))]
+fn backend_poll_task<B: InverterBackend>(state: &AppState, backend: &Arc<Mutex<B>>, publish: bool) {
+    if let Ok(mut backend) = backend.try_lock() {
+        on_bus(state, || {
+            backend.poll(state, &state.topic_prefix, &mut |messages| {
+                if publish {
+                    publish_messages(state, messages);
+                } else {
+                    hold_messages(state, messages);
+                }
+            })
+        });
     } else {
-        info!("Aurora lock failed, skipping inverter poll")
+        info!("{} lock failed, skipping inverter poll", B::NAME)
+    }
+}
+
+#[cfg(any(feature = "aurora", feature = "solax"))]
+fn publish_messages(state: &AppState, messages: Vec<MqttMessage>) {
+    // held from an outage go first, unless this poll has a newer value for the topic
+    let held = match state.outbox.lock() {
+        Ok(mut outbox) => outbox.take(),
+        Err(_) => vec![],
+    };
+    if !held.is_empty() {
+        info!("Flushing {} held MQTT messages", held.len());
+    }
+    let pending = held
+        .into_iter()
+        .filter(|h| !messages.iter().any(|m| m.topic == h.topic))
+        .chain(messages);
+    let mut failed = vec![];
+    let mut cache = state.publish_cache.lock().ok();
+    for m in pending {
+        if let Some(cache) = cache.as_mut() {
+            if !cache.should_publish(&m.topic, &m.payload) {
+                continue;
+            }
+        }
+        let options = match m.importance {
+            Importance::Normal => PublishOptions::default(),
+            Importance::Critical => PublishOptions::at_least_once(),
+            Importance::Retained => PublishOptions {
+                retain: true,
+                ..PublishOptions::at_least_once()
+            },
+        };
+        match publish_all(state, &m.topic, m.payload.as_bytes(), options) {
+            Ok(()) => {
+                if let Some(cache) = cache.as_mut() {
+                    cache.published(&m.topic, &m.payload);
+                }
+            }
+            Err(e) => {
+                warn!("MQTT publish to {} failed {:?}", m.topic, e);
+                failed.push(m);
+            }
+        }
+    }
+    hold_messages(state, failed);
+}
+
+#[cfg(any(feature = "aurora", feature = "solax"))]
+fn hold_messages(state: &AppState, messages: Vec<MqttMessage>) {
+    if messages.is_empty() {
+        return;
+    }
+    if let Ok(mut outbox) = state.outbox.lock() {
+        outbox.hold(messages);
+    }
+}
+
+// <topic>/sys/*, plain values for trending uptime resets, heap leaks and link quality
+fn publish_sys_diagnostics(state: &AppState) {
+    let connectivity = &state.connectivity;
+    let free_heap = unsafe { esp_idf_sys::esp_get_free_heap_size() };
+    let mut values = vec![
+        (
+            "uptime_secs",
+            state.boot_time.elapsed().as_secs().to_string(),
+            PublishOptions::retained(),
+        ),
+        (
+            "free_heap",
+            free_heap.to_string(),
+            PublishOptions::default(),
+        ),
+        (
+            "fw_version",
+            crate::VERSION.to_string(),
+            PublishOptions::retained(),
+        ),
+    ];
+    // poll cycles since boot, a stalled poll loop stops it while uptime keeps counting.
+    // Not retained so a dead gateway doesn't leave a plausible value behind
+    if let Ok(stats) = state.stats.lock() {
+        values.push((
+            "heartbeat",
+            stats.cycles.to_string(),
+            PublishOptions::default(),
+        ));
+        // length of the previous poll cycle, all inverters on the bus included
+        values.push((
+            "cycle_ms",
+            stats.last_cycle_ms.to_string(),
+            PublishOptions::default(),
+        ));
+    }
+    // link details are skipped while unknown
+    for (name, value) in [
+        ("rssi", connectivity.rssi().map(|rssi| rssi.to_string())),
+        ("ssid", connectivity.wifi_ssid()),
+        ("ip", connectivity.wifi_ip()),
+    ] {
+        if let Some(value) = value {
+            values.push((name, value, PublishOptions::default()));
+        }
+    }
+    for (name, value, options) in values {
+        let topic = format!("{}/sys/{}", state.topic_prefix, name);
+        if let Err(e) = publish_all(state, &topic, value.as_bytes(), options) {
+            warn!("MQTT publish to {} failed {:?}", topic, e);
+        }
+    }
+}
+
+// primary result is returned and counted for the reconnect logic, mirror failures are only logged
+fn publish_all(
+    state: &AppState,
+    topic: &str,
+    payload: &[u8],
+    options: PublishOptions,
+) -> anyhow::Result<()> {
+    let result = state.connectivity.track(mqtt_publish_with(
+        state.mqttclient.clone(),
+        topic,
+        payload,
+        options,
+    ));
+    for (url, mirrored) in state.mqtt_mirrors.publish(topic, payload, options) {
+        if let Err(e) = mirrored {
+            info!("MQTT mirror {} publish failed {:?} {}", url, e, topic);
+        }
+    }
+    result
+}
+
+fn publish_lifecycle(state: &AppState, lifecycle: &mut LifecycleTracker) {
+    let topic = format!("{}/lifecycle", state.topic_prefix);
+    match lifecycle.to_json() {
+        Ok(json) => {
+            if let Err(e) = publish_all(state, &topic, json.as_bytes(), PublishOptions::retained())
+            {
+                warn!("MQTT publish to {} failed {:?}", topic, e);
+            }
+        }
+        Err(e) => info!("Lifecycle serialise failed {:?}", e),
+    }
+}
+
+// commands from <topic>/cmd, a poll runs alongside the timer, the bus locks keep them apart
+pub fn mqtt_command_listener(
+    state: AppState,
+    commands: Receiver<MqttCommand>,
+    poller: Arc<Mutex<EspTimer>>,
+    default_nvs: Arc<EspDefaultNvs>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for command in commands {
+            match command {
+                MqttCommand::Poll => inverter_poll_task(&state),
+                MqttCommand::Interval(secs) => {
+                    match set_poll_interval(&poller, default_nvs.clone(), secs) {
+                        Ok(()) => state.deadman.set_poll_interval(Duration::from_secs(secs)),
+                        Err(e) => info!("Poll interval change to {}s failed {:?}", secs, e),
+                    }
+                }
+                MqttCommand::SetTime(secs) => {
+                    if let Err(e) = set_inverter_time(&state, secs) {
+                        info!("Inverter clock set failed {:?}", e);
+                    }
+                }
+                MqttCommand::SelfTest => publish_self_test(&state),
+                MqttCommand::RawDsp {
+                    id,
+                    function,
+                    command,
+                } => {
+                    if let Err(e) = raw_dsp(&state, id, function, command) {
+                        info!("Raw DSP request failed {:?}", e);
+                    }
+                }
+                MqttCommand::ResetEnergy { id, confirm } => {
+                    if let Err(e) = reset_energy(&state, id, &confirm) {
+                        info!("ABB{} energy reset refused {:?}", id, e);
+                    }
+                }
+                MqttCommand::Restart => {
+                    info!("Restart requested over MQTT");
+                    if let Err(e) = announce_offline(state.mqttclient.clone(), &state.topic_prefix)
+                    {
+                        info!("Offline announcement failed {:?}", e);
+                    }
+                    state.mqtt_mirrors.announce_offline(&state.topic_prefix);
+                    // let the connection thread ack the message and send the announcement
+                    thread::sleep(Duration::from_secs(1));
+                    unsafe { esp_idf_sys::esp_restart() };
+                }
+            }
+        }
+        info!("MQTT command channel closed");
+    })
+}
+
+// starts a new since_gateway_reset period from the current total, confirm is the inverter serial.
+// gateway side only, the inverter's counters are unchanged. returns the stored baseline
+pub fn reset_energy(state: &AppState, id: u8, confirm: &str) -> anyhow::Result<f32> {
+    let mut inverters = state
+        .inverters
+        .lock()
+        .map_err(|_| anyhow::anyhow!("inverter list lock failed"))?;
+    let inverter = inverters
+        .iter_mut()
+        .find(|inverter| inverter.id() == id)
+        .ok_or_else(|| anyhow::anyhow!("no inverter {}", id))?;
+    // the total is only current while the inverter answers
+    if !inverter.is_online() {
+        return Err(anyhow::anyhow!("ABB{} is offline", id));
+    }
+    match &inverter.identity {
+        Some(identity) if identity.serial == confirm.trim() => (),
+        Some(_) => {
+            return Err(anyhow::anyhow!(
+                "confirmation doesn't match the ABB{} serial",
+                id
+            ))
+        }
+        None => return Err(anyhow::anyhow!("ABB{} serial not read yet", id)),
+    }
+    let total = inverter.energy.total();
+    state
+        .energy_baselines
+        .lock()
+        .map_err(|_| anyhow::anyhow!("energy baseline lock failed"))?
+        .reset(id, total)?;
+    inverter.energy.set_baseline(Some(total));
+    info!("ABB{} energy reset at {} kWh", id, total);
+    Ok(total)
+}
+
+// every configured ID, absent ones included, the point is finding out what's on the bus
+#[cfg(feature = "aurora")]
+pub fn self_test(state: &AppState) -> anyhow::Result<Vec<SelfTest>> {
+    let aurora = match &state.aurora {
+        Some(aurora) => aurora,
+        None => return Err(anyhow::anyhow!("no Aurora inverters configured")),
+    };
+    let mut aurora = aurora
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Aurora lock failed"))?;
+    let mut inverters = state
+        .inverters
+        .lock()
+        .map_err(|_| anyhow::anyhow!("inverter list lock failed"))?;
+    Ok(on_bus(state, || {
+        inverters
+            .iter_mut()
+            .map(|inverter| aurora.self_test(inverter))
+            .collect()
+    }))
+}
+
+#[cfg(not(feature = "aurora"))]
+pub fn self_test(_state: &AppState) -> anyhow::Result<Vec<SelfTest>> {
+    Err(anyhow::anyhow!("built without the aurora feature"))
+}
+
+fn publish_self_test(state: &AppState) {
+    let payload = match self_test(state) {
+        Ok(results) => serde_json::json!(results),
+        Err(e) => serde_json::json!({ "error": e.to_string() }),
+    }
+    .to_string();
+    let topic = format!("{}/sys/selftest", state.topic_prefix);
+    if let Err(e) = mqtt_publish_with(
+        state.mqttclient.clone(),
+        &topic,
+        payload.as_bytes(),
+        PublishOptions::at_least_once(),
+    ) {
+        info!("Self test publish failed {:?}", e);
+    }
+}
+
+// one reply per addressed inverter on <topic>/debug/raw, None addresses every present one
+#[cfg(feature = "aurora")]
+fn raw_dsp(state: &AppState, id: Option<u8>, function: u8, command: u8) -> anyhow::Result<()> {
+    let function = crate::proto::aurora::DspFunction::read_from_code(function)
+        .ok_or_else(|| anyhow::anyhow!("function {} is not a read", function))?;
+    let aurora = match &state.aurora {
+        Some(aurora) => aurora,
+        None => return Err(anyhow::anyhow!("no Aurora inverters configured")),
+    };
+    let mut aurora = aurora
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Aurora lock failed"))?;
+    let mut inverters = state
+        .inverters
+        .lock()
+        .map_err(|_| anyhow::anyhow!("inverter list lock failed"))?;
+    let topic = format!("{}/debug/raw", state.topic_prefix);
+    for inverter in inverters.iter_mut().filter(|inverter| match id {
+        Some(id) => inverter.id() == id,
+        None => inverter.is_present(),
+    }) {
+        let reply = on_bus(state, || aurora.raw_measure(inverter, function, command));
+        mqtt_publish_with(
+            state.mqttclient.clone(),
+            &topic,
+            reply.to_string().as_bytes(),
+            PublishOptions::at_least_once(),
+        )?;
     }
+    Ok(())
+}
+
+#[cfg(not(feature = "aurora"))]
+fn raw_dsp(_state: &AppState, _id: Option<u8>, _function: u8, _command: u8) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("built without the aurora feature"))
+}
+
+// every present inverter, None takes the SNTP local time
+#[cfg(feature = "aurora")]
+fn set_inverter_time(state: &AppState, secs: Option<u32>) -> anyhow::Result<()> {
+    let secs = match secs {
+        Some(secs) => secs,
+        None => match state.clock.local_secs_since_2000() {
+            Some(secs) => secs.try_into()?,
+            None => return Err(anyhow::anyhow!("clock not synced yet")),
+        },
+    };
+    let aurora = match &state.aurora {
+        Some(aurora) => aurora,
+        None => return Err(anyhow::anyhow!("no Aurora inverters configured")),
+    };
+    let mut aurora = aurora
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Aurora lock failed"))?;
+    let mut inverters = state
+        .inverters
+        .lock()
+        .map_err(|_| anyhow::anyhow!("inverter list lock failed"))?;
+    for inverter in inverters
+        .iter_mut()
+        .filter(|inverter| inverter.is_present())
+    {
+        if let Err(e) = on_bus(state, || aurora.set_time(inverter, secs)) {
+            warn!("{:?}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "aurora"))]
+fn set_inverter_time(_state: &AppState, _secs: Option<u32>) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("built without the aurora feature"))
+}
+
+// rearms the running timer and persists the period for the next boot
+fn set_poll_interval(
+    poller: &Arc<Mutex<EspTimer>>,
+    default_nvs: Arc<EspDefaultNvs>,
+    secs: u64,
+) -> anyhow::Result<()> {
+    if !config::valid_poll_interval(secs) {
+        return Err(anyhow::anyhow!(
+            "outside {}..={}s",
+            config::POLL_INTERVAL_MIN_SECS,
+            config::POLL_INTERVAL_MAX_SECS
+        ));
+    }
+    let mut device_config = config::load(default_nvs.clone())?;
+    device_config.poll_interval_secs = Some(secs);
+    config::store(default_nvs, &device_config)?;
+    match poller.lock() {
+        Ok(mut timer) => timer.every(Duration::from_secs(secs))?,
+        Err(_) => return Err(anyhow::anyhow!("poll timer lock failed")),
+    }
+    info!("Polling every {}s", secs);
+    Ok(())
 }
 
 pub fn periodic_inverter_event(
-    inverters: Arc<Mutex<Vec<AuroraInverter>>>,
-    aurora: Arc<Mutex<Aurora>>,
-    mqttclient: Arc<Mutex<MqttClientType>>,
+    state: AppState,
     poll_frequency: Duration,
-    boot_time: Instant,
 ) -> anyhow::Result<EspTimer> {
-    use embedded_svc::timer::PeriodicTimer;
     use embedded_svc::timer::TimerService as _;
+
     let mut periodic_timer = esp_idf_svc::timer::EspTimerService::new()?.timer(move || {
-        inverter_poll_task(
-            inverters.clone(),
-            aurora.clone(),
-            mqttclient.clone(),
-            boot_time,
-        );
+        inverter_poll_task(&state);
     })?;
 
     periodic_timer.every(poll_frequency)?;
@@ -0,0 +1,68 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Frame {
+    pub uptime_ms: u64,
+    pub backend: &'static str,
+    pub direction: Direction,
+    pub hex: String,
+}
+
+// Last `capacity` raw RS485 frames for GET /frames, oldest dropped first
+#[derive(Clone)]
+pub struct FrameLog {
+    frames: Arc<Mutex<VecDeque<Frame>>>,
+    capacity: usize,
+    boot_time: Instant,
+}
+
+impl FrameLog {
+    pub fn new(capacity: usize, boot_time: Instant) -> Self {
+        Self {
+            frames: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+            boot_time,
+        }
+    }
+    // never holds up the bus, a frame is dropped while a request is reading the log
+    pub fn record(&self, backend: &'static str, direction: Direction, bytes: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut frames = match self.frames.try_lock() {
+            Ok(frames) => frames,
+            Err(_) => return,
+        };
+        if frames.len() == self.capacity {
+            frames.pop_front();
+        }
+        frames.push_back(Frame {
+            uptime_ms: self.boot_time.elapsed().as_millis() as u64,
+            backend,
+            direction,
+            hex: bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" "),
+        });
+    }
+    // oldest first
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        let frames = self
+            .frames
+            .lock()
+            .map_err(|_| anyhow::anyhow!("frame log lock failed"))?;
+        Ok(serde_json::to_string(&*frames)?)
+    }
+}
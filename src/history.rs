@@ -0,0 +1,60 @@
+use crate::inverter::{AuroraInverter, Dsp, EnergyTotals};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+// One inverter after one poll cycle
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Snapshot {
+    pub uptime_secs: u64,
+    pub online: bool,
+    pub data: Dsp,
+    pub energy: EnergyTotals,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InverterHistory {
+    pub id: u8,
+    // oldest first
+    pub snapshots: VecDeque<Snapshot>,
+}
+
+// Last `capacity` poll snapshots per inverter, oldest dropped first
+#[derive(Debug, Serialize)]
+pub struct History {
+    #[serde(skip)]
+    capacity: usize,
+    inverters: Vec<InverterHistory>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inverters: vec![],
+        }
+    }
+
+    pub fn push(&mut self, inverter: &AuroraInverter, uptime_secs: u64) {
+        let snapshot = Snapshot {
+            uptime_secs,
+            online: inverter.is_online(),
+            data: inverter.data,
+            energy: inverter.energy,
+        };
+        let index = match self.inverters.iter().position(|h| h.id == inverter.id()) {
+            Some(index) => index,
+            None => {
+                self.inverters.push(InverterHistory {
+                    id: inverter.id(),
+                    snapshots: VecDeque::with_capacity(self.capacity),
+                });
+                self.inverters.len() - 1
+            }
+        };
+        let snapshots = &mut self.inverters[index].snapshots;
+        if snapshots.len() == self.capacity {
+            snapshots.pop_front();
+        }
+        snapshots.push_back(snapshot);
+    }
+}
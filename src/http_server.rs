@@ -0,0 +1,1042 @@
+use crate::config::{self, DeviceConfig, InverterKind};
+use crate::idf_mqtt::{
+    announce_offline, mqtt_publish_with, mqtt_try_publish, MqttClientType, MqttMirrors,
+    PublishOptions, HA_ENERGY_SENSORS,
+};
+use crate::inflate::{Encoding, Inflater};
+use crate::inverter::{
+    AlarmCode, Dsp, EnergyFunction, EnergyTotals, Identity, OperatingState, DSP_FIELDS,
+};
+use crate::multipart::MultipartFile;
+use crate::state::{AppState, PollStats};
+use anyhow::anyhow;
+use embedded_svc::http::server::registry::Registry;
+use embedded_svc::http::server::*;
+use embedded_svc::http::*;
+use embedded_svc::io::{Read, Write};
+use embedded_svc::ota::{Ota, OtaUpdate};
+use esp_idf_svc::http::server::{EspHttpRequest, EspHttpServer};
+use esp_idf_svc::nvs::EspDefaultNvs;
+use esp_idf_svc::ota::EspOta;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const REALM: &str = "Basic realm=\"abb_to_mqtt\"";
+const MAX_FORM_LEN: usize = 8192;
+// lets the response reach the browser before the restart
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+// hex digest of the whole image, e.g. curl -H "X-Firmware-Sha256: $(sha256sum fw.bin)" --data-binary @fw.bin
+// compressed uploads add -H "Content-Encoding: gzip" --data-binary @fw.bin.gz, or ?gzip=1 for
+// form uploads, the digest is still of the uncompressed fw.bin
+const SHA256_HEADER: &str = "X-Firmware-Sha256";
+const OTA_CHUNK: usize = 4096;
+// percent between <topic>/sys/ota_progress updates
+const OTA_PROGRESS_STEP: usize = 5;
+
+#[derive(Serialize)]
+struct InverterDiag {
+    id: u8,
+    online: bool,
+    poll_errors: u32,
+    last_seen_secs: u64,
+    address_collision: bool,
+    // rolling average, the reply timeout follows it
+    turnaround_ms: Option<f32>,
+    // last complete poll pass, interleaved with any other inverters on the bus
+    last_pass_ms: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    // profile and inverter features compiled in
+    build: String,
+    idf_version: String,
+    chip: String,
+}
+
+#[derive(Serialize)]
+struct Diagnostics {
+    firmware_version: &'static str,
+    uptime_secs: u64,
+    free_heap: u32,
+    wifi_connected: bool,
+    wifi_rssi: Option<i8>,
+    wifi_ip: Option<String>,
+    mqtt_connected: bool,
+    sntp_synced: bool,
+    poll: PollStats,
+    inverters: Vec<InverterDiag>,
+}
+
+#[derive(Serialize)]
+struct InverterStatus {
+    id: u8,
+    // answered discovery, absent inverters carry no readings
+    present: bool,
+    online: bool,
+    last_seen_secs: u64,
+    identity: Option<Identity>,
+    alarms: Vec<AlarmCode>,
+    operating_state: Option<OperatingState>,
+    data: Dsp,
+    energy: EnergyTotals,
+    // None until the energy command has been probed
+    energy_function: Option<EnergyFunction>,
+}
+
+#[derive(Serialize)]
+struct StatusReport {
+    firmware_version: &'static str,
+    uptime_secs: u64,
+    wifi_connected: bool,
+    mqtt_connected: bool,
+    inverters: Vec<InverterStatus>,
+    solax: Option<serde_json::Value>,
+}
+
+pub fn httpd(
+    state: AppState,
+    default_nvs: Arc<EspDefaultNvs>,
+    username: &str,
+    password: &str,
+) -> anyhow::Result<EspHttpServer> {
+    let auth = basic_auth(username, password);
+    let mut server = EspHttpServer::new(&Default::default())?;
+    handle_config(
+        &mut server,
+        default_nvs,
+        &auth,
+        Some(Announcer::from(&state)),
+    )?;
+
+    let restart_auth = auth.clone();
+    let restart_announcer = Announcer::from(&state);
+    server.handle_post("/restart", move |req, resp| {
+        if !authorized(&req, &restart_auth) {
+            resp.status(401)
+                .header("WWW-Authenticate", REALM)
+                .send_str("Unauthorized")?;
+            return Ok(());
+        }
+        resp.send_str("Restarting")?;
+        schedule_restart(Some(restart_announcer.clone()));
+        Ok(())
+    })?;
+
+    // raw or gzip image body, only marked bootable once the digest matches; ?reboot=1 restarts into it
+    let ota_auth = auth.clone();
+    let ota_announcer = Announcer::from(&state);
+    server.handle_post("/ota", move |mut req, resp| {
+        if !authorized(&req, &ota_auth) {
+            resp.status(401)
+                .header("WWW-Authenticate", REALM)
+                .send_str("Unauthorized")?;
+            return Ok(());
+        }
+        let query = req.query_string();
+        let reboot = query_flag(&query, "reboot");
+        let gzip = query_flag(&query, "gzip");
+        let result = ota_processing(&mut req, &ota_announcer, gzip);
+        ota_announcer.publish_result(&result);
+        match result {
+            Ok(bytes) if reboot => {
+                resp.send_str(&format!("Firmware verified ({} bytes), restarting", bytes))?;
+                schedule_restart(Some(ota_announcer.clone()));
+            }
+            Ok(bytes) => {
+                resp.send_str(&format!(
+                    "Firmware verified ({} bytes), runs after the next restart",
+                    bytes
+                ))?;
+            }
+            Err(e) => {
+                log::info!("OTA failed {:?}", e);
+                resp.status(400).send_str(&format!("OTA failed: {}", e))?;
+            }
+        }
+        Ok(())
+    })?;
+
+    // confirms what's running before and after an OTA upload
+    server.handle_get("/version", move |_req, resp| {
+        let json = serde_json::to_string(&version_info())?;
+        resp.header("Content-Type", "application/json")
+            .send_str(&json)?;
+        Ok(())
+    })?;
+    server.handle_get("/", move |_req, resp| {
+        resp.header("Content-Type", "text/html")
+            .send_str(&landing_page(&version_info()))?;
+        Ok(())
+    })?;
+    // the upload POSTs to /ota, which checks the credentials
+    server.handle_get("/ota", move |_req, resp| {
+        resp.header("Content-Type", "text/html")
+            .send_str(&ota_page(&version_info()))?;
+        Ok(())
+    })?;
+
+    // same data as the MQTT topics, left open for scrapers
+    let metrics_state = state.clone();
+    server.handle_get("/metrics", move |_req, resp| {
+        let text = metrics(&metrics_state)?;
+        resp.header("Content-Type", "text/plain; version=0.0.4")
+            .send_str(&text)?;
+        Ok(())
+    })?;
+    let status_state = state.clone();
+    server.handle_get("/status", move |_req, resp| {
+        let json = serde_json::to_string(&status_report(&status_state)?)?;
+        resp.header("Content-Type", "application/json")
+            .send_str(&json)?;
+        Ok(())
+    })?;
+
+    let history_state = state.clone();
+    server.handle_get("/history", move |_req, resp| {
+        let json = match history_state.history.lock() {
+            Ok(history) => serde_json::to_string(&*history)?,
+            Err(_) => return Err(anyhow!("history lock failed").into()),
+        };
+        resp.header("Content-Type", "application/json")
+            .send_str(&json)?;
+        Ok(())
+    })?;
+
+    // raw bytes for bug reports, the poll keeps running while it's read
+    let frames_state = state.clone();
+    server.handle_get("/frames", move |_req, resp| {
+        let json = frames_state.frame_log.to_json()?;
+        resp.header("Content-Type", "application/json")
+            .send_str(&json)?;
+        Ok(())
+    })?;
+
+    // may show network and broker names, so credentials required
+    let logs_auth = auth.clone();
+    server.handle_get("/logs", move |req, resp| {
+        if !authorized(&req, &logs_auth) {
+            resp.status(401)
+                .header("WWW-Authenticate", REALM)
+                .send_str("Unauthorized")?;
+            return Ok(());
+        }
+        resp.header("Content-Type", "text/plain; charset=utf-8")
+            .send_str(&crate::log_ring::dump())?;
+        Ok(())
+    })?;
+
+    // drives the RS485 bus, so credentials required
+    let selftest_auth = auth.clone();
+    let selftest_state = state.clone();
+    server.handle_get("/selftest", move |req, resp| {
+        if !authorized(&req, &selftest_auth) {
+            resp.status(401)
+                .header("WWW-Authenticate", REALM)
+                .send_str("Unauthorized")?;
+            return Ok(());
+        }
+        match crate::events::self_test(&selftest_state) {
+            Ok(results) => {
+                let json = serde_json::to_string(&results)?;
+                resp.header("Content-Type", "application/json")
+                    .send_str(&json)?;
+            }
+            Err(e) => {
+                resp.status(503)
+                    .send_str(&format!("Self test unavailable: {}", e))?;
+            }
+        }
+        Ok(())
+    })?;
+
+    // ?id=2&confirm=<inverter serial>, the serial makes a stray or mistyped request harmless
+    let reset_auth = auth.clone();
+    let reset_state = state.clone();
+    server.handle_post("/reset_energy", move |req, resp| {
+        if !authorized(&req, &reset_auth) {
+            resp.status(401)
+                .header("WWW-Authenticate", REALM)
+                .send_str("Unauthorized")?;
+            return Ok(());
+        }
+        let query = req.query_string();
+        let id = query_value(&query, "id").and_then(|id| id.parse::<u8>().ok());
+        let confirm = query_value(&query, "confirm").unwrap_or_default();
+        let result = match id {
+            Some(id) => crate::events::reset_energy(&reset_state, id, &confirm),
+            None => Err(anyhow!("id query parameter missing")),
+        };
+        match result {
+            Ok(total) => resp.send_str(&format!("Energy reset at {} kWh", total))?,
+            Err(e) => resp
+                .status(400)
+                .send_str(&format!("Energy reset refused: {}", e))?,
+        }
+        Ok(())
+    })?;
+
+    // reveals network details, so credentials required
+    server.handle_get("/diag", move |req, resp| {
+        if !authorized(&req, &auth) {
+            resp.status(401)
+                .header("WWW-Authenticate", REALM)
+                .send_str("Unauthorized")?;
+            return Ok(());
+        }
+        let json = serde_json::to_string(&diagnostics(&state))?;
+        resp.header("Content-Type", "application/json")
+            .send_str(&json)?;
+        Ok(())
+    })?;
+
+    Ok(server)
+}
+
+// Config form only, for the SoftAP fallback
+pub fn config_portal(
+    default_nvs: Arc<EspDefaultNvs>,
+    username: &str,
+    password: &str,
+) -> anyhow::Result<EspHttpServer> {
+    let mut server = EspHttpServer::new(&Default::default())?;
+    handle_config(
+        &mut server,
+        default_nvs,
+        &basic_auth(username, password),
+        None,
+    )?;
+    Ok(server)
+}
+
+// GET renders the form (?json=1 for JSON without secrets), POST stores a form or a partial
+// JSON object to NVS after validation, restarting when reboot=1 is in the query or form
+fn handle_config(
+    server: &mut EspHttpServer,
+    default_nvs: Arc<EspDefaultNvs>,
+    auth: &str,
+    // None in the config portal, there is no broker connection there
+    announcer: Option<Announcer>,
+) -> anyhow::Result<()> {
+    let get_auth = auth.to_string();
+    let get_nvs = default_nvs.clone();
+    server.handle_get("/config", move |req, resp| {
+        if !authorized(&req, &get_auth) {
+            resp.status(401)
+                .header("WWW-Authenticate", REALM)
+                .send_str("Unauthorized")?;
+            return Ok(());
+        }
+        let current = config::load(get_nvs.clone())?;
+        if query_flag(&req.query_string(), "json") {
+            let json = serde_json::to_string(&config_json(&current)?)?;
+            resp.header("Content-Type", "application/json")
+                .send_str(&json)?;
+        } else {
+            resp.header("Content-Type", "text/html")
+                .send_str(&config_form(&current))?;
+        }
+        Ok(())
+    })?;
+
+    let post_auth = auth.to_string();
+    server.handle_post("/config", move |mut req, resp| {
+        if !authorized(&req, &post_auth) {
+            resp.status(401)
+                .header("WWW-Authenticate", REALM)
+                .send_str("Unauthorized")?;
+            return Ok(());
+        }
+        let json = req
+            .header("Content-Type")
+            .map_or(false, |t| t.starts_with("application/json"));
+        let query = req.query_string();
+        let body = read_body(&mut req)?;
+        let mut updated = config::load(default_nvs.clone())?;
+        let applied = if json {
+            apply_json(&mut updated, &body)
+        } else {
+            apply_form(&mut updated, &String::from_utf8_lossy(&body));
+            Ok(())
+        };
+        if let Err(e) = applied.and_then(|_| updated.validate()) {
+            log::info!("Config rejected {:?}", e);
+            resp.status(400)
+                .send_str(&format!("Config rejected: {}", e))?;
+            return Ok(());
+        }
+        config::store(default_nvs.clone(), &updated)?;
+        let reboot = query_flag(&query, "reboot")
+            || (!json && query_flag(&String::from_utf8_lossy(&body), "reboot"));
+        if reboot {
+            resp.send_str("Saved, restarting")?;
+            schedule_restart(announcer.clone());
+        } else {
+            resp.send_str("Saved, applies after the next restart")?;
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+// true for ?name=1 or ?name=true
+fn query_flag(query: &str, name: &str) -> bool {
+    matches!(
+        query_value(query, name).as_deref(),
+        Some("1") | Some("true")
+    )
+}
+
+fn query_value(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| url_decode(value))
+}
+
+// broker handle for the offline announcement ahead of a restart
+#[derive(Clone)]
+struct Announcer {
+    client: Arc<Mutex<MqttClientType>>,
+    mirrors: MqttMirrors,
+    topic: String,
+}
+
+impl Announcer {
+    // <topic>/sys/ota_progress, dropped rather than stalling the upload while the poll task publishes
+    fn publish_progress(&self, percent: usize) {
+        let topic = format!("{}/sys/ota_progress", self.topic);
+        match mqtt_try_publish(
+            &self.client,
+            &topic,
+            percent.to_string().as_bytes(),
+            PublishOptions::default(),
+        ) {
+            Ok(true) => (),
+            Ok(false) => log::info!("MQTT busy, OTA progress {}% not published", percent),
+            Err(e) => log::info!("OTA progress publish failed {:?}", e),
+        }
+    }
+    // retained, a fleet script may only subscribe after the upload
+    fn publish_result(&self, result: &anyhow::Result<usize>) {
+        let topic = format!("{}/sys/ota_result", self.topic);
+        let payload = match result {
+            Ok(bytes) => format!("ok {} bytes", bytes),
+            Err(e) => format!("failed: {}", e),
+        };
+        if let Err(e) = mqtt_publish_with(
+            self.client.clone(),
+            &topic,
+            payload.as_bytes(),
+            PublishOptions {
+                retain: true,
+                ..PublishOptions::at_least_once()
+            },
+        ) {
+            log::info!("OTA result publish failed {:?}", e);
+        }
+    }
+}
+
+impl From<&AppState> for Announcer {
+    fn from(state: &AppState) -> Self {
+        Self {
+            client: state.mqttclient.clone(),
+            mirrors: state.mqtt_mirrors.clone(),
+            topic: state.topic_prefix.clone(),
+        }
+    }
+}
+
+// response is sent before this, the delay lets it flush
+fn schedule_restart(announcer: Option<Announcer>) {
+    std::thread::spawn(move || {
+        if let Some(announcer) = announcer {
+            if let Err(e) = announce_offline(announcer.client, &announcer.topic) {
+                log::info!("Offline announcement failed {:?}", e);
+            }
+            announcer.mirrors.announce_offline(&announcer.topic);
+        }
+        // also lets the HTTP reply and the offline publish go out
+        std::thread::sleep(RESTART_DELAY);
+        unsafe { esp_idf_sys::esp_restart() };
+    });
+}
+
+// decompressed image on its way into the OTA slot, hashed and size checked per write
+struct OtaSink<'a, U: Write> {
+    update: &'a mut U,
+    hasher: Sha256,
+    total: usize,
+    capacity: usize,
+}
+
+impl<U: Write> std::io::Write for OtaSink<'_, U> {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        let io_error = |msg: String| std::io::Error::new(std::io::ErrorKind::Other, msg);
+        if self.total + bytes.len() > self.capacity {
+            return Err(io_error(format!(
+                "image larger than the {} byte OTA partition",
+                self.capacity
+            )));
+        }
+        self.hasher.update(bytes);
+        self.update
+            .write_all(bytes)
+            .map_err(|e| io_error(format!("OTA write failed {:?}", e)))?;
+        self.total += bytes.len();
+        Ok(bytes.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// bytes the slot the update goes to can hold
+fn ota_partition_size() -> anyhow::Result<usize> {
+    let partition = unsafe { esp_idf_sys::esp_ota_get_next_update_partition(std::ptr::null()) };
+    if partition.is_null() {
+        return Err(anyhow!("no OTA partition to update"));
+    }
+    Ok(unsafe { (*partition).size } as usize)
+}
+
+// returns the image size, the update is aborted on any error or digest mismatch
+fn ota_processing(
+    req: &mut EspHttpRequest,
+    announcer: &Announcer,
+    gzip: bool,
+) -> anyhow::Result<usize> {
+    let expected = match req.header(SHA256_HEADER) {
+        Some(header) => header.to_string().trim().to_ascii_lowercase(),
+        None => return Err(anyhow!("missing {} header", SHA256_HEADER)),
+    };
+    // no header and no ?gzip=1 keeps plain uploads working
+    let encoding = match req.header("Content-Encoding") {
+        Some(header) => Encoding::parse(&header)?,
+        None if gzip => Encoding::Gzip,
+        None => Encoding::Identity,
+    };
+    let capacity = ota_partition_size()?;
+    // form uploads wrap the image, raw --data-binary bodies don't
+    let mut multipart = req
+        .header("Content-Type")
+        .and_then(|content_type| MultipartFile::from_content_type(&content_type));
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    // multipart framing makes the body a little larger than the image, fine for progress
+    let body_len = req
+        .header("Content-Length")
+        .and_then(|len| len.trim().parse::<usize>().ok())
+        .filter(|len| *len > 0);
+
+    let streamed = (|| -> anyhow::Result<(usize, Sha256)> {
+        let mut reader = req.reader();
+        let mut buf = vec![0u8; OTA_CHUNK];
+        let mut image = Vec::with_capacity(OTA_CHUNK);
+        // multipart framing comes off before decompression
+        let mut inflater = Inflater::new(
+            encoding,
+            OtaSink {
+                update: &mut update,
+                hasher: Sha256::new(),
+                total: 0,
+                capacity,
+            },
+        );
+        let mut received = 0;
+        let mut reported = 0;
+        loop {
+            let n = reader.read(&mut buf)?;
+            received += n;
+            if let Some(len) = body_len {
+                let percent = (received * 100 / len).min(100);
+                if percent >= reported + OTA_PROGRESS_STEP {
+                    announcer.publish_progress(percent);
+                    reported = percent;
+                }
+            }
+            if n == 0 {
+                if let Some(multipart) = &multipart {
+                    multipart.finish()?;
+                }
+                let sink = inflater.finish()?;
+                return Ok((sink.total, sink.hasher));
+            }
+            let bytes = match &mut multipart {
+                Some(multipart) => {
+                    image.clear();
+                    multipart.feed(&buf[..n], &mut image)?;
+                    &image[..]
+                }
+                None => &buf[..n],
+            };
+            inflater.feed(bytes)?;
+        }
+    })();
+    let (total, hasher) = match streamed {
+        Ok(streamed) => streamed,
+        Err(e) => {
+            update.abort()?;
+            return Err(e);
+        }
+    };
+
+    let digest: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if digest != expected {
+        update.abort()?;
+        return Err(anyhow!(
+            "SHA-256 mismatch after {} bytes, expected {} got {}",
+            total,
+            expected,
+            digest
+        ));
+    }
+    // sets the boot partition
+    update.complete()?;
+    Ok(total)
+}
+
+fn config_form(current: &DeviceConfig) -> String {
+    // secrets are never echoed back, blank keeps the stored value
+    format!(
+        "<html><body><h1>abb_to_mqtt {}</h1><form method=\"post\" action=\"/config\">\
+         WiFi SSID <input name=\"ssid\" value=\"{}\"><br>\
+         WiFi password <input name=\"pass\" type=\"password\"><br>\
+         MQTT URL <input name=\"mqtt_addr\" value=\"{}\"><br>\
+         Mirror MQTT URLs (comma separated) <input name=\"mqtt_mirror_addrs\" value=\"{}\"><br>\
+         Device name (MQTT topic level) <input name=\"device_name\" value=\"{}\"><br>\
+         Inverter <select name=\"inverter\">{}</select><br>\
+         Inverter IDs (comma separated) <input name=\"inverter_ids\" value=\"{}\"><br>\
+         Poll interval (s) <input name=\"poll_interval_secs\" value=\"{}\"><br>\
+         NTP servers (comma separated) <input name=\"ntp_servers\" value=\"{}\"><br>\
+         Timezone (POSIX TZ) <input name=\"timezone\" value=\"{}\"><br>\
+         Aurora request gap (ms) <input name=\"request_gap_ms\" value=\"{}\"><br>\
+         Aurora measurements (comma separated) <input name=\"measurements\" value=\"{}\"><br>\
+         MQTT username <input name=\"mqtt_username\" value=\"{}\"><br>\
+         MQTT password <input name=\"mqtt_password\" type=\"password\"><br>\
+         MQTT CA certificate (PEM, mqtts://)<br><textarea name=\"mqtt_ca_cert\" rows=\"6\" cols=\"64\">{}</textarea><br>\
+         MQTT client certificate (PEM)<br><textarea name=\"mqtt_client_cert\" rows=\"6\" cols=\"64\">{}</textarea><br>\
+         MQTT client key (PEM)<br><textarea name=\"mqtt_client_key\" rows=\"6\" cols=\"64\"></textarea><br>\
+         <input type=\"checkbox\" name=\"reboot\" value=\"1\" checked> Restart now<br>\
+         <input type=\"submit\" value=\"Save\"></form></body></html>",
+        crate::VERSION,
+        html_escape(&current.ssid),
+        html_escape(&current.mqtt_addr),
+        html_escape(&current.mqtt_mirror_addrs.join(",")),
+        html_escape(current.device_name.as_deref().unwrap_or_default()),
+        inverter_options(current.inverter_kind()),
+        current
+            .inverter_ids()
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+        current.poll_interval().as_secs(),
+        html_escape(&current.ntp_servers().join(",")),
+        html_escape(current.timezone()),
+        current.request_gap().as_millis(),
+        current
+            .measurements()
+            .iter()
+            .map(|meta| meta.field)
+            .collect::<Vec<_>>()
+            .join(","),
+        html_escape(&current.mqtt_username),
+        html_escape(current.mqtt_ca_cert.as_deref().unwrap_or_default()),
+        html_escape(current.mqtt_client_cert.as_deref().unwrap_or_default()),
+    )
+}
+
+// keys as stored, top level fields replace the current values
+fn apply_json(config: &mut DeviceConfig, body: &[u8]) -> anyhow::Result<()> {
+    let patch: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(body)?;
+    let mut merged = serde_json::to_value(&*config)?;
+    for (key, value) in patch {
+        merged[key.as_str()] = value;
+    }
+    *config = serde_json::from_value(merged)?;
+    Ok(())
+}
+
+// same fields as stored, secrets left out like on the form
+fn config_json(config: &DeviceConfig) -> anyhow::Result<serde_json::Value> {
+    let mut json = serde_json::to_value(config)?;
+    if let Some(fields) = json.as_object_mut() {
+        for secret in ["pass", "mqtt_password", "mqtt_client_key", "http_password"] {
+            fields.remove(secret);
+        }
+        fields.remove("extra_networks");
+        fields.insert(
+            "extra_network_ssids".to_string(),
+            config
+                .extra_networks
+                .iter()
+                .map(|(ssid, _)| ssid.clone())
+                .collect::<Vec<_>>()
+                .into(),
+        );
+    }
+    Ok(json)
+}
+
+// backends left out of the build aren't offered
+fn inverter_options(selected: InverterKind) -> String {
+    [InverterKind::Aurora, InverterKind::Solax]
+        .iter()
+        .filter(|kind| kind.built_in())
+        .map(|kind| {
+            format!(
+                "<option value=\"{}\"{}>{}</option>",
+                kind.as_str(),
+                if *kind == selected { " selected" } else { "" },
+                kind.as_str()
+            )
+        })
+        .collect()
+}
+
+fn apply_form(config: &mut DeviceConfig, body: &str) {
+    for (key, value) in body.split('&').filter_map(|pair| pair.split_once('=')) {
+        let value = url_decode(value);
+        if value.is_empty() {
+            continue;
+        }
+        match key {
+            "ssid" => config.ssid = value,
+            "pass" => config.pass = value,
+            "mqtt_addr" => config.mqtt_addr = value,
+            "mqtt_mirror_addrs" => {
+                config.mqtt_mirror_addrs = value
+                    .split(',')
+                    .map(|url| url.trim().to_string())
+                    .filter(|url| !url.is_empty())
+                    .collect()
+            }
+            "device_name" => config.device_name = Some(value),
+            "inverter_ids" => match value
+                .split(',')
+                .map(|id| id.trim().parse())
+                .collect::<std::result::Result<Vec<u8>, _>>()
+            {
+                Ok(ids) => config.inverter_ids = Some(ids),
+                Err(_) => log::info!("Ignoring inverter IDs {}", value),
+            },
+            "inverter" => match InverterKind::parse(&value) {
+                Some(kind) if kind.built_in() => config.inverter = Some(kind),
+                _ => log::info!("Ignoring inverter {}", value),
+            },
+            "poll_interval_secs" => match value.parse() {
+                Ok(secs) if crate::config::valid_poll_interval(secs) => {
+                    config.poll_interval_secs = Some(secs)
+                }
+                _ => log::info!("Ignoring poll interval {}", value),
+            },
+            "ntp_servers" => {
+                config.ntp_servers = Some(
+                    value
+                        .split(',')
+                        .map(|server| server.trim().to_string())
+                        .filter(|server| !server.is_empty())
+                        .collect(),
+                )
+            }
+            "timezone" => config.timezone = Some(value.trim().to_string()),
+            "request_gap_ms" => match value.parse() {
+                Ok(ms) if crate::config::valid_request_gap(ms) => config.request_gap_ms = Some(ms),
+                _ => log::info!("Ignoring request gap {}", value),
+            },
+            // checked by DeviceConfig::validate before it's stored
+            "measurements" => {
+                config.measurements = Some(
+                    value
+                        .split(',')
+                        .map(|name| name.trim().to_string())
+                        .filter(|name| !name.is_empty())
+                        .collect(),
+                )
+            }
+            "mqtt_username" => config.mqtt_username = value,
+            "mqtt_password" => config.mqtt_password = value,
+            // textareas post CRLF line endings
+            "mqtt_ca_cert" => config.mqtt_ca_cert = Some(value.replace("\r\n", "\n")),
+            "mqtt_client_cert" => config.mqtt_client_cert = Some(value.replace("\r\n", "\n")),
+            "mqtt_client_key" => config.mqtt_client_key = Some(value.replace("\r\n", "\n")),
+            _ => (),
+        }
+    }
+}
+
+// application/x-www-form-urlencoded value
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn read_body(req: &mut EspHttpRequest) -> anyhow::Result<Vec<u8>> {
+    let mut reader = req.reader();
+    let mut body = vec![];
+    let mut buf = [0u8; 128];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => break,
+            n => body.extend_from_slice(&buf[..n]),
+        }
+        if body.len() > MAX_FORM_LEN {
+            return Err(anyhow!("Request body over {} bytes", MAX_FORM_LEN));
+        }
+    }
+    Ok(body)
+}
+
+fn basic_auth(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        base64::encode(format!("{}:{}", username, password))
+    )
+}
+
+fn authorized(req: &EspHttpRequest, expected: &str) -> bool {
+    match req.header("Authorization") {
+        Some(header) => same_secret(header.as_bytes(), expected.as_bytes()),
+        None => false,
+    }
+}
+
+// no early exit, so response timing doesn't leak how much of the header matched
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Prometheus text exposition, fields and units from inverter::DSP_FIELDS like HA discovery
+fn metrics(state: &AppState) -> anyhow::Result<String> {
+    let mut inverters: Vec<(u8, bool, serde_json::Value, serde_json::Value)> = vec![];
+    if let Ok(locked) = state.inverters.lock() {
+        for inverter in locked.iter() {
+            inverters.push((
+                inverter.id(),
+                inverter.is_online(),
+                serde_json::to_value(&inverter.data)?,
+                serde_json::to_value(&inverter.energy)?,
+            ));
+        }
+    }
+
+    let mut out = String::new();
+    writeln!(out, "# TYPE abb_online gauge")?;
+    for (id, online, _, _) in &inverters {
+        writeln!(out, "abb_online{{inverter=\"{}\"}} {}", id, *online as u8)?;
+    }
+    for meta in DSP_FIELDS.iter() {
+        let (suffix, scale) = prometheus_unit(meta.unit);
+        let name = format!("abb_{}_{}", meta.field, suffix);
+        writeln!(out, "# TYPE {} gauge", name)?;
+        for (id, _, data, _) in &inverters {
+            if let Some(value) = data[meta.field].as_f64() {
+                writeln!(out, "{}{{inverter=\"{}\"}} {}", name, id, value * scale)?;
+            }
+        }
+    }
+    for field in HA_ENERGY_SENSORS.iter() {
+        // kWh from the inverter, day/week/... reset which counters tolerate
+        let name = format!("abb_energy_{}_watt_hours_total", field);
+        writeln!(out, "# TYPE {} counter", name)?;
+        for (id, _, _, energy) in &inverters {
+            if let Some(value) = energy[*field].as_f64() {
+                writeln!(out, "{}{{inverter=\"{}\"}} {}", name, id, value * 1000.0)?;
+            }
+        }
+    }
+    Ok(out)
+}
+
+// base unit suffix and scale from the HA unit
+fn prometheus_unit(unit: &str) -> (&'static str, f64) {
+    match unit {
+        "kW" => ("watts", 1000.0),
+        "V" => ("volts", 1.0),
+        "A" => ("amperes", 1.0),
+        "Hz" => ("hertz", 1.0),
+        "°C" => ("celsius", 1.0),
+        _ => ("value", 1.0),
+    }
+}
+
+fn status_report(state: &AppState) -> anyhow::Result<StatusReport> {
+    let inverters = match state.inverters.lock() {
+        Ok(inverters) => inverters
+            .iter()
+            .map(|inverter| InverterStatus {
+                id: inverter.id(),
+                present: inverter.is_present(),
+                online: inverter.is_online(),
+                last_seen_secs: inverter.seconds_since_last_message(),
+                identity: inverter.identity.clone(),
+                alarms: inverter.alarms.clone(),
+                operating_state: inverter.operating_state,
+                data: inverter.data,
+                energy: inverter.energy,
+                energy_function: inverter.energy_function(),
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+    #[cfg(feature = "solax")]
+    let solax = match &state.solax {
+        Some(solax) => match solax.lock() {
+            Ok(solax) => Some(serde_json::to_value(&solax.data)?),
+            Err(_) => None,
+        },
+        None => None,
+    };
+    #[cfg(not(feature = "solax"))]
+    let solax = None;
+    Ok(StatusReport {
+        firmware_version: crate::VERSION,
+        uptime_secs: state.boot_time.elapsed().as_secs(),
+        wifi_connected: state.connectivity.wifi_up(),
+        mqtt_connected: state.connectivity.mqtt_up(),
+        inverters,
+        solax,
+    })
+}
+
+fn version_info() -> VersionInfo {
+    let profile = if cfg!(debug_assertions) {
+        "debug"
+    } else {
+        "release"
+    };
+    let features: Vec<&str> = [
+        ("aurora", cfg!(feature = "aurora")),
+        ("solax", cfg!(feature = "solax")),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| *name)
+    .collect();
+    let idf_version = unsafe { std::ffi::CStr::from_ptr(esp_idf_sys::esp_get_idf_version()) }
+        .to_string_lossy()
+        .to_string();
+    let mut chip_info = esp_idf_sys::esp_chip_info_t::default();
+    unsafe { esp_idf_sys::esp_chip_info(&mut chip_info) };
+    #[allow(non_upper_case_globals)]
+    let model = match chip_info.model {
+        esp_idf_sys::esp_chip_model_t_CHIP_ESP32 => "ESP32",
+        esp_idf_sys::esp_chip_model_t_CHIP_ESP32S2 => "ESP32-S2",
+        esp_idf_sys::esp_chip_model_t_CHIP_ESP32S3 => "ESP32-S3",
+        esp_idf_sys::esp_chip_model_t_CHIP_ESP32C3 => "ESP32-C3",
+        _ => "unknown",
+    };
+    VersionInfo {
+        version: crate::VERSION,
+        build: format!("{} {}", profile, features.join(",")),
+        idf_version,
+        chip: format!("{} rev {}", model, chip_info.revision),
+    }
+}
+
+fn landing_page(version: &VersionInfo) -> String {
+    format!(
+        "<html><body><h1>abb_to_mqtt {}</h1>\
+         <p>{} on {}, ESP-IDF {}</p><ul>\
+         <li><a href=\"/status\">Status</a></li>\
+         <li><a href=\"/diag\">Diagnostics</a></li>\
+         <li><a href=\"/history\">History</a></li>\
+         <li><a href=\"/metrics\">Metrics</a></li>\
+         <li><a href=\"/logs\">Logs</a></li>\
+         <li><a href=\"/frames\">RS485 frames</a></li>\
+         <li><a href=\"/config\">Configuration</a></li>\
+         <li><a href=\"/ota\">Firmware update</a></li>\
+         </ul></body></html>",
+        version.version,
+        html_escape(&version.build),
+        html_escape(&version.chip),
+        html_escape(&version.idf_version),
+    )
+}
+
+// a browser form can't send the digest header, so the upload itself stays a curl call
+fn ota_page(version: &VersionInfo) -> String {
+    format!(
+        "<html><body><h1>abb_to_mqtt {}</h1>\
+         <p>Running {} ({}), reload after the restart to confirm the new version</p>\
+         <pre>curl -u user:pass -H \"{}: $(sha256sum image.bin | cut -d' ' -f1)\" \\\n\
+         \x20    --data-binary @image.bin http://&lt;device&gt;/ota?reboot=1</pre>\
+         </body></html>",
+        version.version,
+        version.version,
+        html_escape(&version.build),
+        SHA256_HEADER,
+    )
+}
+
+fn diagnostics(state: &AppState) -> Diagnostics {
+    let inverters = match state.inverters.lock() {
+        Ok(inverters) => inverters
+            .iter()
+            .map(|inverter| InverterDiag {
+                id: inverter.id(),
+                online: inverter.is_online(),
+                poll_errors: inverter.poll_errors(),
+                last_seen_secs: inverter.seconds_since_last_message(),
+                address_collision: inverter.address_collision(),
+                turnaround_ms: inverter.turnaround.avg_ms(),
+                last_pass_ms: inverter.last_pass_ms,
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+    let poll = match state.stats.lock() {
+        Ok(stats) => *stats,
+        Err(_) => PollStats::default(),
+    };
+    Diagnostics {
+        firmware_version: crate::VERSION,
+        uptime_secs: state.boot_time.elapsed().as_secs(),
+        free_heap: unsafe { esp_idf_sys::esp_get_free_heap_size() },
+        wifi_connected: state.connectivity.wifi_up(),
+        wifi_rssi: state.connectivity.rssi(),
+        wifi_ip: state.connectivity.wifi_ip(),
+        mqtt_connected: state.connectivity.mqtt_up(),
+        sntp_synced: state.clock.is_synced(),
+        poll,
+        inverters,
+    }
+}
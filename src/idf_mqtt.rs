@@ -1,66 +1,612 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use embedded_svc::mqtt::client::utils::ConnState;
-use embedded_svc::mqtt::client::{Client, Connection, MessageImpl, Publish, QoS};
+use embedded_svc::mqtt::client::{Client, Connection, Event, Message, MessageImpl, Publish, QoS};
 use esp_idf_svc::mqtt::client::*;
+use esp_idf_svc::tls::X509;
 use log::*;
 
+use crate::inverter::DspFieldMeta;
+#[cfg(feature = "solax")]
+use crate::proto::solax::{ErrorCode, RunMode};
+
 pub(crate) type MqttClientType = EspMqttClient<ConnState<MessageImpl, esp_idf_sys::EspError>>;
 
-pub fn mqtt_client(
-    url: String,
-    subscription: Vec<String>,
-    client_id: Option<&str>,
-    topic: String,
-    conf: MqttClientConfiguration,
-) -> anyhow::Result<MqttClientType> {
+// Owned copy of everything needed to (re)build the client
+#[derive(Debug, Clone)]
+pub struct MqttSettings {
+    pub url: String,
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    // PEM, only used for mqtts:// and wss:// urls
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub keepalive: Duration,
+    pub clean_session: bool,
+    pub reconnect_timeout: Duration,
+    pub subscriptions: Vec<String>,
+    pub topic: String,
+}
+
+// Broker connection state shared with the connection thread
+#[derive(Debug, Clone, Default)]
+pub struct MqttLink {
+    connected: Arc<AtomicBool>,
+    // set on each (re)connect, subscriptions and availability are sent from the poll task
+    needs_setup: Arc<AtomicBool>,
+    // payloads received on <topic>/cmd, kept across client rebuilds
+    commands: Arc<Mutex<Option<Sender<MqttCommand>>>>,
+}
+
+// Commands accepted on <topic>/cmd
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MqttCommand {
+    // poll the inverters now instead of waiting for the timer
+    Poll,
+    Restart,
+    // RS485 wiring check, results on <topic>/sys/selftest
+    SelfTest,
+    // <topic>/cmd/interval, poll period in seconds
+    Interval(u64),
+    // <topic>/cmd/raw_dsp "[id] function command", reads only, replies on <topic>/debug/raw
+    RawDsp {
+        id: Option<u8>,
+        function: u8,
+        command: u8,
+    },
+    // <topic>/cmd/set_time, seconds since 2000-01-01 or "now" for the SNTP local time
+    SetTime(Option<u32>),
+    // <topic>/cmd/reset_energy "id serial", the inverter serial guards against stray resets.
+    // gateway side, the inverter's own counters can't be reset over RS485
+    ResetEnergy {
+        id: u8,
+        confirm: String,
+    },
+}
+
+impl MqttCommand {
+    // subtopic is the part after <topic>/cmd
+    pub fn parse(subtopic: &str, payload: &[u8]) -> Option<Self> {
+        let payload = String::from_utf8_lossy(payload);
+        match (subtopic, payload.trim()) {
+            ("", "poll") => Some(Self::Poll),
+            ("", "restart") => Some(Self::Restart),
+            ("", "selftest") => Some(Self::SelfTest),
+            ("/interval", secs) => secs.parse().ok().map(Self::Interval),
+            ("/raw_dsp", args) => Self::parse_raw_dsp(args),
+            ("/set_time", "now") => Some(Self::SetTime(None)),
+            ("/set_time", secs) => secs.parse().ok().map(|secs| Self::SetTime(Some(secs))),
+            ("/reset_energy", args) => match args.split_whitespace().collect::<Vec<_>>()[..] {
+                [id, confirm] => id.parse().ok().map(|id| Self::ResetEnergy {
+                    id,
+                    confirm: confirm.to_string(),
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+    fn parse_raw_dsp(args: &str) -> Option<Self> {
+        let codes = args
+            .split_whitespace()
+            .map(|code| code.parse::<u8>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        match codes[..] {
+            [function, command] => Some(Self::RawDsp {
+                id: None,
+                function,
+                command,
+            }),
+            [id, function, command] => Some(Self::RawDsp {
+                id: Some(id),
+                function,
+                command,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl MqttLink {
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+    pub fn take_needs_setup(&self) -> bool {
+        self.needs_setup.swap(false, Ordering::Relaxed)
+    }
+    pub fn request_setup(&self) {
+        self.needs_setup.store(true, Ordering::Relaxed);
+    }
+    // replaces any earlier receiver
+    pub fn command_channel(&self) -> Receiver<MqttCommand> {
+        let (tx, rx) = channel();
+        if let Ok(mut commands) = self.commands.lock() {
+            *commands = Some(tx);
+        }
+        rx
+    }
+    fn send_command(&self, command: MqttCommand) {
+        if let Ok(commands) = self.commands.lock() {
+            if let Some(tx) = commands.as_ref() {
+                if let Err(e) = tx.send(command) {
+                    info!("MQTT command {:?} dropped, no listener", e.0);
+                }
+            }
+        }
+    }
+}
+
+// Publish-only broker next to the primary, commands are only taken from the primary
+pub struct MqttMirror {
+    settings: MqttSettings,
+    link: MqttLink,
+    client: Arc<Mutex<MqttClientType>>,
+}
+
+// every DeviceConfig::mqtt_mirror_addrs broker, esp-mqtt keeps reconnecting each on its own
+#[derive(Clone, Default)]
+pub struct MqttMirrors(Arc<Vec<MqttMirror>>);
+
+impl MqttMirrors {
+    // a mirror that fails to start is left out rather than holding up the boot
+    pub fn start(urls: &[String], primary: &MqttSettings) -> Self {
+        let mirrors = urls
+            .iter()
+            .filter_map(|url| {
+                let settings = MqttSettings {
+                    url: url.clone(),
+                    subscriptions: vec![],
+                    ..primary.clone()
+                };
+                let link = MqttLink::default();
+                match mqtt_client(&settings, link.clone()) {
+                    Ok(client) => Some(MqttMirror {
+                        settings,
+                        link,
+                        client: Arc::new(Mutex::new(client)),
+                    }),
+                    Err(e) => {
+                        warn!("MQTT mirror {} not started {:?}", url, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        Self(Arc::new(mirrors))
+    }
+    // availability after each mirror (re)connects
+    pub fn maintain(&self) {
+        for mirror in self.0.iter() {
+            if mirror.link.take_needs_setup() {
+                if let Err(e) = on_connected(mirror.client.clone(), &mirror.settings) {
+                    info!("MQTT mirror {} setup failed {:?}", mirror.settings.url, e);
+                    mirror.link.request_setup();
+                }
+            }
+        }
+    }
+    // one result per mirror by URL, disconnected mirrors are skipped with an Err
+    pub fn publish(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        options: PublishOptions,
+    ) -> Vec<(&str, anyhow::Result<()>)> {
+        self.0
+            .iter()
+            .map(|mirror| {
+                let result = if !mirror.link.is_connected() {
+                    Err(anyhow::anyhow!("not connected"))
+                } else {
+                    match mirror.client.lock() {
+                        Ok(mut client) => client
+                            .publish(topic, options.qos, options.retain, payload)
+                            .map(|_| ())
+                            .map_err(anyhow::Error::from),
+                        Err(_) => Err(anyhow::anyhow!("client lock failed")),
+                    }
+                };
+                (mirror.settings.url.as_str(), result)
+            })
+            .collect()
+    }
+    pub fn announce_offline(&self, topic: &str) {
+        let results = self.publish(
+            &availability_topic(topic),
+            b"offline",
+            PublishOptions {
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            },
+        );
+        for (url, result) in results {
+            if let Err(e) = result {
+                info!("MQTT mirror {} offline announcement failed {:?}", url, e);
+            }
+        }
+    }
+}
+
+// retained online/offline, offline set by the broker via the last will
+pub fn availability_topic(topic: &str) -> String {
+    format!("{}/status", topic)
+}
+
+pub fn command_topic(topic: &str) -> String {
+    format!("{}/cmd", topic)
+}
+
+pub fn mqtt_client(settings: &MqttSettings, link: MqttLink) -> anyhow::Result<MqttClientType> {
     info!("About to start MQTT client");
 
-    let (mut client, mut connection) = EspMqttClient::new_with_conn(url, &conf)?;
+    let availability = availability_topic(&settings.topic);
+    let tls = is_tls(&settings.url);
+    // X509 wants the terminating NUL inside the slice
+    let pem = |value: &Option<String>| value.as_deref().filter(|_| tls).map(nul_terminated);
+    let (ca_cert, client_cert, client_key) = (
+        pem(&settings.ca_cert),
+        pem(&settings.client_cert),
+        pem(&settings.client_key),
+    );
+    if tls && ca_cert.is_none() {
+        // the certificate bundle is disabled in sdkconfig, so the handshake will fail
+        warn!("{} needs mqtt_ca_cert in the device config", settings.url);
+    }
+    let conf = MqttClientConfiguration {
+        client_id: Some(&settings.client_id),
+        username: Some(&settings.username),
+        password: Some(&settings.password),
+        lwt: Some(LwtConfiguration {
+            topic: &availability,
+            payload: b"offline",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }),
+        server_certificate: ca_cert
+            .as_deref()
+            .map(|pem| X509::pem_until_nul(pem.as_bytes())),
+        client_certificate: client_cert
+            .as_deref()
+            .map(|pem| X509::pem_until_nul(pem.as_bytes())),
+        private_key: client_key
+            .as_deref()
+            .map(|pem| X509::pem_until_nul(pem.as_bytes())),
+        keep_alive_interval: Some(settings.keepalive),
+        disable_clean_session: !settings.clean_session,
+        reconnect_timeout: Some(settings.reconnect_timeout),
+        ..Default::default()
+    };
+    let (client, mut connection) = EspMqttClient::new_with_conn(settings.url.clone(), &conf)?;
 
     info!("MQTT client started");
 
+    let commands = command_topic(&settings.topic);
     std::thread::spawn(move || {
         info!("MQTT Listening for messages");
 
         while let Some(msg) = connection.next() {
             match msg {
                 Err(e) => info!("MQTT Message ERROR: {}", e),
+                Ok(Event::Connected(_)) => {
+                    info!("MQTT connected");
+                    link.connected.store(true, Ordering::Relaxed);
+                    link.request_setup();
+                }
+                Ok(Event::Disconnected) => {
+                    info!("MQTT disconnected");
+                    link.connected.store(false, Ordering::Relaxed);
+                }
+                Ok(Event::Received(msg))
+                    if msg
+                        .topic()
+                        .map_or(false, |topic| topic.starts_with(commands.as_str())) =>
+                {
+                    let topic = msg.topic().unwrap_or_default().to_string();
+                    match MqttCommand::parse(&topic[commands.len()..], &msg.data()) {
+                        Some(command) => {
+                            info!("MQTT command {:?}", command);
+                            link.send_command(command);
+                        }
+                        None => info!(
+                            "Unknown MQTT command {} {}",
+                            topic,
+                            String::from_utf8_lossy(&msg.data())
+                        ),
+                    }
+                }
                 Ok(msg) => info!("MQTT Message: {:?}", msg), // handle incomming messages
             }
         }
 
         info!("MQTT connection loop exit");
     });
-    for sub in subscription {
-        client.subscribe(&sub, QoS::AtMostOnce)?;
-        info!("Subscribed to all topics {}", topic);
+    Ok(client)
+}
+
+// TLS transport, needs CONFIG_MQTT_TRANSPORT_SSL (on in sdkconfig.defaults)
+fn is_tls(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    url.starts_with("mqtts://") || url.starts_with("wss://")
+}
+
+fn nul_terminated(pem: &str) -> String {
+    format!("{}\0", pem.trim_end_matches('\0'))
+}
+
+// Per session setup, run after every (re)connect
+pub fn on_connected(
+    client_m: Arc<Mutex<MqttClientType>>,
+    settings: &MqttSettings,
+) -> anyhow::Result<()> {
+    if let Ok(mut client) = client_m.lock() {
+        for sub in &settings.subscriptions {
+            client.subscribe(sub, QoS::AtMostOnce)?;
+            info!("Subscribed to all topics {}", settings.topic);
+        }
+
+        client.publish(
+            &settings.client_id,
+            QoS::AtMostOnce,
+            false,
+            "Alive".as_bytes(),
+        )?;
+
+        info!("Published an alive message to topic {}", settings.topic);
+
+        client.publish(
+            &availability_topic(&settings.topic),
+            QoS::AtLeastOnce,
+            true,
+            "online".as_bytes(),
+        )?;
+    } else {
+        info!("MQTT Mutex lock fail")
     }
+    Ok(())
+}
 
-    client.publish(
-        client_id.unwrap(),
-        QoS::AtMostOnce,
-        false,
-        "Alive".as_bytes(),
-    )?;
+// Planned restarts say so, the last will only covers crashes and lost links
+pub fn announce_offline(client_m: Arc<Mutex<MqttClientType>>, topic: &str) -> anyhow::Result<()> {
+    info!("Announcing offline before restart");
+    mqtt_publish_with(
+        client_m,
+        &availability_topic(topic),
+        b"offline",
+        PublishOptions {
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        },
+    )
+}
 
-    info!("Published an alive message to topic {}", topic);
+// Tears down the current client and swaps in a fresh connection
+pub fn reconnect(
+    client_m: Arc<Mutex<MqttClientType>>,
+    settings: &MqttSettings,
+    link: MqttLink,
+) -> anyhow::Result<()> {
+    info!("MQTT reconnecting to {}", settings.url);
+    link.connected.store(false, Ordering::Relaxed);
+    let client = mqtt_client(settings, link)?;
+    if let Ok(mut current) = client_m.lock() {
+        // old client is dropped here, closing its connection thread
+        *current = client;
+    } else {
+        info!("MQTT Mutex lock fail")
+    }
+    Ok(())
+}
 
-    Ok(client)
+#[derive(Debug, Copy, Clone)]
+pub struct PublishOptions {
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+impl Default for PublishOptions {
+    fn default() -> Self {
+        Self {
+            qos: QoS::AtMostOnce,
+            retain: false,
+        }
+    }
+}
+
+impl PublishOptions {
+    // last value survives broker-side for late subscribers
+    pub fn retained() -> Self {
+        Self {
+            retain: true,
+            ..Default::default()
+        }
+    }
+    // redelivered until the broker acks
+    pub fn at_least_once() -> Self {
+        Self {
+            qos: QoS::AtLeastOnce,
+            ..Default::default()
+        }
+    }
 }
 
 pub fn mqtt_publish(
     client_m: Arc<Mutex<MqttClientType>>,
     topic: &str,
     payload: &[u8],
+) -> anyhow::Result<()> {
+    mqtt_publish_with(client_m, topic, payload, PublishOptions::default())
+}
+
+// EnergyTotals counters, all kWh, day_delta is left out as it is not total_increasing
+pub(crate) const HA_ENERGY_SENSORS: [&str; 6] =
+    ["day", "week", "month", "year", "total", "since_reset"];
+
+// Retained Home Assistant discovery configs for one inverter's Dsp and EnergyTotals topics,
+// fields being the measured subset of DSP_FIELDS
+pub fn publish_ha_discovery(
+    client_m: Arc<Mutex<MqttClientType>>,
+    fields: &[DspFieldMeta],
+    inverter_id: u8,
+    topic_prefix: &str,
+    // gateway name, unnamed gateways keep the original ids
+    node: Option<&str>,
+    single_json: bool,
+) -> anyhow::Result<()> {
+    let (object_id, name) = match node {
+        Some(node) => (
+            format!("{}_{}", node, inverter_id),
+            format!("ABB inverter {} {}", node, inverter_id),
+        ),
+        None => (
+            inverter_id.to_string(),
+            format!("ABB inverter {}", inverter_id),
+        ),
+    };
+    let device = serde_json::json!({
+        "identifiers": [format!("abb_{}", object_id)],
+        "name": name,
+        "manufacturer": "ABB",
+    });
+    let sensors = fields
+        .iter()
+        .map(|meta| {
+            (
+                meta.field,
+                meta.device_class,
+                meta.unit,
+                "measurement",
+                "data",
+            )
+        })
+        .chain(
+            HA_ENERGY_SENSORS
+                .iter()
+                .map(|field| (*field, "energy", "kWh", "total_increasing", "energy")),
+        );
+    for (field, device_class, unit, state_class, section) in sensors {
+        // power goes out in kW on the topics, HA gets it as W through the template
+        let (unit, scale) = match unit {
+            "kW" => ("W", " | float * 1000"),
+            unit => (unit, ""),
+        };
+        let mut config = serde_json::json!({
+            "name": format!("ABB{} {}", inverter_id, field),
+            "unique_id": format!("abb_{}_{}", object_id, field),
+            "state_topic": format!("{}/{}/{}", topic_prefix, inverter_id, field),
+            "device_class": device_class,
+            "unit_of_measurement": unit,
+            "state_class": state_class,
+            "device": device,
+        });
+        if single_json {
+            // every sensor reads its field out of the one state document
+            config["state_topic"] = format!("{}/{}/state", topic_prefix, inverter_id).into();
+            config["value_template"] =
+                format!("{{{{ value_json.{}.{}{} }}}}", section, field, scale).into();
+        } else if !scale.is_empty() {
+            config["value_template"] = format!("{{{{ value{} }}}}", scale).into();
+        }
+        let topic = format!("homeassistant/sensor/{}/{}/config", object_id, field);
+        mqtt_publish_with(
+            client_m.clone(),
+            &topic,
+            config.to_string().as_bytes(),
+            PublishOptions::retained(),
+        )?;
+    }
+    Ok(())
+}
+
+// Retained Home Assistant discovery configs for the Solax state enums under <prefix>/solax,
+// payloads are the variant names
+#[cfg(feature = "solax")]
+pub fn publish_solax_ha_discovery(
+    client_m: Arc<Mutex<MqttClientType>>,
+    topic_prefix: &str,
+    node: Option<&str>,
+) -> anyhow::Result<()> {
+    let (object_id, name) = match node {
+        Some(node) => (format!("{}_solax", node), format!("Solax X1 Air {}", node)),
+        None => ("solax".to_string(), "Solax X1 Air".to_string()),
+    };
+    let device = serde_json::json!({
+        "identifiers": [object_id],
+        "name": name,
+        "manufacturer": "Solax",
+    });
+    let run_modes: Vec<String> = RunMode::ALL.iter().map(|v| format!("{:?}", v)).collect();
+    let error_codes: Vec<String> = ErrorCode::ALL.iter().map(|v| format!("{:?}", v)).collect();
+    let sensors = [
+        ("run_mode", Some(run_modes), None),
+        ("error_code", Some(error_codes), None),
+        // grid code, set at install and rarely worth a dashboard tile
+        ("safety", None, Some("diagnostic")),
+    ];
+    for (field, options, entity_category) in sensors {
+        let mut config = serde_json::json!({
+            "name": format!("Solax {}", field),
+            "unique_id": format!("{}_{}", object_id, field),
+            "state_topic": format!("{}/solax/{}", topic_prefix, field),
+            "value_template": "{{ value }}",
+            // Unregistered and Registered are still handshaking, only Online has data
+            "availability": [{
+                "topic": format!("{}/solax/status", topic_prefix),
+                "value_template": "{{ 'online' if value == 'Online' else 'offline' }}",
+            }],
+            "device": device,
+        });
+        if let Some(options) = options {
+            config["device_class"] = "enum".into();
+            config["options"] = options.into();
+        }
+        if let Some(category) = entity_category {
+            config["entity_category"] = category.into();
+        }
+        let topic = format!("homeassistant/sensor/{}/{}/config", object_id, field);
+        mqtt_publish_with(
+            client_m.clone(),
+            &topic,
+            config.to_string().as_bytes(),
+            PublishOptions::retained(),
+        )?;
+    }
+    Ok(())
+}
+
+// skips instead of waiting when another task holds the client, Ok(false) if skipped
+pub fn mqtt_try_publish(
+    client_m: &Arc<Mutex<MqttClientType>>,
+    topic: &str,
+    payload: &[u8],
+    options: PublishOptions,
+) -> anyhow::Result<bool> {
+    match client_m.try_lock() {
+        Ok(mut client) => {
+            client.publish(topic, options.qos, options.retain, payload)?;
+            Ok(true)
+        }
+        Err(_) => Ok(false),
+    }
+}
+
+pub fn mqtt_publish_with(
+    client_m: Arc<Mutex<MqttClientType>>,
+    topic: &str,
+    payload: &[u8],
+    options: PublishOptions,
 ) -> anyhow::Result<()> {
     if let Ok(mut client) = client_m.lock() {
-        client.publish(topic, QoS::AtMostOnce, false, payload)?;
+        client.publish(topic, options.qos, options.retain, payload)?;
         log::info!(
             "Published {} {:?} {:?} {}",
             topic,
-            QoS::AtMostOnce,
-            false,
+            options.qos,
+            options.retain,
             String::from_utf8_lossy(payload)
         )
     } else {
@@ -0,0 +1,60 @@
+use anyhow::anyhow;
+use flate2::write::{GzDecoder, ZlibDecoder};
+use std::io::Write;
+
+// Content-Encoding of an upload body
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    // zlib wrapped, as HTTP uses the name
+    Deflate,
+}
+
+impl Encoding {
+    // unknown encodings are refused rather than written out as is
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Ok(Encoding::Identity),
+            "gzip" | "x-gzip" => Ok(Encoding::Gzip),
+            "deflate" => Ok(Encoding::Deflate),
+            other => Err(anyhow!("unsupported content encoding {}", other)),
+        }
+    }
+}
+
+// Streams body chunks into out, decompressing whatever the chunk alignment.
+// The gzip header is skipped by the decoder, its CRC and length trailer are checked in finish.
+pub enum Inflater<W: Write> {
+    Identity(W),
+    Gzip(GzDecoder<W>),
+    Deflate(ZlibDecoder<W>),
+}
+
+impl<W: Write> Inflater<W> {
+    pub fn new(encoding: Encoding, out: W) -> Self {
+        match encoding {
+            Encoding::Identity => Inflater::Identity(out),
+            Encoding::Gzip => Inflater::Gzip(GzDecoder::new(out)),
+            Encoding::Deflate => Inflater::Deflate(ZlibDecoder::new(out)),
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Inflater::Identity(out) => out.write_all(chunk)?,
+            Inflater::Gzip(decoder) => decoder.write_all(chunk)?,
+            Inflater::Deflate(decoder) => decoder.write_all(chunk)?,
+        }
+        Ok(())
+    }
+
+    // errors on a truncated stream or a trailer mismatch
+    pub fn finish(self) -> anyhow::Result<W> {
+        Ok(match self {
+            Inflater::Identity(out) => out,
+            Inflater::Gzip(decoder) => decoder.finish()?,
+            Inflater::Deflate(decoder) => decoder.finish()?,
+        })
+    }
+}
@@ -0,0 +1,103 @@
+use crate::config::InfluxConfig;
+use crate::inverter::AuroraInverter;
+use embedded_svc::http::client::{Client, Request, RequestWrite};
+use embedded_svc::http::{SendHeaders, Status};
+use embedded_svc::io::Write;
+use esp_idf_svc::http::client::EspHttpClient;
+use std::fmt::Write as _;
+
+const MEASUREMENT: &str = "abb";
+
+// InfluxDB v2 /api/v2/write, a sink alongside MQTT for setups without a broker
+pub struct InfluxSink {
+    write_url: String,
+    authorization: String,
+}
+
+impl InfluxSink {
+    pub fn new(config: &InfluxConfig) -> Self {
+        Self {
+            write_url: format!(
+                "{}/api/v2/write?org={}&bucket={}&precision=s",
+                config.url.trim_end_matches('/'),
+                query_escape(&config.org),
+                query_escape(&config.bucket)
+            ),
+            authorization: format!("Token {}", config.token),
+        }
+    }
+
+    // one POST per poll cycle, a fresh client each time so a dead server holds nothing open
+    pub fn write(&self, lines: &str) -> anyhow::Result<()> {
+        let mut client = EspHttpClient::new_default()?;
+        let mut request = client.post(&self.write_url)?;
+        request
+            .set_header("Authorization", &self.authorization)
+            .set_header("Content-Type", "text/plain; charset=utf-8");
+        let mut writer = request.into_writer(lines.len())?;
+        writer.write_all(lines.as_bytes())?;
+        let response = writer.submit()?;
+        match response.status() {
+            200..=299 => Ok(()),
+            status => Err(anyhow::anyhow!("InfluxDB write returned {}", status)),
+        }
+    }
+}
+
+// abb,inverter=2 grid=230.1,gridpower=1.2345,... <unix secs>, online inverters only.
+// Without SNTP the timestamp is left for the server to fill in
+pub fn line_protocol(
+    inverters: &[AuroraInverter],
+    device_node: Option<&str>,
+    unix_secs: Option<i64>,
+) -> anyhow::Result<String> {
+    let mut lines = String::new();
+    for inverter in inverters.iter().filter(|inverter| inverter.is_online()) {
+        let mut fields = vec![];
+        for (prefix, section) in [
+            ("", serde_json::to_value(&inverter.data)?),
+            ("energy_", serde_json::to_value(&inverter.energy)?),
+        ] {
+            if let serde_json::Value::Object(values) = section {
+                for (key, value) in values {
+                    if let Some(value) = value.as_f64() {
+                        fields.push(format!("{}{}={}", prefix, key, value));
+                    }
+                }
+            }
+        }
+        if fields.is_empty() {
+            continue;
+        }
+        write!(lines, "{},inverter={}", MEASUREMENT, inverter.id())?;
+        if let Some(node) = device_node {
+            write!(lines, ",device={}", tag_escape(node))?;
+        }
+        write!(lines, " {}", fields.join(","))?;
+        if let Some(secs) = unix_secs {
+            write!(lines, " {}", secs)?;
+        }
+        lines.push('\n');
+    }
+    Ok(lines)
+}
+
+// device names are letters, digits, - and _ but escape anyway
+fn tag_escape(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn query_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
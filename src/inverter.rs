@@ -0,0 +1,597 @@
+#![allow(dead_code, clippy::clone_on_copy)]
+
+// Aurora data model shared with the web, MQTT, history and InfluxDB side, builds without the
+// aurora feature. crate::aurora is the UART driver that fills it in
+use crate::proto::aurora::*;
+pub use crate::proto::readings::*;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+// adaptive reply timeout, twice the average turnaround plus the margin, within these bounds
+const REPLY_TIMEOUT_MIN: Duration = Duration::from_millis(50);
+const REPLY_TIMEOUT_MAX: Duration = Duration::from_millis(1000);
+const REPLY_TIMEOUT_MARGIN: Duration = Duration::from_millis(20);
+// weight of each new sample in the rolling average
+const TURNAROUND_WEIGHT: f32 = 0.125;
+// a missed reply stretches the average so a slow bus isn't locked out by a short timeout
+const TURNAROUND_BACKOFF: f32 = 1.5;
+
+#[derive(Debug, Copy, Clone, Serialize)]
+pub enum Status {
+    Offline,
+    Online,
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Availablilty {
+    pub(crate) status: Status,
+}
+
+// Alarm state table from the ABB Aurora protocol, some codes share a meaning
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum AlarmCode {
+    NoAlarm,
+    SunLow,
+    InputOverCurrent,
+    InputUnderVoltage,
+    InputOverVoltage,
+    NoParameters,
+    BulkOverVoltage,
+    CommError,
+    OutputOverCurrent,
+    IgbtSat,
+    BulkUnderVoltage,
+    InternalError,
+    GridFail,
+    BulkLow,
+    RampFail,
+    DcDcFail,
+    WrongMode,
+    GroundFault,
+    OverTemperature,
+    BulkCapacitorFail,
+    InverterFail,
+    StartTimeout,
+    DegaussError,
+    IleakSensorFail,
+    SelfTestError1,
+    SelfTestError2,
+    SelfTestError3,
+    SelfTestError4,
+    DcInjectionError,
+    GridOverVoltage,
+    GridUnderVoltage,
+    GridOverFrequency,
+    GridUnderFrequency,
+    GridImpedanceHigh,
+    IsolationResistanceLow,
+    VrefError,
+    ErrorMeasureVoltage,
+    ErrorMeasureFrequency,
+    ErrorMeasureImpedance,
+    ErrorMeasureIleak,
+    ErrorReadVoltage,
+    ErrorReadCurrent,
+    TableFail,
+    FanFail,
+    UnderTemperature,
+    InterlockFail,
+    RemoteOff,
+    VoutAverageError,
+    BatteryLow,
+    ClockFail,
+    InputUnderCurrent,
+    ZeroPower,
+    FanStuck,
+    DcSwitchOpen,
+    TrasSwitchOpen,
+    AcSwitchOpen,
+    AutoExclusion,
+    GridDfDt,
+    DenSwitchOpen,
+    JboxFail,
+    Unknown(u8),
+}
+
+impl AlarmCode {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => Self::NoAlarm,
+            1 => Self::SunLow,
+            2 => Self::InputOverCurrent,
+            3 => Self::InputUnderVoltage,
+            4 => Self::InputOverVoltage,
+            5 => Self::SunLow,
+            6 => Self::NoParameters,
+            7 => Self::BulkOverVoltage,
+            8 => Self::CommError,
+            9 => Self::OutputOverCurrent,
+            10 => Self::IgbtSat,
+            11 => Self::BulkUnderVoltage,
+            12 => Self::InternalError,
+            13 => Self::GridFail,
+            14 => Self::BulkLow,
+            15 => Self::RampFail,
+            16 => Self::DcDcFail,
+            17 => Self::WrongMode,
+            18 => Self::GroundFault,
+            19 => Self::OverTemperature,
+            20 => Self::BulkCapacitorFail,
+            21 => Self::InverterFail,
+            22 => Self::StartTimeout,
+            23 => Self::GroundFault,
+            24 => Self::DegaussError,
+            25 => Self::IleakSensorFail,
+            26 => Self::DcDcFail,
+            27 => Self::SelfTestError1,
+            28 => Self::SelfTestError2,
+            29 => Self::SelfTestError3,
+            30 => Self::SelfTestError4,
+            31 => Self::DcInjectionError,
+            32 => Self::GridOverVoltage,
+            33 => Self::GridUnderVoltage,
+            34 => Self::GridOverFrequency,
+            35 => Self::GridUnderFrequency,
+            36 => Self::GridImpedanceHigh,
+            37 => Self::InternalError,
+            38 => Self::IsolationResistanceLow,
+            39 => Self::VrefError,
+            40 => Self::ErrorMeasureVoltage,
+            41 => Self::ErrorMeasureFrequency,
+            42 => Self::ErrorMeasureImpedance,
+            43 => Self::ErrorMeasureIleak,
+            44 => Self::ErrorReadVoltage,
+            45 => Self::ErrorReadCurrent,
+            46 => Self::TableFail,
+            47 => Self::FanFail,
+            48 => Self::UnderTemperature,
+            49 => Self::InterlockFail,
+            50 => Self::RemoteOff,
+            51 => Self::VoutAverageError,
+            52 => Self::BatteryLow,
+            53 => Self::ClockFail,
+            54 => Self::InputUnderCurrent,
+            55 => Self::ZeroPower,
+            56 => Self::FanStuck,
+            57 => Self::DcSwitchOpen,
+            58 => Self::TrasSwitchOpen,
+            59 => Self::AcSwitchOpen,
+            60 => Self::BulkUnderVoltage,
+            61 => Self::AutoExclusion,
+            62 => Self::GridDfDt,
+            63 => Self::DenSwitchOpen,
+            64 => Self::JboxFail,
+            _ => Self::Unknown(code),
+        }
+    }
+}
+
+// Global state table from the ABB Aurora protocol, codes marking a single internal error share a variant
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum GlobalState {
+    SendingParameters,
+    WaitSunGrid,
+    CheckingGrid,
+    MeasuringRiso,
+    DcDcStart,
+    InverterStart,
+    Run,
+    Recovery,
+    Pause,
+    GroundFault,
+    OverTemperatureFault,
+    AddressSetting,
+    SelfTest,
+    SelfTestFail,
+    SensorTest,
+    LeakFault,
+    WaitingManualReset,
+    InternalError,
+    SendingWindTable,
+    FailedSendingTable,
+    UnderTemperatureFault,
+    RemoteOff,
+    InterlockFail,
+    ExecutingAutotest,
+    WaitingSun,
+    TemperatureFault,
+    FanStuck,
+    InternalCommFault,
+    SlaveInsertion,
+    DcSwitchOpen,
+    TrasSwitchOpen,
+    MasterExclusion,
+    AutoExclusion,
+    ErasingEeprom,
+    CountingEeprom,
+    Freeze,
+    Unknown(u8),
+}
+
+impl GlobalState {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => Self::SendingParameters,
+            1 => Self::WaitSunGrid,
+            2 => Self::CheckingGrid,
+            3 => Self::MeasuringRiso,
+            4 => Self::DcDcStart,
+            5 => Self::InverterStart,
+            6 => Self::Run,
+            7 => Self::Recovery,
+            8 => Self::Pause,
+            9 => Self::GroundFault,
+            10 => Self::OverTemperatureFault,
+            11 => Self::AddressSetting,
+            12 => Self::SelfTest,
+            13 => Self::SelfTestFail,
+            14 => Self::SensorTest,
+            15 => Self::LeakFault,
+            16 => Self::WaitingManualReset,
+            17..=21 => Self::InternalError,
+            22 => Self::SendingWindTable,
+            23 => Self::FailedSendingTable,
+            24 => Self::UnderTemperatureFault,
+            25 => Self::RemoteOff,
+            26 => Self::InterlockFail,
+            27 => Self::ExecutingAutotest,
+            30 => Self::WaitingSun,
+            31 => Self::TemperatureFault,
+            32 => Self::FanStuck,
+            33 => Self::InternalCommFault,
+            34 => Self::SlaveInsertion,
+            35 => Self::DcSwitchOpen,
+            36 => Self::TrasSwitchOpen,
+            37 => Self::MasterExclusion,
+            38 => Self::AutoExclusion,
+            98 | 99 => Self::ErasingEeprom,
+            100 => Self::CountingEeprom,
+            101 => Self::Freeze,
+            _ => Self::Unknown(code),
+        }
+    }
+}
+
+// Inverter (DC/AC stage) state table from the ABB Aurora protocol
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum InverterState {
+    StandBy,
+    CheckingGrid,
+    Run,
+    BulkOverVoltage,
+    OutputOverCurrent,
+    IgbtSat,
+    BulkUnderVoltage,
+    DegaussError,
+    NoParameters,
+    BulkLow,
+    GridOverVoltage,
+    CommError,
+    Degaussing,
+    Starting,
+    BulkCapacitorFail,
+    LeakFail,
+    DcDcFail,
+    IleakSensorFail,
+    SelfTest,
+    SelfTestFail,
+    DcInjection,
+    InternalError,
+    ForbiddenState,
+    InputUnderCurrent,
+    ZeroPower,
+    GridNotPresent,
+    WaitingStart,
+    Mppt,
+    GridFail,
+    InputOverCurrent,
+    Unknown(u8),
+}
+
+impl InverterState {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => Self::StandBy,
+            1 => Self::CheckingGrid,
+            2 => Self::Run,
+            3 => Self::BulkOverVoltage,
+            4 => Self::OutputOverCurrent,
+            5 => Self::IgbtSat,
+            6 => Self::BulkUnderVoltage,
+            7 => Self::DegaussError,
+            8 => Self::NoParameters,
+            9 => Self::BulkLow,
+            10 => Self::GridOverVoltage,
+            11 => Self::CommError,
+            12 => Self::Degaussing,
+            13 => Self::Starting,
+            14 => Self::BulkCapacitorFail,
+            15 => Self::LeakFail,
+            16 => Self::DcDcFail,
+            17 => Self::IleakSensorFail,
+            18..=20 | 24 | 25 | 27..=29 => Self::SelfTest,
+            21..=23 => Self::SelfTestFail,
+            26 => Self::DcInjection,
+            30 | 31 => Self::InternalError,
+            40 => Self::ForbiddenState,
+            41 => Self::InputUnderCurrent,
+            42 => Self::ZeroPower,
+            43 => Self::GridNotPresent,
+            44 => Self::WaitingStart,
+            45 => Self::Mppt,
+            46 => Self::GridFail,
+            47 => Self::InputOverCurrent,
+            _ => Self::Unknown(code),
+        }
+    }
+}
+
+// DC/DC channel state table from the ABB Aurora protocol
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum DcDcState {
+    Off,
+    RampStart,
+    Mppt,
+    InputOverCurrent,
+    InputUnderVoltage,
+    InputOverVoltage,
+    InputLow,
+    NoParameters,
+    BulkOverVoltage,
+    CommError,
+    RampFail,
+    InternalError,
+    InputModeError,
+    GroundFault,
+    InverterFail,
+    IgbtSat,
+    IleakFail,
+    GridFail,
+    Unknown(u8),
+}
+
+impl DcDcState {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => Self::Off,
+            1 => Self::RampStart,
+            2 => Self::Mppt,
+            4 => Self::InputOverCurrent,
+            5 => Self::InputUnderVoltage,
+            6 => Self::InputOverVoltage,
+            7 => Self::InputLow,
+            8 => Self::NoParameters,
+            9 => Self::BulkOverVoltage,
+            10 | 19 => Self::CommError,
+            11 => Self::RampFail,
+            12 => Self::InternalError,
+            13 => Self::InputModeError,
+            14 => Self::GroundFault,
+            15 => Self::InverterFail,
+            16 => Self::IgbtSat,
+            17 => Self::IleakFail,
+            18 => Self::GridFail,
+            _ => Self::Unknown(code),
+        }
+    }
+}
+
+// Function 50 reply, bytes 1..6 after the transmission state
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct OperatingState {
+    pub global: GlobalState,
+    pub inverter: InverterState,
+    pub dcdc1: DcDcState,
+    pub dcdc2: DcDcState,
+    pub alarm: AlarmCode,
+}
+
+// ASCII identity strings, read once per inverter
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Identity {
+    pub serial: String,
+    pub version: String,
+    pub firmware: String,
+    // inventory only, None when the model doesn't answer these
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub part_number: Option<String>,
+    // ISO week, e.g. 2014-W23
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufacture_date: Option<String>,
+}
+
+// One raw state read for wiring checks, see Aurora::self_test
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTest {
+    pub id: u8,
+    pub answered: bool,
+    pub crc_ok: bool,
+    pub round_trip_ms: u32,
+    // decoded first reply byte
+    pub transmission_state: Option<String>,
+    pub error: Option<String>,
+}
+
+// Rolling average from request sent to first reply byte
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Turnaround {
+    avg_ms: Option<f32>,
+}
+
+impl Turnaround {
+    pub fn record(&mut self, sample: Duration) {
+        let ms = sample.as_secs_f32() * 1000.0;
+        self.avg_ms = Some(match self.avg_ms {
+            Some(avg) => avg + (ms - avg) * TURNAROUND_WEIGHT,
+            None => ms,
+        });
+    }
+    pub fn timed_out(&mut self) {
+        // capped where the timeout would clamp anyway
+        let cap = REPLY_TIMEOUT_MAX.as_secs_f32() * 1000.0 / 2.0;
+        if let Some(avg) = &mut self.avg_ms {
+            *avg = (*avg * TURNAROUND_BACKOFF).min(cap);
+        }
+    }
+    pub fn avg_ms(&self) -> Option<f32> {
+        self.avg_ms
+    }
+    // fallback until the inverter has answered once
+    pub fn timeout(&self, fallback: Duration) -> Duration {
+        match self.avg_ms {
+            Some(avg) => (Duration::from_secs_f32(avg * 2.0 / 1000.0) + REPLY_TIMEOUT_MARGIN)
+                .clamp(REPLY_TIMEOUT_MIN, REPLY_TIMEOUT_MAX),
+            None => fallback,
+        }
+    }
+}
+
+// non-OK transmission states, the last one is published retained on <id>/last_error
+#[derive(Clone, Default)]
+pub struct TransmissionErrors {
+    pub last: Option<(String, TransmissionState)>,
+    pub counts: Vec<(TransmissionState, u32)>,
+    unpublished: bool,
+}
+
+impl TransmissionErrors {
+    pub(crate) fn record(&mut self, request: String, state: TransmissionState) {
+        match self.counts.iter_mut().find(|(s, _)| *s == state) {
+            Some((_, count)) => *count = count.saturating_add(1),
+            None => self.counts.push((state, 1)),
+        }
+        self.last = Some((request, state));
+        self.unpublished = true;
+    }
+    // true once after each new error, until published
+    pub fn take_unpublished(&mut self) -> bool {
+        std::mem::replace(&mut self.unpublished, false)
+    }
+    pub fn to_json(&self) -> serde_json::Value {
+        let counts: serde_json::Map<String, serde_json::Value> = self
+            .counts
+            .iter()
+            .map(|(state, count)| (format!("{:?}", state), (*count).into()))
+            .collect();
+        serde_json::json!({
+            "request": self.last.as_ref().map(|(request, _)| request),
+            "state": self.last.as_ref().map(|(_, state)| format!("{:?}", state)),
+            "counts": counts,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct AuroraInverter {
+    pub data: Dsp,
+    pub(crate) availability: Availablilty,
+    pub(crate) id: u8,
+    pub energy: EnergyTotals,
+    pub(crate) energy_check: EnergyCheck,
+    pub(crate) lastmessage: Instant,
+    pub(crate) address_collision: bool,
+    poll_errors: u32,
+    // last four alarms as reported by the inverter, most recent first
+    pub alarms: Vec<AlarmCode>,
+    pub identity: Option<Identity>,
+    pub(crate) identity_unpublished: bool,
+    pub operating_state: Option<OperatingState>,
+    // inverter RTC, seconds since 2000-01-01 in whatever zone it was set to
+    pub clock_secs: Option<u32>,
+    // answered a discovery probe, absent ids are skipped by the poll loop
+    pub(crate) present: bool,
+    pub(crate) next_probe: Instant,
+    // resolved energy command, None until probed
+    pub(crate) energy_function: Option<EnergyFunction>,
+    // sets the reply timeout for this inverter
+    pub turnaround: Turnaround,
+    pub(crate) pass_start: Instant,
+    // GLOBAL_FIELDS values by suffix, DSP_FIELDS scaling
+    pub global: Vec<(&'static str, f32)>,
+    // refused a global measure, single inverter models, not asked again this boot
+    pub(crate) global_unsupported: bool,
+    // an implausible float has been logged once
+    pub(crate) float_order_warned: bool,
+    // wall time of the last complete poll pass, including turns given to other inverters
+    pub last_pass_ms: Option<u32>,
+    pub transmission_errors: TransmissionErrors,
+}
+impl AuroraInverter {
+    pub fn new(id: u8) -> Self {
+        Self {
+            data: Dsp::default(),
+            availability: Availablilty {
+                status: Status::Offline,
+            },
+            id,
+            energy: EnergyTotals::default(),
+            energy_check: EnergyCheck::new(crate::ENERGY_MAX_KW),
+            lastmessage: Instant::now() - Duration::from_secs(60),
+            address_collision: false,
+            poll_errors: 0,
+            alarms: vec![],
+            identity: None,
+            identity_unpublished: false,
+            operating_state: None,
+            clock_secs: None,
+            present: true,
+            next_probe: Instant::now(),
+            energy_function: None,
+            turnaround: Turnaround::default(),
+            pass_start: Instant::now(),
+            global: vec![],
+            global_unsupported: false,
+            float_order_warned: false,
+            last_pass_ms: None,
+            transmission_errors: TransmissionErrors::default(),
+        }
+    }
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+    pub fn is_online(&self) -> bool {
+        matches!(self.availability.status, Status::Online)
+    }
+    pub fn address_collision(&self) -> bool {
+        self.address_collision
+    }
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+    pub fn reprobe_due(&self) -> bool {
+        Instant::now() >= self.next_probe
+    }
+    pub fn energy_function(&self) -> Option<EnergyFunction> {
+        self.energy_function
+    }
+    pub fn poll_errors(&self) -> u32 {
+        self.poll_errors
+    }
+    pub fn record_poll_error(&mut self) {
+        self.poll_errors = self.poll_errors.saturating_add(1);
+    }
+    pub fn seconds_since_last_message(&self) -> u64 {
+        self.lastmessage.elapsed().as_secs()
+    }
+    // true once after the identity has been read, until published
+    pub fn take_identity_unpublished(&mut self) -> bool {
+        std::mem::replace(&mut self.identity_unpublished, false)
+    }
+    // most recent alarm still reported, NoAlarm when clear
+    pub fn active_alarm(&self) -> AlarmCode {
+        self.alarms
+            .iter()
+            .copied()
+            .find(|a| *a != AlarmCode::NoAlarm)
+            .unwrap_or(AlarmCode::NoAlarm)
+    }
+}
+impl core::fmt::Debug for AuroraInverter {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(
+            f,
+            "Inverter ID: {}\n{:?}\n{:?}\n{:#?}\n{:#?}",
+            self.id, self.availability, self.alarms, self.energy, self.data
+        )
+    }
+}
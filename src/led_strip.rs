@@ -11,12 +11,30 @@ const WS2812_T0L_NS: u32 = 1000;
 const WS2812_T1H_NS: u32 = 1000;
 const WS2812_T1L_NS: u32 = 350;
 //const WS2812_RESET_US: u32 = 280;
+const LEVEL_ON: u8 = 0x10;
+// set_status expects to be called once per tick
+pub const STATUS_TICK: Duration = Duration::from_millis(100);
+// one green breath, in ticks
+const BREATHE_TICKS: u32 = 30;
 
 pub enum LedState {
     Off,
     On,
     NC,
 }
+// running state shown on the LED, most urgent first
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LedStatus {
+    // solid red
+    WifiDown,
+    // solid amber
+    MqttDown,
+    // fast red blink, RS485 hardware errors
+    InverterFault,
+    // green breathing
+    Healthy,
+}
+
 #[derive(Debug)]
 pub struct EspError {
     inner: esp_err_t,
@@ -198,23 +216,45 @@ impl LedStrip<1> {
     ) -> Result<(), EspError> {
         match red {
             LedState::Off => self.buffer[0][1] = 0,
-            LedState::On => self.buffer[0][1] = 0x10,
+            LedState::On => self.buffer[0][1] = LEVEL_ON,
             LedState::NC => (),
         }
         match green {
             LedState::Off => self.buffer[0][0] = 0,
-            LedState::On => self.buffer[0][0] = 0x10,
+            LedState::On => self.buffer[0][0] = LEVEL_ON,
             LedState::NC => (),
         }
         match blue {
             LedState::Off => self.buffer[0][2] = 0,
-            LedState::On => self.buffer[0][2] = 0x10,
+            LedState::On => self.buffer[0][2] = LEVEL_ON,
             LedState::NC => (),
         }
         // self.buffer[0] = [green, red, blue];
         self.update()
     }
 
+    // one step of the status pattern, tick counts STATUS_TICK periods
+    pub fn set_status(&mut self, status: LedStatus, tick: u32) -> Result<(), EspError> {
+        let (red, green, blue) = match status {
+            LedStatus::WifiDown => (LEVEL_ON, 0, 0),
+            LedStatus::MqttDown => (LEVEL_ON, LEVEL_ON / 2, 0),
+            LedStatus::InverterFault if tick % 2 == 0 => (LEVEL_ON, 0, 0),
+            LedStatus::InverterFault => (0, 0, 0),
+            LedStatus::Healthy => {
+                let half = BREATHE_TICKS / 2;
+                let phase = tick % BREATHE_TICKS;
+                let level = if phase < half {
+                    phase
+                } else {
+                    BREATHE_TICKS - phase
+                };
+                (0, (LEVEL_ON as u32 * level / half) as u8, 0)
+            }
+        };
+        self.buffer[0] = [green, red, blue];
+        self.update()
+    }
+
     fn update(&mut self) -> Result<(), EspError> {
         unsafe {
             esp_res(esp_idf_sys::rmt_write_sample(
@@ -0,0 +1,126 @@
+use crate::storage;
+use esp_idf_svc::nvs::EspDefaultNvs;
+use esp_idf_svc::nvs_storage::EspNvsStorage;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const NAMESPACE: &str = "lifecycle";
+const KEY: &str = "record";
+const FIRMWARE_HISTORY_LEN: usize = 5;
+
+// Persisted across reboots, published retained to {topic}/lifecycle
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Lifecycle {
+    pub boot_count: u32,
+    // seconds powered on since first flash
+    pub total_uptime_secs: u64,
+    // oldest first, running firmware last
+    pub firmware_history: Vec<String>,
+    // unix time a new firmware first booted, filled in once SNTP has synced
+    #[serde(default)]
+    pub last_ota_unix_secs: Option<i64>,
+    // total_uptime_secs at that boot, only kept until the wall time is known
+    pub last_ota_uptime_secs: Option<u64>,
+}
+
+pub struct LifecycleTracker {
+    storage: EspNvsStorage,
+    record: Lifecycle,
+    uptime_at_boot: u64,
+    boot_time: Instant,
+    last_save: Instant,
+    save_interval: Duration,
+    unpublished: bool,
+    // this boot is the first of a new firmware and the clock wasn't synced yet
+    ota_time_pending: bool,
+}
+
+impl LifecycleTracker {
+    // bumps boot count and notes a firmware change, then persists immediately
+    pub fn new(
+        default_nvs: Arc<EspDefaultNvs>,
+        boot_time: Instant,
+        save_interval: Duration,
+    ) -> anyhow::Result<Self> {
+        let mut storage = storage::open(default_nvs, NAMESPACE)?;
+        let mut record: Lifecycle = storage::load(&storage, KEY)?.unwrap_or_default();
+
+        record.boot_count = record.boot_count.saturating_add(1);
+        let mut ota_time_pending = false;
+        if record.firmware_history.last().map(String::as_str) != Some(crate::VERSION) {
+            if !record.firmware_history.is_empty() {
+                record.last_ota_unix_secs = None;
+                record.last_ota_uptime_secs = Some(record.total_uptime_secs);
+                ota_time_pending = true;
+            }
+            record.firmware_history.push(crate::VERSION.to_string());
+            if record.firmware_history.len() > FIRMWARE_HISTORY_LEN {
+                record.firmware_history.remove(0);
+            }
+        }
+        storage::store(&mut storage, KEY, &record)?;
+        info!(
+            "Boot #{} running firmware {}",
+            record.boot_count,
+            crate::VERSION
+        );
+
+        Ok(Self {
+            storage,
+            uptime_at_boot: record.total_uptime_secs,
+            record,
+            boot_time,
+            last_save: Instant::now(),
+            save_interval,
+            unpublished: true,
+            ota_time_pending,
+        })
+    }
+
+    // dates this boot's OTA once SNTP has synced, back dated to the boot itself
+    pub fn record_ota_time(&mut self, unix_secs: Option<i64>) -> anyhow::Result<()> {
+        let now = match unix_secs {
+            Some(now) if self.ota_time_pending => now,
+            _ => return Ok(()),
+        };
+        self.ota_time_pending = false;
+        self.record.last_ota_unix_secs = Some(now - self.boot_time.elapsed().as_secs() as i64);
+        self.record.last_ota_uptime_secs = None;
+        self.snapshot();
+        storage::store(&mut self.storage, KEY, &self.record)?;
+        self.unpublished = true;
+        Ok(())
+    }
+
+    pub fn snapshot(&mut self) -> &Lifecycle {
+        self.record.total_uptime_secs = self.uptime_at_boot + self.boot_time.elapsed().as_secs();
+        &self.record
+    }
+
+    // NVS writes are rate limited to save flash wear
+    pub fn save_if_due(&mut self) -> anyhow::Result<()> {
+        if self.last_save.elapsed() < self.save_interval {
+            return Ok(());
+        }
+        self.snapshot();
+        storage::store(&mut self.storage, KEY, &self.record)?;
+        self.last_save = Instant::now();
+        self.unpublished = true;
+        Ok(())
+    }
+
+    // true once after boot and after each save, until published
+    pub fn take_unpublished(&mut self) -> bool {
+        std::mem::replace(&mut self.unpublished, false)
+    }
+
+    pub fn to_json(&mut self) -> anyhow::Result<String> {
+        let mut json = serde_json::to_value(self.snapshot())?;
+        if let Some(secs) = self.record.last_ota_unix_secs {
+            json["last_ota_time"] = crate::clock::iso8601(secs).into();
+        }
+        Ok(json.to_string())
+    }
+}
@@ -0,0 +1,74 @@
+use esp_idf_svc::log::EspLogger;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+
+// long lines are cut, the ring is sized in lines
+const MAX_LINE_LEN: usize = 200;
+
+static LINES: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static LOGGER: LogRing = LogRing {
+    esp: EspLogger,
+    capacity: crate::LOG_RING_LEN,
+};
+
+// Tees the log facade into the ESP logger and the last `capacity` lines for GET /logs
+struct LogRing {
+    esp: EspLogger,
+    capacity: usize,
+}
+
+impl Log for LogRing {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.esp.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.esp.log(record);
+        if !self.enabled(record.metadata()) || self.capacity == 0 {
+            return;
+        }
+        let uptime_ms = unsafe { esp_idf_sys::esp_timer_get_time() } / 1000;
+        let mut line = format!("{} {:<5} ", uptime_ms, record.level());
+        let _ = write!(line, "{}", record.args());
+        if line.len() > MAX_LINE_LEN {
+            let mut end = MAX_LINE_LEN;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+        }
+        // a logging thread never waits on a GET /logs copy
+        if let Ok(mut lines) = LINES.try_lock() {
+            if lines.len() == self.capacity {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+    }
+
+    fn flush(&self) {
+        self.esp.flush()
+    }
+}
+
+// replaces EspLogger::initialize_default
+pub fn init(level: LevelFilter) {
+    match log::set_logger(&LOGGER) {
+        Ok(()) => log::set_max_level(level),
+        Err(e) => println!("Logger already set {:?}", e),
+    }
+}
+
+// oldest first, one line each as "<uptime ms> <level> <message>"
+pub fn dump() -> String {
+    match LINES.lock() {
+        Ok(lines) => lines.iter().fold(String::new(), |mut text, line| {
+            text.push_str(line);
+            text.push('\n');
+            text
+        }),
+        Err(_) => String::new(),
+    }
+}
@@ -1,24 +1,66 @@
 #![feature(backtrace)]
 #![allow(clippy::redundant_clone)]
 
-use embedded_hal::digital::v2::OutputPin;
+// hardware free frame and upload parsing lives in its own crate so it tests on the host
+use abb_proto::{self as proto, error, multipart};
+use esp_idf_hal::gpio;
 use esp_idf_hal::peripherals::Peripherals;
 use esp_idf_hal::prelude::Hertz;
 use esp_idf_hal::serial;
 use esp_idf_svc::{netif::EspNetifStack, nvs::EspDefaultNvs, sysloop::EspSysLoopStack};
+use log::info;
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
+#[cfg(feature = "aurora")]
 mod aurora;
+mod backend;
+mod clock;
+mod config;
+mod connectivity;
+mod deadman;
+mod energy_baseline;
 mod events;
+mod frame_log;
+mod history;
+mod http_server;
 mod idf_mqtt;
+mod inflate;
+mod influx;
+mod inverter;
 mod led_strip;
+mod lifecycle;
+mod log_ring;
+mod outbox;
+#[cfg(feature = "aurora")]
+mod power_stats;
+mod publish_cache;
+mod rollback;
+#[cfg(feature = "solax")]
+mod solax_x1_air;
+mod state;
+mod storage;
+mod transceiver;
 mod wifi_init;
+#[cfg(feature = "aurora")]
 use aurora::*;
-use esp_idf_svc::mqtt::client::MqttClientConfiguration;
-use led_strip::{Led, LedState};
+use config::InverterKind;
+#[cfg(feature = "solax")]
+use config::SolaxFraming;
+use connectivity::Connectivity;
+use history::History;
+use inverter::*;
+use led_strip::{Led, LedState, LedStatus};
+use lifecycle::LifecycleTracker;
+#[cfg(feature = "solax")]
+use solax_x1_air::{Framing, SolaxX1Air};
+use state::{AppState, PollStats};
+use transceiver::Transceiver;
 
-// Secrets from .env file
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Secrets from .env file, first boot defaults for the NVS DeviceConfig
 const SSID: &str = env!("SSID");
 const PASS: &str = env!("PASS");
 const MQTT_ADDR: &str = env!("MQTT_ADDR");
@@ -26,23 +68,115 @@ const MQTT_USERNAME: &str = env!("MQTT_USERNAME");
 const MQTT_PASSWORD: &str = env!("MQTT_PASSWORD");
 const MQTT_CLIENT_ID: &str = env!("MQTT_CLIENT_ID");
 const MQTT_TOPIC_NAME: &str = env!("MQTT_TOPIC_NAME");
+// topics under <MQTT_TOPIC_NAME>/<mac> when no DeviceConfig::device_name is set
+const MQTT_TOPIC_WITH_MAC: bool = false;
+const HTTP_USERNAME: &str = env!("HTTP_USERNAME");
+const HTTP_PASSWORD: &str = env!("HTTP_PASSWORD");
 const MQTT_FREQUENCY: Duration = Duration::from_secs(10);
+// unchanged inverter values are resent after this many skipped cycles, 0 sends every cycle
+const MQTT_DEDUP_REFRESH_CYCLES: u32 = 30;
+// numeric values closer than this count as unchanged
+const MQTT_DEDUP_EPSILON: f64 = 0.001;
+// inverter messages held through a broker outage, latest per topic, ~100 bytes each
+const MQTT_OUTBOX_LEN: usize = 100;
+// broker drops the session and sends the offline LWT after ~1.5x this without a packet,
+// 30s notices a dead gateway within a minute without much traffic on a flaky AP
+const MQTT_KEEPALIVE: Duration = Duration::from_secs(30);
+// a clean session costs nothing here, subscriptions are resent on every connect
+const MQTT_CLEAN_SESSION: bool = true;
+// esp-mqtt's own wait between reconnect attempts, connectivity.rs rebuilds the client on top
+const MQTT_RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// reply timeout until an inverter has answered, then it adapts to the measured turnaround
+#[cfg(feature = "aurora")]
 const INVERTER_COMMS_TIMEOUT: Duration = Duration::from_millis(250);
-
-/*
-Need to qualify MQTT publish with a check on wifi status
-Maybe add a state
-*/
+// reply timeout from Solax protocol 1.7
+#[cfg(feature = "solax")]
+const SOLAX_COMMS_TIMEOUT: Duration = Duration::from_millis(500);
+// gap between the registration and query requests, a cold inverter needs the time
+#[cfg(feature = "solax")]
+const SOLAX_REQUEST_DELAY: Duration = Duration::from_millis(300);
+// used when DeviceConfig::solax_framing is modbus, check the register map of the firmware
+#[cfg(feature = "solax")]
+const SOLAX_MODBUS_ADDRESS: u8 = 1;
+#[cfg(feature = "solax")]
+const SOLAX_MODBUS_LIVE_DATA_BASE: u16 = 0x0400;
+// also publish the undecoded QueryConfig registers under <topic>/solax/raw
+#[cfg(feature = "solax")]
+const SOLAX_RAW_CONFIG: bool = false;
+// protocol default baud rates, DeviceConfig::rs485_baud overrides when supported
+const RS485_BAUD: u32 = 19_200;
+// UART1 pins to the RS485 transceiver, each pin is its own type so a rewire changes these and
+// the matching peripherals.pins field in main, a mismatch doesn't compile
+type Rs485Tx = gpio::Gpio5<gpio::Unknown>;
+type Rs485Rx = gpio::Gpio4<gpio::Unknown>;
+#[cfg(feature = "solax")]
+const SOLAX_BAUD: u32 = 9_600;
+// backend on the RS485 port unless DeviceConfig::inverter says otherwise
+const INVERTER_KIND: InverterKind = InverterKind::Aurora;
+// RS485 addresses probed at boot unless DeviceConfig::inverter_ids is set,
+// ids that don't answer are skipped
+const AURORA_INVERTER_IDS: [u8; 2] = [2, 3];
+// skip MQTT publishes (inverters are still polled) while WiFi/MQTT is down
+const PUBLISH_ONLY_WHEN_CONNECTED: bool = true;
+// with PUBLISH_ONLY_WHEN_CONNECTED, also hold publishes while the AP is weaker than this (dBm),
+// a marginal link blocks each publish for its full timeout
+const MQTT_MIN_RSSI: Option<i8> = None;
+// one retained JSON document per inverter on <topic>/<id>/state instead of a topic per field
+const MQTT_SINGLE_JSON: bool = false;
+const MQTT_RECONNECT_AFTER_FAILURES: u32 = 5;
+// consecutive UART errors before the RS485 transceiver is power cycled
+const RS485_POWER_CYCLE_AFTER: u32 = 3;
+// SNTP servers and TZ unless DeviceConfig::ntp_servers / timezone are set
+const NTP_SERVER: &str = "pool.ntp.org";
+const NTP_FALLBACK_SERVER: &str = "time.google.com";
+// POSIX TZ string, local midnight is derived from this
+const TIMEZONE: &str = "GMT0BST,M3.5.0/1,M10.5.0";
+#[cfg(feature = "aurora")]
+const ADDRESS_COLLISION_POLICY: CollisionPolicy = CollisionPolicy::Report;
+// energy readings rising faster than this much power since the last read are dropped as bad
+// frames, well above any single Aurora model
+const ENERGY_MAX_KW: f32 = 100.0;
+// Cumulated (78) or CumulatedFloat (68) to pin the energy command, Auto probes each inverter
+#[cfg(feature = "aurora")]
+const ENERGY_FUNCTION: EnergyFunction = EnergyFunction::Auto;
+// pause between Aurora requests, DeviceConfig::request_gap_ms overrides, zero sends back to back
+const AURORA_REQUEST_GAP: Duration = Duration::ZERO;
+// inverter RTC this far from SNTP local time is logged, its energy day rolls at its own midnight
+#[cfg(feature = "aurora")]
+const INVERTER_CLOCK_SKEW_WARN: Duration = Duration::from_secs(300);
+// site power min/max/avg window, None restarts them at local midnight
+#[cfg(feature = "aurora")]
+const SITE_POWER_WINDOW: Option<Duration> = None;
+const LIFECYCLE_SAVE_INTERVAL: Duration = Duration::from_secs(600);
+// a new OTA image that hasn't reached WiFi and MQTT by then is rolled back
+const OTA_CONFIRM_TIMEOUT: Duration = Duration::from_secs(300);
+// restart when no poll cycle completes within this many poll intervals, 0 disables.
+// Never sooner than DEADMAN_MIN, a long cycle on a short interval still finishes
+const DEADMAN_POLL_MULTIPLE: u32 = 3;
+const DEADMAN_MIN: Duration = Duration::from_secs(120);
+// poll snapshots kept per inverter for GET /history, ~130 bytes each
+const HISTORY_LEN: usize = 60;
+// raw RS485 frames kept for GET /frames, a request and its reply are two
+const FRAME_LOG_LEN: usize = 64;
+// log lines kept for GET /logs, up to 200 bytes each
+const LOG_RING_LEN: usize = 64;
+// the ESP tag levels still apply below this
+const LOG_LEVEL: log::LevelFilter = log::LevelFilter::Info;
+// SoftAP brought up when the configured WiFi can't be joined, WPA2 needs 8+ characters
+const AP_FALLBACK_SSID: &str = "abb_to_mqtt";
+const AP_FALLBACK_PASS: &str = "abbsetup";
 
 fn main() -> anyhow::Result<()> {
     // Temporary. Will disappear once ESP-IDF 4.4 is released, but for now it is necessary to call this function once,
     // or else some patches to the runtime implemented by esp-idf-sys might not link properly.
     esp_idf_sys::link_patches();
 
-    // Bind the log crate to the ESP Logging facilities
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // Bind the log crate to the ESP Logging facilities, with a copy for GET /logs
+    log_ring::init(LOG_LEVEL);
 
     let boot_time: Instant = Instant::now();
+    info!("ABB_TO_MQTT version {}", VERSION);
+    let boot_guard = rollback::BootGuard::start(OTA_CONFIRM_TIMEOUT);
 
     #[allow(unused)]
     let netif_stack = Arc::new(EspNetifStack::new()?);
@@ -51,21 +185,40 @@ fn main() -> anyhow::Result<()> {
     #[allow(unused)]
     let default_nvs = Arc::new(EspDefaultNvs::new()?);
 
+    let device_config = config::load(default_nvs.clone())?;
+
+    let lifecycle = Arc::new(Mutex::new(LifecycleTracker::new(
+        default_nvs.clone(),
+        boot_time,
+        LIFECYCLE_SAVE_INTERVAL,
+    )?));
+
     // GPIO setup ****************************
     let peripherals = Peripherals::take().expect("Problem aquiring Peripherals::take()");
 
     // +3v3 for RS485 tranceiver**************
     let mut powerpin = peripherals.pins.gpio6.into_output()?;
     powerpin.set_drive_strength(esp_idf_hal::gpio::DriveStrength::I40mA)?;
-    powerpin.set_high()?; // power to RS485
+    // power to RS485
+    let transceiver = Arc::new(Mutex::new(Transceiver::new(
+        powerpin,
+        RS485_POWER_CYCLE_AFTER,
+        device_config.low_power,
+    )?));
 
     // For UART 1 ****************************
-    let config = serial::config::Config::default().baudrate(Hertz(19_200));
+    let inverter_kind = device_config.inverter_kind();
+    info!("Inverter backend {:?}", inverter_kind);
+    let rs485_baud = rs485_baud(inverter_kind, device_config.rs485_baud);
+    info!("RS485 at {} baud", rs485_baud);
+    let config = serial::config::Config::default().baudrate(Hertz(rs485_baud));
+    let rs485_tx: Rs485Tx = peripherals.pins.gpio5;
+    let rs485_rx: Rs485Rx = peripherals.pins.gpio4;
     let userial: serial::Serial<serial::UART1, _, _> = serial::Serial::new(
         peripherals.uart1,
         serial::Pins {
-            tx: peripherals.pins.gpio5,
-            rx: peripherals.pins.gpio4,
+            tx: rs485_tx,
+            rx: rs485_rx,
             cts: None,
             rts: None,
         },
@@ -82,16 +235,37 @@ fn main() -> anyhow::Result<()> {
     led.set_color(LedState::Off, LedState::Off, LedState::Off)?;
 
     // Init WiFi network ****************************
-    let _wifi = wifi_init::wifi(
+    let (wifi, wifi_mode) = wifi_init::wifi_multi(
         netif_stack.clone(),
         sys_loop_stack.clone(),
         default_nvs.clone(),
-        SSID,
-        PASS,
+        &device_config.networks(),
+        AP_FALLBACK_SSID,
+        AP_FALLBACK_PASS,
     )?;
 
+    if wifi_mode == wifi_init::WifiMode::AccessPoint {
+        // nothing to publish to, serve the config form until restarted with new credentials
+        info!("Config portal up on SoftAP {}", AP_FALLBACK_SSID);
+        let _portal = http_server::config_portal(
+            default_nvs.clone(),
+            &device_config.http_username,
+            &device_config.http_password,
+        )?;
+        loop {
+            led.set_color(LedState::On, LedState::NC, LedState::NC)?;
+            thread::sleep(Duration::from_millis(250));
+            led.set_color(LedState::Off, LedState::NC, LedState::NC)?;
+            thread::sleep(Duration::from_millis(250));
+        }
+    }
+    let wifi = Arc::new(Mutex::new(wifi));
+
     led.set_color(LedState::NC, LedState::On, LedState::NC)?;
-    let _current_ssid = &(*SSID);
+    let clock = Arc::new(clock::Clock::start(
+        &device_config.ntp_servers(),
+        device_config.timezone(),
+    )?);
 
     // Get MAC address - janky + unsafe
     let mut mac: [u8; 6] = [0; 6];
@@ -102,40 +276,210 @@ fn main() -> anyhow::Result<()> {
         )
     })?;
 
+    let device_node = device_config.device_node(&mac);
+    let topic_prefix = match &device_node {
+        Some(node) => format!("{}/{}", MQTT_TOPIC_NAME, node),
+        None => MQTT_TOPIC_NAME.to_string(),
+    };
+    info!("MQTT topics under {}", topic_prefix);
+
     // MQTT unique client_id
-    let client_id = &format!("{}{:?}", MQTT_CLIENT_ID, mac);
-    let conf = MqttClientConfiguration {
-        client_id: Some(client_id),
-        username: Some(MQTT_USERNAME),
-        password: Some(MQTT_PASSWORD),
-        ..Default::default()
+    let mqtt_settings = idf_mqtt::MqttSettings {
+        url: device_config.mqtt_addr.clone(),
+        client_id: format!("{}{:?}", device_config.mqtt_client_id, mac),
+        username: device_config.mqtt_username.clone(),
+        password: device_config.mqtt_password.clone(),
+        ca_cert: device_config.mqtt_ca_cert.clone(),
+        client_cert: device_config.mqtt_client_cert.clone(),
+        client_key: device_config.mqtt_client_key.clone(),
+        keepalive: device_config.mqtt_keepalive(),
+        clean_session: device_config
+            .mqtt_clean_session
+            .unwrap_or(MQTT_CLEAN_SESSION),
+        reconnect_timeout: device_config.mqtt_reconnect_timeout(),
+        subscriptions: vec![
+            "test".to_string(),
+            format!("{}/#", idf_mqtt::command_topic(&topic_prefix)),
+        ],
+        topic: topic_prefix.clone(),
     };
+    let mqtt_link = idf_mqtt::MqttLink::default();
+    let mqtt_commands = mqtt_link.command_channel();
     let mqttclient = Arc::new(Mutex::new(idf_mqtt::mqtt_client(
-        MQTT_ADDR.to_string(),
-        vec!["test".to_string()],
-        Some(client_id),
-        "12panels".to_string(),
-        conf,
+        &mqtt_settings,
+        mqtt_link.clone(),
     )?));
+    let mqtt_mirrors =
+        idf_mqtt::MqttMirrors::start(&device_config.mqtt_mirror_addrs, &mqtt_settings);
+    let connectivity = Connectivity::new(
+        wifi.clone(),
+        wifi_init::WifiSettings {
+            networks: device_config.networks(),
+        },
+        mqtt_link,
+        mqtt_settings,
+        MQTT_RECONNECT_AFTER_FAILURES,
+    );
 
     let (tx, rx) = userial.split();
-    let aurora_arc_mutex = Arc::new(Mutex::new(Aurora::new(rx, tx, INVERTER_COMMS_TIMEOUT)?));
-    let inverters_arc_mutex = Arc::new(Mutex::new(vec![
-        AuroraInverter::new(2),
-        AuroraInverter::new(3),
-    ]));
-    let _poller = events::periodic_inverter_event(
-        inverters_arc_mutex,
-        aurora_arc_mutex,
+    let energy_baselines = energy_baseline::EnergyBaselines::load(default_nvs.clone())?;
+    let frame_log = frame_log::FrameLog::new(FRAME_LOG_LEN, boot_time);
+    #[cfg(feature = "aurora")]
+    let mut aurora_arc_mutex = None;
+    #[cfg(feature = "solax")]
+    let mut solax_arc_mutex = None;
+    let inverters: Vec<AuroraInverter> = match inverter_kind {
+        #[cfg(feature = "aurora")]
+        InverterKind::Aurora => {
+            let settings = AuroraSettings {
+                timeout: INVERTER_COMMS_TIMEOUT,
+                idle_gap: idle_gap_for_baud(rs485_baud),
+                collision_policy: ADDRESS_COLLISION_POLICY,
+                energy_function: ENERGY_FUNCTION,
+                request_gap: device_config.request_gap(),
+                float_order: device_config.aurora_float_order.unwrap_or_default(),
+                measurements: device_config.measurements(),
+            };
+            let aurora = Aurora::new(rx, tx, settings, frame_log.clone())?;
+            aurora_arc_mutex = Some(Arc::new(Mutex::new(aurora)));
+            device_config
+                .inverter_ids()
+                .iter()
+                .map(|id| {
+                    let mut inverter = AuroraInverter::new(*id);
+                    inverter.energy.set_baseline(energy_baselines.get(*id));
+                    inverter
+                })
+                .collect()
+        }
+        #[cfg(feature = "solax")]
+        InverterKind::Solax => {
+            solax_arc_mutex = Some(Arc::new(Mutex::new(SolaxX1Air::new(
+                rx,
+                tx,
+                SOLAX_COMMS_TIMEOUT,
+                SOLAX_REQUEST_DELAY,
+                match device_config.solax_framing() {
+                    SolaxFraming::Aa55 => Framing::Aa55,
+                    SolaxFraming::Modbus => Framing::ModbusRtu {
+                        address: SOLAX_MODBUS_ADDRESS,
+                        live_data_base: SOLAX_MODBUS_LIVE_DATA_BASE,
+                    },
+                },
+                frame_log.clone(),
+            ))));
+            vec![]
+        }
+        #[allow(unreachable_patterns)]
+        kind => {
+            log::error!("{:?} support not built in, nothing to poll", kind);
+            vec![]
+        }
+    };
+    let inverters_arc_mutex = Arc::new(Mutex::new(inverters));
+    let state = AppState {
+        inverters: inverters_arc_mutex,
+        #[cfg(feature = "aurora")]
+        aurora: aurora_arc_mutex,
+        #[cfg(feature = "solax")]
+        solax: solax_arc_mutex,
         mqttclient,
-        MQTT_FREQUENCY,
+        mqtt_mirrors,
+        lifecycle,
+        connectivity,
+        transceiver,
+        clock,
+        stats: Arc::new(Mutex::new(PollStats::default())),
+        history: Arc::new(Mutex::new(History::new(HISTORY_LEN))),
+        frame_log,
+        deadman: deadman::Deadman::new(
+            device_config.poll_interval(),
+            DEADMAN_POLL_MULTIPLE,
+            DEADMAN_MIN,
+        ),
+        #[cfg(feature = "aurora")]
+        site_power: Arc::new(Mutex::new(power_stats::PowerStats::new(SITE_POWER_WINDOW))),
+        energy_baselines: Arc::new(Mutex::new(energy_baselines)),
+        influx: device_config
+            .influx
+            .as_ref()
+            .map(|config| Arc::new(influx::InfluxSink::new(config))),
+        outbox: Arc::new(Mutex::new(outbox::Outbox::new(MQTT_OUTBOX_LEN))),
+        publish_cache: Arc::new(Mutex::new(publish_cache::PublishCache::new(
+            MQTT_DEDUP_REFRESH_CYCLES,
+            MQTT_DEDUP_EPSILON,
+        ))),
+        ha_discovery_sent: Arc::new(AtomicBool::new(false)),
+        topic_prefix,
+        device_node,
         boot_time,
+    };
+    events::init_backends(&state);
+    let _httpd = http_server::httpd(
+        state.clone(),
+        default_nvs.clone(),
+        &device_config.http_username,
+        &device_config.http_password,
     )?;
+    let led_state = state.clone();
+    let poll_interval = device_config.poll_interval();
+    info!("Polling every {:?}", poll_interval);
+    let poller = Arc::new(Mutex::new(events::periodic_inverter_event(
+        state.clone(),
+        poll_interval,
+    )?));
+    // boot probing is over, from here a cycle is due every poll interval
+    state.deadman.kick();
+    let _deadman = state.deadman.start();
+    let _commands =
+        events::mqtt_command_listener(state, mqtt_commands, poller, default_nvs.clone());
 
+    let mut tick: u32 = 0;
     loop {
-        led.set_color(LedState::NC, LedState::NC, LedState::On)?;
-        thread::sleep(Duration::from_millis(500));
-        led.set_color(LedState::NC, LedState::NC, LedState::Off)?;
-        thread::sleep(Duration::from_millis(500));
+        // a trial OTA image has proven it can still be reached
+        if led_state.connectivity.is_up() {
+            boot_guard.confirm();
+        }
+        led.set_status(led_status(&led_state), tick)?;
+        tick = tick.wrapping_add(1);
+        thread::sleep(led_strip::STATUS_TICK);
+    }
+}
+
+// network first, an inverter fault can't be reported without it anyway
+fn led_status(state: &AppState) -> LedStatus {
+    if !state.connectivity.wifi_up() {
+        LedStatus::WifiDown
+    } else if !state.connectivity.mqtt_up() {
+        LedStatus::MqttDown
+    } else if state
+        .transceiver
+        .lock()
+        .map(|transceiver| transceiver.failing())
+        .unwrap_or(false)
+    {
+        LedStatus::InverterFault
+    } else {
+        LedStatus::Healthy
+    }
+}
+
+// configured baud if the selected protocol supports it, else the protocol default
+fn rs485_baud(kind: InverterKind, configured: Option<u32>) -> u32 {
+    let (supported, default): (&[u32], u32) = match kind {
+        #[cfg(feature = "solax")]
+        InverterKind::Solax => (&solax_x1_air::SUPPORTED_BAUDS, SOLAX_BAUD),
+        #[cfg(feature = "aurora")]
+        InverterKind::Aurora => (&aurora::SUPPORTED_BAUDS, RS485_BAUD),
+        #[allow(unreachable_patterns)]
+        _ => (&[], RS485_BAUD),
+    };
+    match configured {
+        Some(baud) if supported.contains(&baud) => baud,
+        Some(baud) => {
+            info!("RS485 baud {} not supported, using {}", baud, default);
+            default
+        }
+        None => default,
     }
 }
@@ -0,0 +1,35 @@
+use crate::backend::MqttMessage;
+use log::info;
+
+// Latest message per topic held while the broker is unreachable, oldest dropped past capacity
+pub struct Outbox {
+    messages: Vec<MqttMessage>,
+    capacity: usize,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            messages: vec![],
+            capacity,
+        }
+    }
+
+    // a newer value replaces the held one for its topic
+    pub fn hold(&mut self, messages: Vec<MqttMessage>) {
+        for message in messages {
+            self.messages.retain(|held| held.topic != message.topic);
+            self.messages.push(message);
+        }
+        if self.messages.len() > self.capacity {
+            let dropped = self.messages.len() - self.capacity;
+            self.messages.drain(..dropped);
+            info!("MQTT outbox full, dropped {} oldest messages", dropped);
+        }
+    }
+
+    // oldest first
+    pub fn take(&mut self) -> Vec<MqttMessage> {
+        std::mem::take(&mut self.messages)
+    }
+}
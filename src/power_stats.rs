@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+
+// Running min/max/mean of site power over one window, constant memory whatever the poll rate
+pub struct PowerStats {
+    // None resets at local midnight once SNTP has synced
+    window: Option<Duration>,
+    period_start: Instant,
+    day: Option<i64>,
+    min: f32,
+    max: f32,
+    sum: f64,
+    count: u32,
+}
+
+impl PowerStats {
+    pub fn new(window: Option<Duration>) -> Self {
+        Self {
+            window,
+            period_start: Instant::now(),
+            day: None,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    // local_secs is seconds since 2000 in local time, None before SNTP sync
+    pub fn push(&mut self, power: f32, local_secs: Option<i64>) {
+        if self.period_over(local_secs) {
+            self.reset();
+        }
+        self.min = self.min.min(power);
+        self.max = self.max.max(power);
+        self.sum += power as f64;
+        self.count += 1;
+    }
+
+    // (min, max, avg), None until the window has a sample
+    pub fn summary(&self) -> Option<(f32, f32, f32)> {
+        if self.count == 0 {
+            return None;
+        }
+        Some((self.min, self.max, (self.sum / self.count as f64) as f32))
+    }
+
+    fn period_over(&mut self, local_secs: Option<i64>) -> bool {
+        match self.window {
+            Some(window) => self.period_start.elapsed() >= window,
+            None => {
+                // samples from before the first sync count towards the day it lands in
+                let day = match local_secs {
+                    Some(secs) => secs.div_euclid(86_400),
+                    None => return false,
+                };
+                let over = matches!(self.day, Some(previous) if previous != day);
+                self.day = Some(day);
+                over
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.period_start = Instant::now();
+        self.min = f32::INFINITY;
+        self.max = f32::NEG_INFINITY;
+        self.sum = 0.0;
+        self.count = 0;
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+// Last payload per topic so unchanged values aren't sent every cycle
+pub struct PublishCache {
+    last: HashMap<String, Sent>,
+    // cycles a value may be skipped before it is sent again anyway, 0 disables the cache
+    refresh_cycles: u32,
+    // numeric payloads closer than this count as unchanged
+    epsilon: f64,
+}
+
+struct Sent {
+    payload: String,
+    skipped: u32,
+}
+
+impl PublishCache {
+    pub fn new(refresh_cycles: u32, epsilon: f64) -> Self {
+        Self {
+            last: HashMap::new(),
+            refresh_cycles,
+            epsilon,
+        }
+    }
+
+    // true when payload should go out, counts the skip otherwise
+    pub fn should_publish(&mut self, topic: &str, payload: &str) -> bool {
+        if self.refresh_cycles == 0 {
+            return true;
+        }
+        let epsilon = self.epsilon;
+        match self.last.get_mut(topic) {
+            Some(sent)
+                if sent.skipped < self.refresh_cycles && same(&sent.payload, payload, epsilon) =>
+            {
+                sent.skipped += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    // only delivered values are remembered, a failed publish is retried next cycle
+    pub fn published(&mut self, topic: &str, payload: &str) {
+        if self.refresh_cycles == 0 {
+            return;
+        }
+        self.last.insert(
+            topic.to_string(),
+            Sent {
+                payload: payload.to_string(),
+                skipped: 0,
+            },
+        );
+    }
+
+    // after a broker reconnect everything is sent again
+    pub fn clear(&mut self) {
+        self.last.clear();
+    }
+}
+
+fn same(previous: &str, payload: &str, epsilon: f64) -> bool {
+    match (previous.parse::<f64>(), payload.parse::<f64>()) {
+        (Ok(a), Ok(b)) => (a - b).abs() < epsilon,
+        _ => previous == payload,
+    }
+}
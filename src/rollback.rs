@@ -0,0 +1,54 @@
+use esp_idf_sys::{
+    esp_ota_get_running_partition, esp_ota_get_state_partition, esp_ota_img_states_t,
+    esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY, esp_ota_mark_app_invalid_rollback_and_reboot,
+    esp_ota_mark_app_valid_cancel_rollback, ESP_OK,
+};
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// First boot of a new OTA image, the bootloader reverts to the previous slot on any reset
+// until confirm() runs, needs CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE
+pub struct BootGuard {
+    pending: Arc<AtomicBool>,
+}
+
+impl BootGuard {
+    // no deadline unless the running image is still on trial
+    pub fn start(timeout: Duration) -> Self {
+        let pending = Arc::new(AtomicBool::new(pending_verify()));
+        if pending.load(Ordering::Relaxed) {
+            info!("New firmware on trial, confirming once WiFi and MQTT are up");
+            let deadline = pending.clone();
+            thread::spawn(move || {
+                thread::sleep(timeout);
+                if deadline.load(Ordering::Relaxed) {
+                    warn!("Firmware not confirmed within {:?}, rolling back", timeout);
+                    unsafe { esp_ota_mark_app_invalid_rollback_and_reboot() };
+                }
+            });
+        }
+        Self { pending }
+    }
+
+    // safe to call every loop, only the first call on a trial image does anything
+    pub fn confirm(&self) {
+        if !self.pending.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        match unsafe { esp_ota_mark_app_valid_cancel_rollback() } {
+            ESP_OK => info!("Firmware confirmed, rollback cancelled"),
+            e => warn!("Firmware confirm failed, esp error {}", e),
+        }
+    }
+}
+
+fn pending_verify() -> bool {
+    let mut state: esp_ota_img_states_t = 0;
+    let result =
+        unsafe { esp_ota_get_state_partition(esp_ota_get_running_partition(), &mut state) };
+    // the factory app has no OTA state
+    result == ESP_OK && state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+}
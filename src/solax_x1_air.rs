@@ -1,15 +1,38 @@
+#![allow(dead_code)]
+
+use crate::backend::{Importance, MqttMessage};
+use crate::error::InverterError;
+use crate::frame_log::{Direction, FrameLog};
+use crate::proto::modbus;
+pub use crate::proto::solax::*;
+use crate::SOLAX_RAW_CONFIG;
 use anyhow::*;
-use byteorder::{BigEndian, ByteOrder};
-use embedded_hal_0_2::serial::{Read, Write};
-use esp_idf_hal::serial::{Rx, Tx};
-use esp_idf_hal::{
-    gpio::{Gpio18, Gpio19, Unknown},
-    serial::{Serial, UART1},
-};
+use embedded_hal::serial::{Read, Write};
+use esp_idf_hal::serial::{Rx, Tx, UART1};
+use log::{debug, error, info, warn};
 use nb::block;
 use serde::Serialize;
 use std::result::Result::Ok;
-use std::{thread, time::Duration, u16};
+use std::{
+    thread,
+    time::{Duration, Instant},
+    u16,
+};
+
+// the X1 Air only talks at 9600
+pub const SUPPORTED_BAUDS: [u32; 1] = [9_600];
+
+// FreeRTOS tick, no point polling the UART faster
+const RX_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// tries per init query before the bring up is abandoned
+const QUERY_ATTEMPTS: u32 = 3;
+
+// Modbus-RTU slave address and first live data input register, the map varies by firmware
+#[derive(Debug, Copy, Clone)]
+pub enum Framing {
+    Aa55,
+    ModbusRtu { address: u8, live_data_base: u16 },
+}
 
 #[derive(Debug, Serialize)]
 pub enum Status {
@@ -25,579 +48,368 @@ pub struct SolaxX1Air {
     rx: Rx<UART1>,
     pub status: Status,
     pub serial: Vec<u8>,
+    timeout: Duration,
+    request_delay: Duration,
+    framing: Framing,
+    frame_log: FrameLog,
 }
 
 impl SolaxX1Air {
-    pub fn new(port: Serial<UART1, Gpio19<Unknown>, Gpio18<Unknown>>) -> Self {
-        let (tx, rx) = port.split();
+    pub fn new(
+        rx: Rx<UART1>,
+        tx: Tx<UART1>,
+        timeout: Duration,
+        request_delay: Duration,
+        framing: Framing,
+        frame_log: FrameLog,
+    ) -> Self {
         Self {
             data: Data::default(),
             status: Status::Offline,
             serial: vec![0],
             rx,
             tx,
+            timeout,
+            request_delay,
+            framing,
+            frame_log,
         }
     }
     pub fn init_inverter(&mut self) -> anyhow::Result<()> {
-        let mut status_counter = 0;
-        let delay = 300;
+        // no registration or config queries over Modbus, answering is enough
+        if let Framing::ModbusRtu { .. } = self.framing {
+            self.poll_data()?;
+            info!("Solax answering over Modbus-RTU");
+            return Ok(());
+        }
         if let std::result::Result::Ok(response) = self.send_and_recv(&send_broadcast_message()) {
-            println!("Sent register response back to inverter");
-            if self
-                .send_and_recv(&register_inverter(&response, 0xA))
-                .is_ok()
-            {
+            info!("Sent register response back to inverter");
+            let registered =
+                register_inverter(&response, 0xA).and_then(|request| self.send_and_recv(&request));
+            if registered.is_ok() {
                 self.status = Status::Registered
             }
         } else {
             self.status = Status::Unregistered;
         };
 
-        thread::sleep(Duration::from_millis(delay));
-        if self.send_and_recv(&request_config_data()).is_ok() {
-            status_counter += 1
-        }
-
-        thread::sleep(Duration::from_millis(delay));
-        if self.send_and_recv(&request_query_id_data()).is_ok() {
-            status_counter += 1
-        }
-
-        thread::sleep(Duration::from_millis(delay));
-        if self.send_and_recv(&request_live_data()).is_ok() {
-            status_counter += 1
-        }
-
-        if status_counter != 3 {
-            println!("Not enough inverter data recieved to populate modbus registers");
-            return Err(anyhow!(
-                "Not enough inverter data recieved to populate modbus registers"
-            ));
+        for (name, request) in [
+            ("config", request_config_data()),
+            ("ID", request_query_id_data()),
+            ("live data", request_live_data()),
+        ] {
+            thread::sleep(self.request_delay);
+            self.query(name, &request)?;
         }
-        println!("Enough inverter data to populate modbus registers has been received");
+        info!("Enough inverter data to populate modbus registers has been received");
         self.status = Status::Online;
         Ok(())
     }
+    // a cold inverter often misses the first request or two
+    fn query(&mut self, name: &str, request: &[u8]) -> anyhow::Result<()> {
+        let mut last = InverterError::Timeout;
+        for attempt in 1..=QUERY_ATTEMPTS {
+            match self.send_and_recv(request) {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    debug!("Solax {} request attempt {} failed {}", name, attempt, e);
+                    last = e;
+                }
+            }
+            if attempt < QUERY_ATTEMPTS {
+                thread::sleep(self.request_delay);
+            }
+        }
+        error!("Solax {} request failed {} times", name, QUERY_ATTEMPTS);
+        // context keeps the InverterError downcastable for the poll error logging
+        Err(Error::new(last).context(format!(
+            "Solax {} request failed after {} attempts",
+            name, QUERY_ATTEMPTS
+        )))
+    }
     pub fn poll_data(&mut self) -> anyhow::Result<&Data> {
-        match self.send_and_recv(&request_live_data()) {
+        let polled = match self.framing {
+            Framing::Aa55 => self.send_and_recv(&request_live_data()).map(|_| ()),
+            Framing::ModbusRtu {
+                address,
+                live_data_base,
+            } => self.read_live_registers(address, live_data_base),
+        };
+        match polled {
             std::result::Result::Ok(_) => {
                 self.status = Status::Online;
                 Ok(&self.data)
             }
-            Err(_) => {
+            Err(e) => {
                 self.status = Status::Offline;
-                Err(anyhow!(
-                    "Bad response from inverter during live data request"
-                ))
+                Err(Error::new(e).context("Bad response from inverter during live data request"))
             }
         }
     }
-    fn send_and_recv(&mut self, tx: &[u8]) -> anyhow::Result<Vec<u8>> {
-        let mut response: Vec<u8> = vec![];
-        // clear rx buffer
-        self.flush()?;
-        println!("Gateway >> Solax X1 Air {:02X?}", tx);
-        if self.write_all(tx).is_err() {
-            self.status = Status::Offline;
-            return Err(anyhow!(
-                "Gateway >> Inverter RS485 message could not be sent - hardware failure?"
-            ));
-        };
+    pub fn is_online(&self) -> bool {
+        matches!(self.status, Status::Online)
+    }
+    // stale live data from before a drop doesn't count
+    pub fn is_faulted(&self) -> bool {
+        self.is_online() && self.data.livedata.is_faulted()
+    }
 
-        // timeout value from Solax protocol 1.7
-        thread::sleep(Duration::from_millis(500));
-        match self.waiting_data() {
-            Some(bytes) => {
-                if bytes < 5 {
-                    self.status = Status::Offline;
-                    return Err(anyhow!("No data received from RS485"));
+    // one topic per field, enums published by variant name
+    pub fn data_to_vec_mqtt_json(&self, topic_prefix: &str) -> Vec<MqttMessage> {
+        let mut mqtt_payload: Vec<MqttMessage> = vec![];
+        let mut sections = vec![
+            (
+                topic_prefix.to_string(),
+                serde_json::to_value(&self.data.livedata),
+            ),
+            (
+                topic_prefix.to_string(),
+                serde_json::to_value(&self.data.id),
+            ),
+            (
+                topic_prefix.to_string(),
+                serde_json::to_value(GridSettings::from(&self.data.config)),
+            ),
+        ];
+        // register names as the inverter reports them, for checking the scaling
+        if SOLAX_RAW_CONFIG {
+            sections.push((
+                format!("{}/raw", topic_prefix),
+                serde_json::to_value(&self.data.config),
+            ));
+        }
+        for (prefix, section) in sections.iter() {
+            match section {
+                Ok(serde_json::Value::Object(fields)) => {
+                    fields.iter().for_each(|(key, value)| {
+                        mqtt_payload.push(MqttMessage {
+                            topic: format!("{}/{}", prefix, key),
+                            payload: payload_string(value),
+                            importance: Importance::Normal,
+                        });
+                    });
                 }
+                Ok(_) => (),
+                Err(e) => info!("Solax serde error {:?}", e),
             }
-            None => {
+        }
+        mqtt_payload.push(MqttMessage {
+            topic: format!("{}/status", topic_prefix),
+            payload: format!("{:?}", self.status),
+            importance: Importance::Normal,
+        });
+        // alerting keys off this rather than matching every ErrorCode name
+        mqtt_payload.push(MqttMessage {
+            topic: format!("{}/faulted", topic_prefix),
+            payload: self.is_faulted().to_string(),
+            importance: Importance::Critical,
+        });
+        mqtt_payload
+    }
+
+    fn send_and_recv(&mut self, tx: &[u8]) -> Result<Vec<u8>, InverterError> {
+        // replies chained behind the previous one are still decoded
+        self.drain_pending()?;
+        debug!("Gateway >> Solax X1 Air {:02X?}", tx);
+        self.frame_log.record("solax", Direction::Tx, tx);
+        if let Err(e) = self.write_all(tx) {
+            self.status = Status::Offline;
+            error!("Gateway >> Inverter RS485 message could not be sent - hardware failure?");
+            return Err(e);
+        };
+
+        let frames = match self.read_frames(tx) {
+            Ok(frames) => frames,
+            Err(e) => {
                 self.status = Status::Offline;
-                return Err(anyhow!("Hardware error on RS485 port"));
+                return Err(e);
+            }
+        };
+        if frames.is_empty() {
+            self.status = Status::Offline;
+            return Err(InverterError::Timeout);
+        }
+        // late replies to earlier requests can queue ahead of ours on a busy bus
+        let mut response = None;
+        for frame in frames {
+            if response.is_none() && answers(tx, &frame) {
+                response = Some(frame);
+            } else if let Err(e) = self.decode_frame(&frame) {
+                debug!("Chained frame dropped {:?}", e);
             }
         }
+        let response = response.ok_or(InverterError::UnexpectedReply)?;
+        self.decode_frame(&response)?;
+        Ok(response)
+    }
 
-        // Read incomming data from serial port and create dynamically sized vector from the quanitity of data received.
-        self.read_all(&mut response)?;
-
-        println!("Gateway << Solax X1 Air {:02X?}", response);
-        if response[0] != 0xAA && response[1] != 0x55 {
+    // validates a single frame and stores whatever it carries
+    fn decode_frame(&mut self, response: &[u8]) -> Result<(), InverterError> {
+        debug!("Gateway << Solax X1 Air {:02X?}", response);
+        self.frame_log.record("solax", Direction::Rx, response);
+        if response.len() < MIN_FRAME_LEN {
+            self.flush()?;
+            debug!(
+                "Inverter RS485 message too short ({} bytes)",
+                response.len()
+            );
+            // read_frame gave up before the declared length arrived
+            return Err(InverterError::Timeout);
+        }
+        if !has_preamble(response) {
             // flush rx buffer
             self.flush()?;
-            return Err(anyhow!(
-                "Inverter RS485 message invalid (preamble incorrect)"
-            ));
+            return Err(InverterError::BadPreamble);
         }
 
-        if check_crc(&response).is_ok() {
-            println!("RX CRC ok")
+        if check_crc(response) {
+            debug!("RX CRC ok")
         } else {
-            return Err(anyhow!("Inverter CRC is invalid"));
+            return Err(InverterError::CrcMismatch);
         };
 
         if response[6] == 0x10 {
-            println!("Incomming RS485 data - Register ");
+            debug!("Incomming RS485 data - Register ");
             match response[7] {
                 0x80 => {
-                    println!("Inverter register request");
-                    return Ok(response);
+                    info!("Inverter register request");
+                    return Ok(());
                 }
                 0x81 => {
-                    println!("Inverter address confirmed");
-                    return Ok(response);
+                    info!("Inverter address confirmed");
+                    return Ok(());
                 }
                 0x82 => {
-                    println!("Inverter remove confirmed");
-                    return Ok(response);
+                    info!("Inverter remove confirmed");
+                    return Ok(());
                 }
                 _ => (),
             };
         };
         if response[6] == 0x11 {
-            println!("Incomming RS485 data - Read ");
+            debug!("Incomming RS485 data - Read ");
             match response[7] {
                 0x82 => {
-                    println!("Received response for query (live data)");
-                    self.data.livedata = LiveData::decode(&response);
-                    println!("{:#?}", self.data.livedata);
-                    return Ok(response);
+                    debug!("Received response for query (live data)");
+                    self.data.livedata = LiveData::decode(response)?;
+                    debug!("{:#?}", self.data.livedata);
+                    return Ok(());
                 }
                 0x83 => {
-                    println!("Received response for query (ID info)");
-                    self.data.id = QueryID::decode(&response);
-                    println!("{:#?}", self.data.id);
-                    return Ok(response);
+                    debug!("Received response for query (ID info)");
+                    self.data.id = QueryID::decode(response)?;
+                    debug!("{:#?}", self.data.id);
+                    return Ok(());
                 }
                 0x84 => {
-                    println!("Received response for query (config)");
-                    self.data.config = QueryConfig::decode(&response);
-                    println!("{:#?}", self.data.config);
-                    return Ok(response);
+                    debug!("Received response for query (config)");
+                    self.data.config = QueryConfig::decode(response)?;
+                    debug!("{:#?}", self.data.config);
+                    return Ok(());
                 }
                 _ => (),
             }
         };
 
         if response[6] == 0x12 {
-            println!("Incoming RS485 data - Write ");
+            debug!("Incoming RS485 data - Write ");
         };
 
         if response[6] == 0x13 {
-            println!("Incoming RS485 data - Execute ");
+            debug!("Incoming RS485 data - Execute ");
         };
-        println!(
+        warn!(
             "RS485 inverter response was not decoded by parsers {:02X?}",
             response
         );
-        Err(anyhow!("Bad data?"))
+        Err(InverterError::UnexpectedReply)
+    }
+
+    fn read_live_registers(&mut self, address: u8, base: u16) -> Result<(), InverterError> {
+        self.flush()?;
+        let request = modbus::read_input_registers(address, base, LIVE_DATA_REGISTERS);
+        debug!("Gateway >> Solax X1 Air {:02X?}", request);
+        self.frame_log.record("solax", Direction::Tx, &request);
+        self.write_all(&request)?;
+        let deadline = Instant::now() + self.timeout;
+        let mut buf: Vec<u8> = vec![];
+        while !modbus::reply_complete(address, LIVE_DATA_REGISTERS, &buf) {
+            if Instant::now() >= deadline {
+                break;
+            }
+            if self.rx.count().map_err(InverterError::hardware)? == 0 {
+                thread::sleep(RX_POLL_INTERVAL);
+                continue;
+            }
+            self.read_all(&mut buf)?;
+        }
+        debug!("Gateway << Solax X1 Air {:02X?}", buf);
+        self.frame_log.record("solax", Direction::Rx, &buf);
+        let registers = modbus::parse_registers(address, LIVE_DATA_REGISTERS, &buf)?;
+        self.data.livedata = LiveData::from_registers(registers)?;
+        debug!("{:#?}", self.data.livedata);
+        Ok(())
     }
 
-    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<u8> {
-        let bytes = self.rx.count()?;
+    // decodes any whole frames already buffered, partial leftovers are dropped
+    fn drain_pending(&mut self) -> Result<(), InverterError> {
+        let mut buf: Vec<u8> = vec![];
+        self.read_all(&mut buf)?;
+        for frame in split_frames(&buf) {
+            if let Err(e) = self.decode_frame(&frame) {
+                debug!("Pending frame dropped {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<u8, InverterError> {
+        let bytes = self.rx.count().map_err(InverterError::hardware)?;
 
-        println!("RX {} bytes to be read", bytes);
-        while self.rx.count()? > 0 {
+        debug!("RX {} bytes to be read", bytes);
+        while self.rx.count().map_err(InverterError::hardware)? > 0 {
             if let Ok(byte) = block!(self.rx.read()) {
-                // check if two inverter messages are chained in the buffer
-                if buf.len() > 5 && byte == 0x55u8 && buf.last() == Some(&0xaa_u8) {
-                    buf.pop(); // remove last 0xaa
-                    break;
-                };
                 buf.push(byte);
             }
         }
         Ok(bytes)
     }
-    fn waiting_data(&mut self) -> Option<u8> {
-        match self.rx.count() {
-            Ok(byte_count) => Some(byte_count),
-            Err(_) => None,
+    // every complete frame received until one answers tx or the timeout passes
+    fn read_frames(&mut self, tx: &[u8]) -> Result<Vec<Vec<u8>>, InverterError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut buf: Vec<u8> = vec![];
+        while Instant::now() < deadline {
+            if self.rx.count().map_err(InverterError::hardware)? == 0 {
+                thread::sleep(RX_POLL_INTERVAL);
+                continue;
+            }
+            self.read_all(&mut buf)?;
+            let frames = split_frames(&buf);
+            if frames.iter().any(|frame| answers(tx, frame)) {
+                return Ok(frames);
+            }
+        }
+        if !buf.is_empty() {
+            debug!("RX {:02X?} without a reply to the request", buf);
         }
+        Ok(split_frames(&buf))
     }
-    fn write_all(&mut self, bytevec: &[u8]) -> anyhow::Result<()> {
+    fn write_all(&mut self, bytevec: &[u8]) -> Result<(), InverterError> {
         for byte in bytevec {
-            block!(self.tx.write(*byte))?;
+            block!(self.tx.write(*byte)).map_err(InverterError::hardware)?;
         }
         Ok(())
     }
-    fn flush(&mut self) -> anyhow::Result<()> {
+    fn flush(&mut self) -> Result<(), InverterError> {
         let mut dump: Vec<u8> = vec![];
         self.read_all(&mut dump)?;
         Ok(())
     }
 }
-#[derive(Debug, Default, Serialize)]
-pub struct Data {
-    pub livedata: LiveData,
-    pub id: QueryID,
-    pub config: QueryConfig,
-}
-
-// https://github.com/syssi/esphome-modbus-solax-x1
-#[allow(non_snake_case)]
-#[allow(clippy::upper_case_acronyms)]
-#[allow(non_camel_case_types)]
-#[derive(Debug, Serialize)]
-pub enum Safety {
-    VDE0126,
-    VDE4105,
-    AS4777,
-    G98,
-    C10_11,
-    TOR,
-    EN50438_NL,
-    Denmark2019_W,
-    CEB,
-    Cyprus2019,
-    cNRS097_2_1,
-    VDE0126_Greece,
-    UTE_C15_712_Fr,
-    IEC61727,
-    G99,
-    CQC,
-    VDE0126_Greece_is,
-    C15_712_Fr_island_50,
-    C15_712_Fr_island_60,
-    Guyana,
-    MEA_Thailand,
-    PEA_Thailand,
-    cNewZealand,
-    cIreland,
-    cCE10_21,
-    cRD1699,
-    EN50438_Sweden,
-    EN50549_PL,
-    Czech_PPDS,
-    EN50438_Norway,
-    EN50438_Portug,
-    cCQC_WideRange,
-    BRAZIL,
-    EN50438_CEZ,
-    IEC_Chile,
-    Sri_Lanka,
-    BRAZIL_240,
-    EN50549_SK,
-    EN50549_EU,
-    G98_NI,
-    Denmark2019_E,
-    Unknown,
-}
-impl Default for Safety {
-    fn default() -> Self {
-        Safety::Unknown
-    }
-}
-#[derive(Debug, Serialize)]
-pub enum RunMode {
-    Wait,
-    Check,
-    Normal,
-    Fault,
-    PermanentFault,
-    UpdateMode,
-    Unknown,
-}
-
-impl Default for RunMode {
-    fn default() -> Self {
-        RunMode::Unknown
-    }
-}
-#[derive(Debug, Serialize)]
-pub enum ErrorCode {
-    None,
-    MainsLostFault,
-    GridVoltFault,
-    GridFreqFault,
-    PvVoltFault,
-    IsolationFault,
-    TemperatureOverFault,
-    FanFault,
-    OtherDeviceFault,
-    Unknown,
-}
-
-impl Default for ErrorCode {
-    fn default() -> Self {
-        ErrorCode::Unknown
-    }
-}
-#[derive(Debug, Default, Serialize)]
-pub struct LiveData {
-    pub temperature: u16,
-    pub energy_today: u16,
-    pub dc1_voltage: u16,
-    pub dc2_voltage: u16,
-    pub dc1_current: u16,
-    pub dc2_current: u16,
-    pub current: u16,
-    pub voltage: u16,
-    pub frequency: u16,
-    pub active_power: u16,
-    pub import_active: u32,
-    pub runtime_total: u32,
-    pub run_mode: RunMode,
-    pub error_code: ErrorCode,
-}
-impl LiveData {
-    pub fn decode(response: &[u8]) -> LiveData {
-        Self {
-            temperature: BigEndian::read_u16(&response[9..]),
-            energy_today: BigEndian::read_u16(&response[11..]),
-            dc1_voltage: BigEndian::read_u16(&response[13..]),
-            dc2_voltage: BigEndian::read_u16(&response[15..]),
-            dc1_current: BigEndian::read_u16(&response[17..]),
-            dc2_current: BigEndian::read_u16(&response[19..]),
-            current: BigEndian::read_u16(&response[21..]),
-            voltage: BigEndian::read_u16(&response[23..]),
-            frequency: BigEndian::read_u16(&response[25..]),
-            active_power: BigEndian::read_u16(&response[27..]),
-            import_active: BigEndian::read_u32(&response[31..]),
-            runtime_total: BigEndian::read_u32(&response[35..]),
-            run_mode: match BigEndian::read_u16(&response[39..]) {
-                0 => RunMode::Wait,
-                1 => RunMode::Check,
-                2 => RunMode::Normal,
-                3 => RunMode::Fault,
-                4 => RunMode::PermanentFault,
-                5 => RunMode::UpdateMode,
-                _ => RunMode::Unknown,
-            },
-            error_code: match BigEndian::read_u32(&response[55..]) {
-                0 => ErrorCode::None,
-                1 => ErrorCode::MainsLostFault,
-                2 => ErrorCode::GridVoltFault,
-                3 => ErrorCode::GridFreqFault,
-                4 => ErrorCode::PvVoltFault,
-                5 => ErrorCode::IsolationFault,
-                6 => ErrorCode::TemperatureOverFault,
-                7 => ErrorCode::FanFault,
-                8 => ErrorCode::OtherDeviceFault,
-                _ => ErrorCode::Unknown,
-            },
-        }
-    }
-}
-
-#[derive(Debug, Default, Serialize)]
-pub struct QueryID {
-    pub inverter_phases: u8,
-    pub bus_power: String,
-    pub firmware_version: String,
-    pub module_name: String,
-    pub factory_name: String,
-    pub serial_number: String,
-    pub rated_bus_voltage: String,
-}
-
-impl QueryID {
-    pub fn decode(response: &[u8]) -> QueryID {
-        Self {
-            inverter_phases: response[9],
-            bus_power: String::from_utf8_lossy(&response[10..15]).to_string(),
-            firmware_version: String::from_utf8_lossy(&response[16..20]).to_string(),
-            module_name: String::from_utf8_lossy(&response[21..34]).to_string(),
-            factory_name: String::from_utf8_lossy(&response[35..48]).to_string(),
-            serial_number: String::from_utf8_lossy(&response[49..62]).to_string(),
-            rated_bus_voltage: String::from_utf8_lossy(&response[63..66]).to_string(),
-        }
-    }
-}
-
-#[allow(non_snake_case)]
-#[allow(non_camel_case_types)]
-#[derive(Debug, Default, Serialize)]
-pub struct QueryConfig {
-    pub wVpvStart: u16,
-    pub wTimeStart: u16,
-    pub wVacMinProtect: u16,
-    pub wVacMaxProtect: u16,
-    pub wFacMinProtect: u16,
-    pub wFacMaxProtect: u16,
-    pub wDciLimits: u16,
-    pub wGrid10MinAvgProtect: u16,
-    pub wVacMinSlowProtect: u16,
-    pub wVacMaxSlowProtect: u16,
-    pub wFacMinSlowProtect: u16,
-    pub wFacMaxSlowProtect: u16,
-    pub wSafety: Safety,
-    pub wPowerfactor_mode: u8,
-    pub wPowerfactor_data: u8,
-    pub wUpperLimit: u8,
-    pub wLowerLimit: u8,
-    pub wPowerLow: u8,
-    pub wPowerUp: u8,
-    pub Qpower_set: u16,
-    pub WFreqSetPoint: u16,
-    pub WFreqDroopRate: u16,
-    pub QuVupRate: u16,
-    pub QuVlowRate: u16,
-    pub WPowerLimitsPercent: u16,
-    pub WWgra: u16,
-    pub wWv2: u16,
-    pub wWv3: u16,
-    pub wWv4: u16,
-    pub wQurangeV1: u16,
-    pub wQurangeV4: u16,
-    pub BVoltPowerLimtit: u16,
-    pub WPowerManagerEnable: u16,
-    pub WGlobalSeachMPPTStrartFlg: u16,
-    pub WFrqProtectRestrictive: u16,
-    pub WQuDelayTimer: u16,
-    pub WFreqActivePowerDelayTimer: u16,
-}
-
-impl QueryConfig {
-    pub fn decode(response: &[u8]) -> QueryConfig {
-        Self {
-            wVpvStart: BigEndian::read_u16(&response[9..]),
-            wTimeStart: BigEndian::read_u16(&response[11..]),
-            wVacMinProtect: BigEndian::read_u16(&response[13..]),
-            wVacMaxProtect: BigEndian::read_u16(&response[15..]),
-            wFacMinProtect: BigEndian::read_u16(&response[17..]),
-            wFacMaxProtect: BigEndian::read_u16(&response[19..]),
-            wDciLimits: BigEndian::read_u16(&response[21..]),
-            wGrid10MinAvgProtect: BigEndian::read_u16(&response[23..]),
-            wVacMinSlowProtect: BigEndian::read_u16(&response[25..]),
-            wVacMaxSlowProtect: BigEndian::read_u16(&response[27..]),
-            wFacMinSlowProtect: BigEndian::read_u16(&response[29..]),
-            wFacMaxSlowProtect: BigEndian::read_u16(&response[31..]),
-            wSafety: match BigEndian::read_u16(&response[33..]) {
-                0 => Safety::VDE0126,
-                1 => Safety::VDE4105,
-                2 => Safety::AS4777,
-                3 => Safety::G98,
-                4 => Safety::C10_11,
-                5 => Safety::TOR,
-                6 => Safety::EN50438_NL,
-                7 => Safety::Denmark2019_W,
-                8 => Safety::CEB,
-                9 => Safety::Cyprus2019,
-                10 => Safety::cNRS097_2_1,
-                11 => Safety::VDE0126_Greece,
-                12 => Safety::UTE_C15_712_Fr,
-                13 => Safety::IEC61727,
-                14 => Safety::G99,
-                15 => Safety::CQC,
-                16 => Safety::VDE0126_Greece_is,
-                17 => Safety::C15_712_Fr_island_50,
-                18 => Safety::C15_712_Fr_island_60,
-                19 => Safety::Guyana,
-                20 => Safety::MEA_Thailand,
-                21 => Safety::PEA_Thailand,
-                22 => Safety::cNewZealand,
-                23 => Safety::cIreland,
-                24 => Safety::cCE10_21,
-                25 => Safety::cRD1699,
-                26 => Safety::EN50438_Sweden,
-                27 => Safety::EN50549_PL,
-                28 => Safety::Czech_PPDS,
-                29 => Safety::EN50438_Norway,
-                30 => Safety::EN50438_Portug,
-                31 => Safety::cCQC_WideRange,
-                32 => Safety::BRAZIL,
-                33 => Safety::EN50438_CEZ,
-                34 => Safety::IEC_Chile,
-                35 => Safety::Sri_Lanka,
-                36 => Safety::BRAZIL_240,
-                37 => Safety::EN50549_SK,
-                38 => Safety::EN50549_EU,
-                39 => Safety::G98_NI,
-                40 => Safety::Denmark2019_E,
-                _ => Safety::Unknown,
-            },
-            wPowerfactor_mode: response[35],
-            wPowerfactor_data: response[36],
-            wUpperLimit: response[37],
-            wLowerLimit: response[38],
-            wPowerLow: response[39],
-            wPowerUp: response[40],
-            Qpower_set: BigEndian::read_u16(&response[41..]),
-            WFreqSetPoint: BigEndian::read_u16(&response[43..]),
-            WFreqDroopRate: BigEndian::read_u16(&response[45..]),
-            QuVupRate: BigEndian::read_u16(&response[47..]),
-            QuVlowRate: BigEndian::read_u16(&response[49..]),
-            WPowerLimitsPercent: BigEndian::read_u16(&response[51..]),
-            WWgra: BigEndian::read_u16(&response[53..]),
-            wWv2: BigEndian::read_u16(&response[55..]),
-            wWv3: BigEndian::read_u16(&response[57..]),
-            wWv4: BigEndian::read_u16(&response[59..]),
-            wQurangeV1: BigEndian::read_u16(&response[61..]),
-            wQurangeV4: BigEndian::read_u16(&response[63..]),
-            BVoltPowerLimtit: BigEndian::read_u16(&response[65..]),
-            WPowerManagerEnable: BigEndian::read_u16(&response[67..]),
-            WGlobalSeachMPPTStrartFlg: BigEndian::read_u16(&response[69..]),
-            WFrqProtectRestrictive: BigEndian::read_u16(&response[71..]),
-            WQuDelayTimer: BigEndian::read_u16(&response[73..]),
-            WFreqActivePowerDelayTimer: BigEndian::read_u16(&response[75..]),
-        }
-    }
-}
-
-fn send_broadcast_message() -> Vec<u8> {
-    let mut request: Vec<u8> = vec![0xAA, 0x55, 0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00];
-    request.extend(calc_partial_crc(&request));
-    request
-}
-
-fn register_inverter(payload: &[u8], inverter_address: u8) -> Vec<u8> {
-    let serial_number = extract_serial_number(payload);
-    println!(
-        "Discovered serial number {:?}",
-        String::from_utf8_lossy(&serial_number)
-    );
-    let mut message: Vec<u8> = vec![0xAA, 0x55, 0x00, 0x00, 0x00, 0x00, 0x10, 0x01, 0x0F];
-    message.extend(serial_number);
-    message.extend([inverter_address]);
-    let crc: Vec<u8> = calc_partial_crc(&message);
-    message.iter().chain(&crc).copied().collect()
-    // output
-}
-
-fn request_live_data() -> Vec<u8> {
-    let mut request: Vec<u8> = vec![0xAA, 0x55, 0x01, 0x00, 0x00, 0x0A, 0x11, 0x02, 0x00];
-    request.extend(calc_partial_crc(&request));
-    request
-}
-
-fn request_query_id_data() -> Vec<u8> {
-    let mut request: Vec<u8> = vec![0xAA, 0x55, 0x01, 0x00, 0x00, 0x0A, 0x11, 0x03, 0x00];
-    request.extend(calc_partial_crc(&request));
-    request
-}
-
-fn request_config_data() -> Vec<u8> {
-    let mut request: Vec<u8> = vec![0xAA, 0x55, 0x01, 0x00, 0x00, 0x0A, 0x11, 0x04, 0x00];
-    request.extend(calc_partial_crc(&request));
-    request
-}
-
-fn extract_serial_number(payload: &[u8]) -> Vec<u8> {
-    payload[9..23].to_vec()
-}
-
-fn extract_crc(payload: &[u8]) -> Vec<u8> {
-    payload.iter().rev().take(2).rev().copied().collect()
-}
-
-fn calc_partial_crc(payload: &Vec<u8>) -> Vec<u8> {
-    let mut val: u16 = 0;
-    for i in payload {
-        val += *i as u16;
-    }
-    vec![(val >> 8) as u8, val as u8]
-}
-
-fn check_crc(i_payload: &Vec<u8>) -> Result<()> {
-    let mut payload = i_payload.to_owned();
-    payload.pop().unwrap();
-    payload.pop().unwrap();
-    let crc_should_be = calc_partial_crc(&payload);
-    let i_crc: Vec<u8> = extract_crc(i_payload);
-    match crc_should_be == i_crc {
-        true => Ok(()),
-        false => Err(anyhow!("CRC invalid")),
+// strings unquoted so enum names arrive as plain text
+fn payload_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
     }
 }
@@ -0,0 +1,69 @@
+#[cfg(feature = "aurora")]
+use crate::aurora::Aurora;
+use crate::clock::Clock;
+use crate::connectivity::Connectivity;
+use crate::deadman::Deadman;
+use crate::energy_baseline::EnergyBaselines;
+use crate::frame_log::FrameLog;
+use crate::history::History;
+use crate::idf_mqtt::{MqttClientType, MqttMirrors};
+use crate::influx::InfluxSink;
+use crate::inverter::AuroraInverter;
+use crate::lifecycle::LifecycleTracker;
+use crate::outbox::Outbox;
+#[cfg(feature = "aurora")]
+use crate::power_stats::PowerStats;
+use crate::publish_cache::PublishCache;
+#[cfg(feature = "solax")]
+use crate::solax_x1_air::SolaxX1Air;
+use crate::transceiver::Transceiver;
+use serde::Serialize;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Debug, Copy, Clone, Default, Serialize)]
+pub struct PollStats {
+    pub cycles: u32,
+    pub last_cycle_ms: u32,
+}
+
+// Handles shared between the poll timer and the HTTP server
+#[derive(Clone)]
+pub struct AppState {
+    pub inverters: Arc<Mutex<Vec<AuroraInverter>>>,
+    // only the backend selected by DeviceConfig::inverter owns the RS485 UART
+    #[cfg(feature = "aurora")]
+    pub aurora: Option<Arc<Mutex<Aurora>>>,
+    #[cfg(feature = "solax")]
+    pub solax: Option<Arc<Mutex<SolaxX1Air>>>,
+    pub mqttclient: Arc<Mutex<MqttClientType>>,
+    // copies of every publish, see events::publish_all
+    pub mqtt_mirrors: MqttMirrors,
+    pub lifecycle: Arc<Mutex<LifecycleTracker>>,
+    pub connectivity: Connectivity,
+    pub transceiver: Arc<Mutex<Transceiver>>,
+    pub clock: Arc<Clock>,
+    pub stats: Arc<Mutex<PollStats>>,
+    pub history: Arc<Mutex<History>>,
+    // <prefix>/site/power_min, _max and _avg
+    #[cfg(feature = "aurora")]
+    pub site_power: Arc<Mutex<PowerStats>>,
+    // shared with the RS485 driver, see GET /frames
+    pub frame_log: FrameLog,
+    // kicked by every completed poll cycle
+    pub deadman: Deadman,
+    pub energy_baselines: Arc<Mutex<EnergyBaselines>>,
+    // DeviceConfig::influx, written after each poll
+    pub influx: Option<Arc<InfluxSink>>,
+    // inverter messages not yet on the broker, flushed on the next publish
+    pub outbox: Arc<Mutex<Outbox>>,
+    // inverter values already on the broker, see events::publish_messages
+    pub publish_cache: Arc<Mutex<PublishCache>>,
+    pub ha_discovery_sent: Arc<AtomicBool>,
+    // MQTT_TOPIC_NAME plus the device node when one is set
+    pub topic_prefix: String,
+    // keeps HA unique ids apart between gateways
+    pub device_node: Option<String>,
+    pub boot_time: Instant,
+}
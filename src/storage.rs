@@ -0,0 +1,31 @@
+use embedded_svc::storage::RawStorage;
+use esp_idf_svc::nvs::EspDefaultNvs;
+use esp_idf_svc::nvs_storage::EspNvsStorage;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+
+// Small JSON blobs kept in their own NVS namespace
+pub fn open(default_nvs: Arc<EspDefaultNvs>, namespace: &str) -> anyhow::Result<EspNvsStorage> {
+    Ok(EspNvsStorage::new_default(default_nvs, namespace, true)?)
+}
+
+pub fn load<T: DeserializeOwned>(storage: &EspNvsStorage, key: &str) -> anyhow::Result<Option<T>> {
+    let len = match storage.len(key)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    let mut buf = vec![0u8; len];
+    match storage.get_raw(key, &mut buf)? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(bytes)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn store<T: Serialize>(
+    storage: &mut EspNvsStorage,
+    key: &str,
+    value: &T,
+) -> anyhow::Result<()> {
+    storage.put_raw(key, &serde_json::to_vec(value)?)?;
+    Ok(())
+}
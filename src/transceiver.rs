@@ -0,0 +1,104 @@
+use embedded_hal::digital::v2::OutputPin;
+use esp_idf_hal::gpio::{Gpio6, Output};
+use log::{info, warn};
+use std::thread;
+use std::time::Duration;
+
+// long enough for the transceiver supply to drop out
+const POWER_OFF_TIME: Duration = Duration::from_millis(200);
+// supply and bus biasing up before the first request after a wake
+const POWER_ON_SETTLE: Duration = Duration::from_millis(100);
+
+// RS485 transceiver supply on GPIO6, power cycled when it latches up
+pub struct Transceiver {
+    power: Gpio6<Output>,
+    hardware_errors: u32,
+    // consecutive hardware errors before a power cycle
+    threshold: u32,
+    // unpowered between bus uses rather than always on
+    low_power: bool,
+    // bus uses in progress, the poll task and a command can overlap
+    users: u32,
+}
+
+impl Transceiver {
+    pub fn new(mut power: Gpio6<Output>, threshold: u32, low_power: bool) -> anyhow::Result<Self> {
+        if low_power {
+            power.set_low()?;
+        } else {
+            power.set_high()?;
+        }
+        Ok(Self {
+            power,
+            hardware_errors: 0,
+            threshold,
+            low_power,
+            users: 0,
+        })
+    }
+
+    // before each bus use, a no-op unless low_power. The UART stays open, the drivers
+    // drain whatever the unpowered line picked up before their next request
+    pub fn wake(&mut self) -> anyhow::Result<()> {
+        if !self.low_power {
+            return Ok(());
+        }
+        if self.users == 0 {
+            self.power.set_high()?;
+            thread::sleep(POWER_ON_SETTLE);
+        }
+        self.users += 1;
+        Ok(())
+    }
+
+    // after each bus use, the last user powers it down
+    pub fn release(&mut self) -> anyhow::Result<()> {
+        if !self.low_power {
+            return Ok(());
+        }
+        self.users = self.users.saturating_sub(1);
+        if self.users == 0 {
+            self.power.set_low()?;
+        }
+        Ok(())
+    }
+
+    // any other outcome breaks the run of hardware errors
+    pub fn record(&mut self, hardware_error: bool) -> anyhow::Result<()> {
+        if !hardware_error {
+            self.hardware_errors = 0;
+            return Ok(());
+        }
+        self.hardware_errors += 1;
+        if self.hardware_errors >= self.threshold {
+            warn!(
+                "{} consecutive RS485 hardware errors, power cycling the transceiver",
+                self.hardware_errors
+            );
+            self.hardware_errors = 0;
+            self.power_cycle()?;
+        }
+        Ok(())
+    }
+
+    // the last poll hit a UART error, cleared by any other outcome
+    pub fn failing(&self) -> bool {
+        self.hardware_errors > 0
+    }
+
+    pub fn power_cycle(&mut self) -> anyhow::Result<()> {
+        self.power.set_low()?;
+        thread::sleep(POWER_OFF_TIME);
+        self.power.set_high()?;
+        info!("RS485 transceiver powered back up");
+        Ok(())
+    }
+}
+
+impl Drop for Transceiver {
+    fn drop(&mut self) {
+        if self.power.set_low().is_err() {
+            warn!("RS485 transceiver power down failed");
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use embedded_svc::ipv4::{self};
 use embedded_svc::ping::Ping;
 use embedded_svc::wifi::*;
@@ -7,57 +7,74 @@ use esp_idf_svc::nvs::EspDefaultNvs;
 use esp_idf_svc::ping;
 use esp_idf_svc::sysloop::EspSysLoopStack;
 use esp_idf_svc::wifi::EspWifi;
-use log::info;
+use log::{info, warn};
 use std::sync::Arc;
 use std::time::Duration;
 
+// runs inside the poll timer, keep it short of the poll interval
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(8);
+
+// Owned copy of the client credentials for reconnects, (ssid, pass) pairs
+#[derive(Debug, Clone)]
+pub struct WifiSettings {
+    pub networks: Vec<(String, String)>,
+}
+
+// Which side of the radio came up
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WifiMode {
+    Client,
+    // configured AP unreachable, serving the config form on a SoftAP
+    AccessPoint,
+}
+
+#[allow(dead_code)]
 pub fn wifi(
     netif_stack: Arc<EspNetifStack>,
     sys_loop_stack: Arc<EspSysLoopStack>,
     default_nvs: Arc<EspDefaultNvs>,
     ssid: &str,
     pass: &str,
-) -> Result<Box<EspWifi>> {
+    ap_ssid: &str,
+    ap_pass: &str,
+) -> Result<(Box<EspWifi>, WifiMode)> {
+    wifi_multi(
+        netif_stack,
+        sys_loop_stack,
+        default_nvs,
+        &[(ssid.to_string(), pass.to_string())],
+        ap_ssid,
+        ap_pass,
+    )
+}
+
+// Joins the strongest configured network in range, SoftAP if none of them are
+pub fn wifi_multi(
+    netif_stack: Arc<EspNetifStack>,
+    sys_loop_stack: Arc<EspSysLoopStack>,
+    default_nvs: Arc<EspDefaultNvs>,
+    networks: &[(String, String)],
+    ap_ssid: &str,
+    ap_pass: &str,
+) -> Result<(Box<EspWifi>, WifiMode)> {
     let mut wifi = Box::new(EspWifi::new(netif_stack, sys_loop_stack, default_nvs)?);
 
     info!("Wifi created, about to scan");
 
-    let ap_infos = wifi.scan()?;
-
-    let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
-
-    let channel = if let Some(ours) = ours {
-        info!(
-            "Found configured access point {} on channel {}",
-            ssid, ours.channel
-        );
-        Some(ours.channel)
-    } else {
-        info!(
-            "Configured access point {} not found during scanning, will go with unknown channel",
-            ssid
-        );
-        None
+    let (ssid, pass, channel) = match strongest(&mut wifi, networks)? {
+        Some(((ssid, pass), channel)) => (ssid, pass, channel),
+        None => {
+            info!("No configured access point found during scanning");
+            start_access_point(&mut wifi, ap_ssid, ap_pass, 1)?;
+            return Ok((wifi, WifiMode::AccessPoint));
+        }
     };
     wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: ssid.into(),
-        password: pass.into(),
-        channel,
+        ssid: ssid.as_str().into(),
+        password: pass.as_str().into(),
+        channel: Some(channel),
         ..Default::default()
     }))?;
-    // wifi.set_configuration(&Configuration::Mixed(
-    //     ClientConfiguration {
-    //         ssid: ssid.into(),
-    //         password: pass.into(),
-    //         channel,
-    //         ..Default::default()
-    //     },
-    //     AccessPointConfiguration {
-    //         ssid: "aptest".into(),
-    //         channel: channel.unwrap_or(1),
-    //         ..Default::default()
-    //     },
-    // ))?;
 
     info!("Wifi configuration set, about to get status");
 
@@ -70,10 +87,6 @@ pub fn wifi(
     };
     let status = wifi.get_status();
 
-    // if let Status(
-    //     ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(ip_settings))),
-    //     ApStatus::Started(ApIpStatus::Done),
-    // ) = status
     if let Status(
         ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(ip_settings))),
         _,
@@ -81,11 +94,59 @@ pub fn wifi(
     {
         info!("Wifi connected");
 
-        ping_init(&ip_settings)?;
-    } else {
-        panic!("Ping gateway failed: {:?}", status);
+        if let Err(e) = ping_init(&ip_settings) {
+            info!("Gateway ping failed {:?}", e);
+        }
+        return Ok((wifi, WifiMode::Client));
+    }
+
+    info!("Wifi client failed {:?}", status);
+    start_access_point(&mut wifi, ap_ssid, ap_pass, channel)?;
+    Ok((wifi, WifiMode::AccessPoint))
+}
+
+// Strongest configured network in a fresh scan, with its channel
+fn strongest<'a>(
+    wifi: &mut EspWifi,
+    networks: &'a [(String, String)],
+) -> Result<Option<(&'a (String, String), u8)>> {
+    let ap_infos = wifi.scan()?;
+    let best = ap_infos
+        .iter()
+        .filter_map(|ap| {
+            networks
+                .iter()
+                .find(|(ssid, _)| ap.ssid == ssid.as_str())
+                .map(|network| (network, ap))
+        })
+        .max_by_key(|(_, ap)| ap.signal_strength);
+    Ok(best.map(|(network, ap)| {
+        info!(
+            "Found configured access point {} on channel {}, signal {}",
+            network.0, ap.channel, ap.signal_strength
+        );
+        (network, ap.channel)
+    }))
+}
+
+fn start_access_point(wifi: &mut EspWifi, ap_ssid: &str, ap_pass: &str, channel: u8) -> Result<()> {
+    info!("Starting access point {}", ap_ssid);
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ap_ssid.into(),
+        password: ap_pass.into(),
+        auth_method: AuthMethod::WPA2Personal,
+        channel,
+        ..Default::default()
+    }))?;
+    if let Err(e) =
+        wifi.wait_status_with_timeout(Duration::from_secs(20), |status| !status.is_transitional())
+    {
+        warn!("Access point start still in progress {:?}", e);
+    }
+    match wifi.get_status() {
+        Status(_, ApStatus::Started(ApIpStatus::Done)) => Ok(()),
+        status => Err(anyhow!("Access point failed to start: {:?}", status)),
     }
-    Ok(wifi)
 }
 
 fn ping_init(ip_settings: &ipv4::ClientSettings) -> Result<()> {
@@ -94,33 +155,79 @@ fn ping_init(ip_settings: &ipv4::ClientSettings) -> Result<()> {
     let ping_summary =
         ping::EspPing::default().ping(ip_settings.subnet.gateway, &Default::default())?;
     if ping_summary.transmitted != ping_summary.received {
-        panic!(
+        return Err(anyhow!(
             "Pinging gateway {} resulted in timeouts",
             ip_settings.subnet.gateway
-        );
+        ));
     }
     info!("Pinging done");
     Ok(())
 }
 
-#[allow(dead_code)]
-pub fn check_state(wifi: &EspWifi) -> Result<()> {
-    if wifi
-        .wait_status_with_timeout(Duration::from_secs(1), |status| !status.is_transitional())
-        .map_err(|e| info!("Unexpected Wifi status: {:?}", e))
-        .is_err()
-    {
-        println!("Debug: wifi error");
-    };
-    let status = wifi.get_status();
+pub fn is_connected(wifi: &EspWifi) -> bool {
+    matches!(
+        wifi.get_status(),
+        Status(
+            ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(_))),
+            _,
+        )
+    )
+}
+
+pub fn ip(wifi: &EspWifi) -> Option<ipv4::Ipv4Addr> {
     if let Status(
-        ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(
-            _ip_settings,
-        ))),
+        ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(ip_settings))),
         _,
-    ) = status
+    ) = wifi.get_status()
+    {
+        return Some(ip_settings.ip);
+    }
+    None
+}
+
+// SSID of the joined network, None in AP mode
+pub fn ssid(wifi: &EspWifi) -> Option<String> {
+    match wifi.get_configuration() {
+        Ok(Configuration::Client(client)) | Ok(Configuration::Mixed(client, _)) => {
+            Some(client.ssid.to_string())
+        }
+        _ => None,
+    }
+}
+
+// RSSI of the associated AP in dBm
+pub fn rssi() -> Option<i8> {
+    let mut ap_info = esp_idf_sys::wifi_ap_record_t::default();
+    if unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut ap_info) } == esp_idf_sys::ESP_OK as i32
     {
-        return Ok(());
+        Some(ap_info.rssi)
+    } else {
+        None
+    }
+}
+
+// Re-runs the scan/associate sequence after the AP was lost
+pub fn reconnect(wifi: &mut EspWifi, settings: &WifiSettings) -> Result<()> {
+    info!("Wifi reconnecting");
+    let ((ssid, pass), channel) = match strongest(wifi, &settings.networks)? {
+        Some(found) => found,
+        None => return Err(anyhow!("No configured access point found")),
+    };
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: ssid.as_str().into(),
+        password: pass.as_str().into(),
+        channel: Some(channel),
+        ..Default::default()
+    }))?;
+    if let Err(e) =
+        wifi.wait_status_with_timeout(RECONNECT_TIMEOUT, |status| !status.is_transitional())
+    {
+        warn!("Wifi reconnect still in progress {:?}", e);
+    }
+    if is_connected(wifi) {
+        info!("Wifi reconnected");
+        Ok(())
+    } else {
+        Err(anyhow!("Wifi still offline: {:?}", wifi.get_status()))
     }
-    panic!("Wifi offline");
 }